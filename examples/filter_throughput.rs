@@ -0,0 +1,77 @@
+//! Measures how many messages per second a filter with thousands of patterns can test.
+//!
+//! `cargo run --release --example filter_throughput [pattern count] [message count]`
+
+use std::time::Instant;
+
+use twitch_chat_watcher::{
+    chat_client::{build_sample_msg, TwitchMsg},
+    filter::{Filter, FilterState, MatchMode, PatternState},
+};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let pattern_count: usize = args.next().and_then(|s| s.parse().ok()).unwrap_or(5000);
+    let msg_count: usize = args.next().and_then(|s| s.parse().ok()).unwrap_or(20000);
+
+    let mut state = FilterState::default();
+    for i in 0..pattern_count {
+        state.exc_msg.push(PatternState {
+            text: format!("banned phrase {}", i),
+            mode: if i % 2 == 0 {
+                MatchMode::Text
+            } else {
+                MatchMode::Word
+            },
+            ..Default::default()
+        });
+    }
+    state.inc_msg.push(PatternState {
+        text: "hello".to_owned(),
+        ..Default::default()
+    });
+
+    let start = Instant::now();
+    let filter = Filter::try_from(&state).expect("valid patterns");
+    println!(
+        "Built filter with {} patterns in {:?}",
+        pattern_count,
+        start.elapsed()
+    );
+
+    let msgs: Vec<TwitchMsg> = (0..msg_count)
+        .map(|i| {
+            let text = match i % 4 {
+                0 => format!("hello there, banned phrase {}", i % (pattern_count * 2)),
+                1 => "hello chat, how is everyone doing today?".to_owned(),
+                2 => format!("some unrelated message number {}", i),
+                _ => "BANNED PHRASE 1 in capitals".to_owned(),
+            };
+            build_sample_msg("#bench", "viewer", &text).unwrap()
+        })
+        .collect();
+
+    let start = Instant::now();
+    let matched = msgs.iter().filter(|m| filter.test(m)).count();
+    let elapsed = start.elapsed();
+    println!(
+        "test:    {} of {} matched in {:?} ({:.0} msg/s)",
+        matched,
+        msg_count,
+        elapsed,
+        msg_count as f64 / elapsed.as_secs_f64()
+    );
+
+    // `explain` tests the patterns one by one
+    let start = Instant::now();
+    let explained = msgs.iter().filter(|m| filter.explain(m).matched).count();
+    let elapsed = start.elapsed();
+    println!(
+        "explain: {} of {} matched in {:?} ({:.0} msg/s)",
+        explained,
+        msg_count,
+        elapsed,
+        msg_count as f64 / elapsed.as_secs_f64()
+    );
+    assert_eq!(matched, explained, "test and explain disagree");
+}
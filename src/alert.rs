@@ -0,0 +1,125 @@
+use std::{collections::VecDeque, fmt::Display};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// When the alert of a filter view is played
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum AlertCondition {
+    /// At least `count` filtered messages within `seconds`
+    Burst { count: usize, seconds: u64 },
+    /// The first filtered message after `seconds` without any
+    AfterSilence { seconds: u64 },
+}
+
+impl Default for AlertCondition {
+    fn default() -> Self {
+        AlertCondition::AfterSilence { seconds: 10 }
+    }
+}
+
+impl AlertCondition {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertCondition::Burst { .. } => "Burst of messages",
+            AlertCondition::AfterSilence { .. } => "First message after silence",
+        }
+    }
+}
+
+/// The counts behind a played alert
+#[derive(Clone, Debug)]
+pub struct AlertRecord {
+    pub time: DateTime<Utc>,
+    /// Filtered messages inside the burst window
+    pub count: usize,
+    /// Time since the previous filtered message, `None` for the first one
+    pub silence: Option<Duration>,
+}
+
+impl Display for AlertRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} message(s) in window",
+            self.time.with_timezone(&chrono::Local).format("%H:%M:%S"),
+            self.count
+        )?;
+        match self.silence {
+            Some(d) => write!(f, ", after {}s of silence", d.num_seconds()),
+            None => write!(f, ", first message"),
+        }
+    }
+}
+
+/// Tracks filtered message times of a view and decides when its alert is played
+#[derive(Debug, Default)]
+pub struct AlertTracker {
+    condition: AlertCondition,
+    window: VecDeque<DateTime<Utc>>,
+    last_match: Option<DateTime<Utc>>,
+    last_alert: Option<AlertRecord>,
+    alert_count: usize,
+}
+
+impl AlertTracker {
+    pub fn condition(&self) -> AlertCondition {
+        self.condition
+    }
+
+    pub fn set_condition(&mut self, condition: AlertCondition) {
+        if self.condition != condition {
+            self.condition = condition;
+            self.window.clear();
+        }
+    }
+
+    /// Record a filtered message, returns whether the alert should be played
+    pub fn on_match(&mut self, now: DateTime<Utc>) -> bool {
+        let silence = self.last_match.map(|t| now - t);
+        self.last_match = Some(now);
+        let fire = match self.condition {
+            AlertCondition::Burst { count, seconds } => {
+                let start = now - Duration::seconds(seconds as i64);
+                while self.window.front().map(|t| *t < start).unwrap_or(false) {
+                    self.window.pop_front();
+                }
+                self.window.push_back(now);
+                self.window.len() >= count.max(1)
+            }
+            AlertCondition::AfterSilence { seconds } => silence
+                .map(|d| d >= Duration::seconds(seconds as i64))
+                .unwrap_or(true),
+        };
+        if fire {
+            self.last_alert = Some(AlertRecord {
+                time: now,
+                count: self.window.len().max(1),
+                silence,
+            });
+            self.alert_count += 1;
+            // A burst has to build up again before the next alert
+            self.window.clear();
+        }
+        fire
+    }
+
+    pub fn last_alert(&self) -> Option<&AlertRecord> {
+        self.last_alert.as_ref()
+    }
+
+    pub fn alert_count(&self) -> usize {
+        self.alert_count
+    }
+
+    /// Filtered messages currently inside the burst window
+    pub fn window_count(&self, now: DateTime<Utc>) -> usize {
+        match self.condition {
+            AlertCondition::Burst { seconds, .. } => {
+                let start = now - Duration::seconds(seconds as i64);
+                self.window.iter().filter(|t| **t >= start).count()
+            }
+            AlertCondition::AfterSilence { .. } => 0,
+        }
+    }
+}
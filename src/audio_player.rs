@@ -1,54 +1,543 @@
 use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
     io::Cursor,
+    path::{Path, PathBuf},
     sync::{
-        mpsc::{channel, SendError, Sender},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
         Arc, Mutex,
     },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use rodio::{Decoder, OutputStream, Sink};
+use chrono::{DateTime, Utc};
+use rodio::{Decoder, OutputStream, Sink, Source};
+use serde::{Deserialize, Serialize};
+
+/// Extensions of the sound files that can be decoded
+pub const SOUND_EXTENSIONS: [&str; 4] = ["wav", "mp3", "ogg", "flac"];
+
+/// Decoded samples of a sound, cheap to clone
+#[derive(Clone, Debug)]
+pub struct Sound {
+    channels: u16,
+    sample_rate: u32,
+    samples: Arc<Vec<i16>>,
+}
+
+impl Sound {
+    fn decode(data: Vec<u8>) -> Result<Self, String> {
+        let decoder = Decoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples: Arc::new(decoder.collect()),
+        })
+    }
+
+    fn duration(&self) -> Duration {
+        let frames = self.samples.len() as u64 / self.channels.max(1) as u64;
+        Duration::from_micros(frames * 1_000_000 / self.sample_rate.max(1) as u64)
+    }
+
+    fn source(&self) -> SoundSource {
+        SoundSource {
+            sound: self.clone(),
+            pos: 0,
+        }
+    }
+}
+
+/// Plays the shared samples of a sound without copying them
+struct SoundSource {
+    sound: Sound,
+    pos: usize,
+}
+
+impl Iterator for SoundSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.sound.samples.get(self.pos).copied();
+        self.pos += 1;
+        sample
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.sound.samples.len().saturating_sub(self.pos);
+        (left, Some(left))
+    }
+}
+
+impl Source for SoundSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.sound.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sound.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(self.sound.duration())
+    }
+}
+
+/// What happens to an alert arriving while other alerts are playing
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum QueuePolicy {
+    /// Skip the alert
+    Drop,
+    /// Skip the alert if one from the same channel or rule is still queued
+    Coalesce,
+    /// Play every alert in sequence
+    PlayAll,
+}
+
+impl QueuePolicy {
+    pub const ALL: [QueuePolicy; 3] = [
+        QueuePolicy::Drop,
+        QueuePolicy::Coalesce,
+        QueuePolicy::PlayAll,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueuePolicy::Drop => "Drop while playing",
+            QueuePolicy::Coalesce => "Coalesce per source",
+            QueuePolicy::PlayAll => "Play all in sequence",
+        }
+    }
+}
+
+/// Alert playback settings of a channel or a rule
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct AlertSettings {
+    /// Minimum time between two alerts of the same source
+    pub cooldown_secs: u64,
+    pub queue: QueuePolicy,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            cooldown_secs: 10,
+            queue: QueuePolicy::Coalesce,
+        }
+    }
+}
+
+/// An alert sent to the player thread
+struct PlayRequest {
+    /// The channel or rule of the alert, cooldowns are tracked per source
+    source: String,
+    /// Name of the source shown when the alert is not heard
+    label: String,
+    sound: Sound,
+    volume: f32,
+    settings: AlertSettings,
+}
+
+#[derive(Clone, Debug)]
+pub enum SoundError {
+    NotFound(PathBuf),
+    Io(PathBuf, String),
+    Decode(PathBuf, String),
+}
+
+impl Display for SoundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SoundError::NotFound(p) => write!(f, "Sound file {} not found", p.display()),
+            SoundError::Io(p, e) => write!(f, "Cannot read sound file {}: {}", p.display(), e),
+            SoundError::Decode(p, e) => {
+                write!(f, "Cannot decode sound file {}: {}", p.display(), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SoundError {}
+
+/// Time between two attempts to open the audio device while it is missing
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Delay past the expected end of the queued sounds after which the device is considered lost
+const STALL_MARGIN: Duration = Duration::from_secs(2);
+/// Alerts kept for the visual fallback
+const MAX_SILENT_ALERTS: usize = 20;
+
+/// State of the audio output of the player
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AudioStatus {
+    Starting,
+    Ready,
+    /// No usable audio device, alerts are only shown
+    Unavailable(String),
+    /// The null backend, which plays nothing
+    Null,
+}
+
+impl Display for AudioStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioStatus::Starting => write!(f, "Opening audio device"),
+            AudioStatus::Ready => write!(f, "Audio device ready"),
+            AudioStatus::Unavailable(e) => {
+                write!(f, "No audio device ({}), alerts are visual only", e)
+            }
+            AudioStatus::Null => write!(f, "No audio output, alerts are visual only"),
+        }
+    }
+}
+
+enum Command {
+    Play(PlayRequest),
+    /// Try to open the audio device again
+    Retry,
+}
+
+/// Audio output of the worker thread
+enum Output {
+    Device {
+        _stream: OutputStream,
+        sink: Sink,
+        /// When the queued sounds should be done playing
+        end: Instant,
+    },
+    Null,
+}
+
+impl Output {
+    fn open(null: bool) -> Result<Self, String> {
+        if null {
+            return Ok(Output::Null);
+        }
+        let (stream, handle) = OutputStream::try_default().map_err(|e| e.to_string())?;
+        let sink = Sink::try_new(&handle).map_err(|e| e.to_string())?;
+        Ok(Output::Device {
+            _stream: stream,
+            sink,
+            end: Instant::now(),
+        })
+    }
+
+    /// Sounds queued or playing
+    fn len(&self) -> usize {
+        match self {
+            Output::Device { sink, .. } => sink.len(),
+            Output::Null => 0,
+        }
+    }
+
+    /// Returns whether the sound is heard
+    fn play(&mut self, sound: &Sound, volume: f32) -> bool {
+        match self {
+            Output::Device { sink, end, .. } => {
+                sink.set_volume(volume);
+                sink.append(sound.source());
+                *end = Instant::now().max(*end) + sound.duration();
+                true
+            }
+            Output::Null => false,
+        }
+    }
+
+    /// Whether the device stopped consuming the queued sounds, it was likely unplugged
+    fn stalled(&self) -> bool {
+        match self {
+            Output::Device { sink, end, .. } => {
+                sink.len() > 0 && Instant::now() > *end + STALL_MARGIN
+            }
+            Output::Null => false,
+        }
+    }
+}
+
+/// Player state shared with the worker thread
+#[derive(Debug)]
+struct PlayerState {
+    status: AudioStatus,
+    /// Alerts sent to the audio device
+    played: usize,
+    /// Sources and times of the alerts which could not be heard
+    silent_alerts: VecDeque<(String, DateTime<Utc>)>,
+    /// Sources and sound file errors of the alerts played with the default sound instead
+    sound_errors: VecDeque<(String, DateTime<Utc>)>,
+}
+
+/// Cooldowns and queue of the worker thread
+#[derive(Default)]
+struct Scheduler {
+    /// Sources of the sounds in the output, the first one is playing
+    queued: VecDeque<String>,
+    last_played: HashMap<String, Instant>,
+}
+
+impl Scheduler {
+    /// Returns whether the alert should be played
+    fn accept(&mut self, request: &PlayRequest, output_len: usize) -> bool {
+        while self.queued.len() > output_len {
+            self.queued.pop_front();
+        }
+        let cooldown = Duration::from_secs(request.settings.cooldown_secs);
+        if self
+            .last_played
+            .get(&request.source)
+            .map(|t| t.elapsed() < cooldown)
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        let skip = match request.settings.queue {
+            QueuePolicy::Drop => output_len > 0,
+            QueuePolicy::Coalesce => self.queued.contains(&request.source),
+            QueuePolicy::PlayAll => false,
+        };
+        if !skip {
+            self.last_played
+                .insert(request.source.clone(), Instant::now());
+        }
+        !skip
+    }
+}
+
+fn run_worker(rx: Receiver<Command>, null: bool, state: Arc<Mutex<PlayerState>>) {
+    let mut output = None;
+    let mut scheduler = Scheduler::default();
+    loop {
+        let command = match rx.recv_timeout(RETRY_INTERVAL) {
+            Ok(command) => Some(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let stalled = output.as_ref().map(Output::stalled).unwrap_or(false);
+        if stalled || output.is_none() || matches!(command, Some(Command::Retry)) {
+            // Release the old device before opening the new one
+            output.take();
+            let status = match Output::open(null) {
+                Ok(o) => {
+                    let status = match o {
+                        Output::Device { .. } => AudioStatus::Ready,
+                        Output::Null => AudioStatus::Null,
+                    };
+                    output = Some(o);
+                    status
+                }
+                Err(e) => {
+                    output = None;
+                    AudioStatus::Unavailable(e)
+                }
+            };
+            state.lock().unwrap().status = status;
+        }
+        let request = match command {
+            Some(Command::Play(request)) => request,
+            _ => continue,
+        };
+        let output_len = output.as_ref().map(|o| o.len()).unwrap_or(0);
+        if !scheduler.accept(&request, output_len) {
+            continue;
+        }
+        let heard = output
+            .as_mut()
+            .map(|o| o.play(&request.sound, request.volume))
+            .unwrap_or(false);
+        if heard {
+            scheduler.queued.push_back(request.source.clone());
+        }
+        let mut state = state.lock().unwrap();
+        if heard {
+            state.played += 1;
+        }
+        if !heard && !request.source.is_empty() {
+            state.silent_alerts.push_back((request.label, Utc::now()));
+            if state.silent_alerts.len() > MAX_SILENT_ALERTS {
+                state.silent_alerts.pop_front();
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct AlertPlayer {
     volume: Arc<Mutex<f32>>,
+    default_sound: Sound,
+    /// Sound files loaded so far by path, with their error so that a broken file is not read
+    /// again on every alert
+    sounds: Arc<Mutex<HashMap<PathBuf, Result<Sound, SoundError>>>>,
+    state: Arc<Mutex<PlayerState>>,
     _worker_handler: Arc<JoinHandle<()>>,
-    tx: Sender<f32>,
+    tx: Sender<Command>,
 }
 
 //unsafe impl std::marker::Send for AlertPlayer {}
 
 impl Default for AlertPlayer {
     fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl AlertPlayer {
+    /// A player which never opens an audio device, for machines without one
+    pub fn null() -> Self {
+        Self::new(true)
+    }
+
+    fn new(null: bool) -> Self {
+        //Sound Effect by UNIVERSFIELD from Pixabay
+        let data = include_bytes!("../assets/new-notification-sound-effect-138807.mp3");
+        let default_sound = Sound::decode(data.to_vec()).unwrap();
+        let state = Arc::new(Mutex::new(PlayerState {
+            status: AudioStatus::Starting,
+            played: 0,
+            silent_alerts: VecDeque::new(),
+            sound_errors: VecDeque::new(),
+        }));
         let (tx, rx) = channel();
-        let worker = thread::spawn(move || {
-            //Sound Effect by UNIVERSFIELD from Pixabay
-            let data = include_bytes!("../assets/new-notification-sound-effect-138807.mp3");
-            let (_stream, _stream_handle) = OutputStream::try_default().unwrap();
-            let sink = Sink::try_new(&_stream_handle).unwrap();
-            let mut last_time_played = Instant::now() - Duration::from_secs(10);
-            while let Ok(volume) = rx.recv() {
-                if Instant::now() - last_time_played < Duration::from_secs(10) {
-                    continue;
-                }
-                let source = Decoder::new(Cursor::new(data)).unwrap();
-                sink.set_volume(volume);
-                sink.append(source);
-                last_time_played = Instant::now();
-            }
-        });
+        let worker_state = state.clone();
+        let worker = thread::spawn(move || run_worker(rx, null, worker_state));
+        // Open the output right away instead of on the first alert
+        let _ = tx.send(Command::Retry);
         Self {
             volume: Arc::new(Mutex::new(1.0)),
+            default_sound,
+            sounds: Arc::new(Mutex::new(HashMap::new())),
+            state,
             _worker_handler: Arc::new(worker),
             tx,
         }
     }
-}
 
-impl AlertPlayer {
-    pub fn play(&self) -> Result<(), SendError<f32>> {
-        self.tx.send(*self.volume.lock().unwrap())
+    /// Play the default sound
+    pub fn play(&self) {
+        self.play_sound(None)
+    }
+
+    /// Play a sound file right away, or the default sound if `path` is `None`
+    pub fn play_sound(&self, path: Option<&Path>) {
+        let settings = AlertSettings {
+            cooldown_secs: 0,
+            queue: QueuePolicy::PlayAll,
+        };
+        self.alert("", "", path, settings)
+    }
+
+    /// Play the alert of `source` (a channel or a rule) unless its cooldown
+    /// or queue policy skips it, `label` names the source in the visual fallback.
+    /// The default sound is played if the sound file cannot be loaded, the error is
+    /// kept for [`Self::sound_errors`].
+    pub fn alert(&self, source: &str, label: &str, path: Option<&Path>, settings: AlertSettings) {
+        let sound = match path.map(|p| self.sound(p)) {
+            Some(Ok(sound)) => sound,
+            Some(Err(e)) => {
+                if !source.is_empty() {
+                    let mut state = self.state.lock().unwrap();
+                    state
+                        .sound_errors
+                        .push_back((format!("{}: {}", label, e), Utc::now()));
+                    if state.sound_errors.len() > MAX_SILENT_ALERTS {
+                        state.sound_errors.pop_front();
+                    }
+                }
+                self.default_sound.clone()
+            }
+            None => self.default_sound.clone(),
+        };
+        self.send(Command::Play(PlayRequest {
+            source: source.to_owned(),
+            label: label.to_owned(),
+            sound,
+            volume: *self.volume.lock().unwrap(),
+            settings,
+        }));
+    }
+
+    /// Try to open the audio device again, it is also retried periodically while missing
+    pub fn retry(&self) {
+        self.send(Command::Retry);
+    }
+
+    fn send(&self, command: Command) {
+        if self.tx.send(command).is_err() {
+            self.state.lock().unwrap().status =
+                AudioStatus::Unavailable("the player stopped".to_owned());
+        }
+    }
+
+    pub fn status(&self) -> AudioStatus {
+        self.state.lock().unwrap().status.clone()
+    }
+
+    /// Number of alerts sent to the audio device
+    pub fn played_count(&self) -> usize {
+        self.state.lock().unwrap().played
+    }
+
+    /// Sources of the alerts not heard within `within`, most recent last
+    pub fn silent_alerts(&self, within: chrono::Duration) -> Vec<String> {
+        let start = Utc::now() - within;
+        self.state
+            .lock()
+            .unwrap()
+            .silent_alerts
+            .iter()
+            .filter(|(_, t)| *t >= start)
+            .map(|(source, _)| source.clone())
+            .collect()
+    }
+
+    /// Sound file errors of the alerts within `within`, with their source, most recent last
+    pub fn sound_errors(&self, within: chrono::Duration) -> Vec<String> {
+        let start = Utc::now() - within;
+        self.state
+            .lock()
+            .unwrap()
+            .sound_errors
+            .iter()
+            .filter(|(_, t)| *t >= start)
+            .map(|(error, _)| error.clone())
+            .collect()
+    }
+
+    /// Read and decode a sound file again, replacing the cached sound or error.
+    /// Called when a file is chosen or a configuration using it is applied.
+    pub fn load(&self, path: &Path) -> Result<(), SoundError> {
+        let sound = Self::read(path);
+        self.sounds
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), sound.clone());
+        sound.map(|_| ())
+    }
+
+    /// The cached sound or error of a file, the file is only read the first time
+    fn sound(&self, path: &Path) -> Result<Sound, SoundError> {
+        if let Some(sound) = self.sounds.lock().unwrap().get(path) {
+            return sound.clone();
+        }
+        let sound = Self::read(path);
+        self.sounds
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), sound.clone());
+        sound
+    }
+
+    fn read(path: &Path) -> Result<Sound, SoundError> {
+        if !path.is_file() {
+            return Err(SoundError::NotFound(path.to_owned()));
+        }
+        let data =
+            std::fs::read(path).map_err(|e| SoundError::Io(path.to_owned(), e.to_string()))?;
+        Sound::decode(data).map_err(|e| SoundError::Decode(path.to_owned(), e))
     }
 
     pub fn set_volume(&mut self, v: f32) {
@@ -59,3 +548,102 @@ impl AlertPlayer {
         *self.volume.lock().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(source: &str, cooldown_secs: u64, queue: QueuePolicy) -> PlayRequest {
+        PlayRequest {
+            source: source.to_owned(),
+            label: source.to_owned(),
+            sound: Sound {
+                channels: 1,
+                sample_rate: 44100,
+                samples: Arc::new(vec![0; 4410]),
+            },
+            volume: 1.0,
+            settings: AlertSettings {
+                cooldown_secs,
+                queue,
+            },
+        }
+    }
+
+    /// Wait for the worker thread to handle the alerts sent so far
+    fn wait_silent(player: &AlertPlayer, count: usize) -> Vec<String> {
+        let start = Instant::now();
+        loop {
+            let silent = player.silent_alerts(chrono::Duration::seconds(60));
+            if silent.len() >= count || start.elapsed() > Duration::from_secs(5) {
+                return silent;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn cooldown_skips_alerts_of_the_same_source() {
+        let mut scheduler = Scheduler::default();
+        assert!(scheduler.accept(&request("a", 10, QueuePolicy::PlayAll), 0));
+        assert!(!scheduler.accept(&request("a", 10, QueuePolicy::PlayAll), 0));
+        assert!(scheduler.accept(&request("b", 10, QueuePolicy::PlayAll), 0));
+        assert!(scheduler.accept(&request("a", 0, QueuePolicy::PlayAll), 0));
+    }
+
+    #[test]
+    fn queue_policies() {
+        let mut scheduler = Scheduler::default();
+        scheduler.queued.push_back("a".to_owned());
+        assert!(!scheduler.accept(&request("b", 0, QueuePolicy::Drop), 1));
+        assert!(!scheduler.accept(&request("a", 0, QueuePolicy::Coalesce), 1));
+        assert!(scheduler.accept(&request("b", 0, QueuePolicy::Coalesce), 1));
+        assert!(scheduler.accept(&request("a", 0, QueuePolicy::PlayAll), 1));
+        // The output finished the queued sound
+        assert!(scheduler.accept(&request("a", 0, QueuePolicy::Coalesce), 0));
+        assert!(scheduler.queued.is_empty());
+    }
+
+    #[test]
+    fn null_player_shows_alerts() {
+        let player = AlertPlayer::null();
+        let settings = AlertSettings {
+            cooldown_secs: 60,
+            queue: QueuePolicy::PlayAll,
+        };
+        player.alert("chan/#0", "chan/rule", None, settings);
+        player.alert("chan/#0", "chan/rule", None, settings);
+        player.alert("chan/#1", "chan/rule", None, settings);
+        player.alert("other", "other", None, settings);
+        let silent = wait_silent(&player, 3);
+        assert_eq!(silent, vec!["chan/rule", "chan/rule", "other"]);
+        assert_eq!(player.status(), AudioStatus::Null);
+        assert_eq!(player.played_count(), 0);
+    }
+
+    #[test]
+    fn sound_errors_are_cached_until_reloaded() {
+        let player = AlertPlayer::null();
+        let path = std::env::temp_dir().join(format!("alert-{}.mp3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let settings = AlertSettings {
+            cooldown_secs: 0,
+            queue: QueuePolicy::PlayAll,
+        };
+        player.alert("chan", "chan", Some(&path), settings);
+        let errors = player.sound_errors(chrono::Duration::seconds(60));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("chan: Sound file") && errors[0].ends_with("not found"));
+        // The file is not looked up again by the alerts
+        std::fs::write(&path, b"not a sound").unwrap();
+        player.alert("chan", "chan", Some(&path), settings);
+        assert!(matches!(player.sound(&path), Err(SoundError::NotFound(_))));
+        // Loading the chosen file again replaces the cached error
+        let reloaded = player.load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!matches!(reloaded, Err(SoundError::NotFound(_))));
+        assert!(!matches!(player.sound(&path), Err(SoundError::NotFound(_))));
+        // The alerts were still played with the default sound
+        assert_eq!(wait_silent(&player, 2).len(), 2);
+    }
+}
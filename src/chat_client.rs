@@ -1,7 +1,14 @@
 use super::{
-    audio_player::AlertPlayer,
-    filter::{Filter, FilterState},
-    ASYNC_RUNTIME,
+    alert::{AlertCondition, AlertTracker},
+    audio_player::{AlertPlayer, AlertSettings},
+    chat_log::{
+        find_log_files, LogFormat, LogSettings, LogTarget, SharedLogSettings, ALL_MSG_LOG_NAME,
+    },
+    command::{CommandRunner, CommandSettings, CommandStatus},
+    filter::{Filter, FilterError, FilterLibrary, FilterState},
+    msg_store::{MessageStore, QuerySpec, StoreSettings},
+    rule::{Rule, RuleState},
+    ASYNC_RUNTIME, DEFAULT_VIEW_NAME,
 };
 use chrono::{DateTime, TimeZone, Utc};
 use futures::stream::StreamExt;
@@ -15,14 +22,14 @@ use irc::{
         {Capability, Message, Prefix, Response},
     },
 };
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
     path::PathBuf,
     sync::Arc,
 };
 use tokio::{
-    fs::OpenOptions,
-    io::AsyncWriteExt,
     sync::{mpsc, Mutex},
     task::JoinHandle,
 };
@@ -129,6 +136,8 @@ impl IrcClient {
                                                 tags.push(Tag("reply-parent-user-login".to_string(), Some(reply_to.sender_login().to_string())));
                                                 tags.push(Tag("reply-parent-display-name".to_string(), Some(reply_to.sender_display().to_string())));
                                                 tags.push(Tag("reply-parent-msg-body".to_string(), Some(reply_to.payload().to_string())));
+                                                tags.push(Tag("reply-thread-parent-msg-id".to_string(), Some(reply_to.reply_thread_root_id().map(|s| s.as_str()).unwrap_or(reply_to.id()).to_string())));
+                                                tags.push(Tag("reply-thread-parent-user-login".to_string(), Some(reply_to.tag("reply-thread-parent-user-login").map(|s| s.as_str()).unwrap_or(reply_to.sender_login()).to_string())));
                                             }
                                             let prefix = format!("{}!{}@{}.tmi.twitch.tv", &worker_username, &worker_username, &worker_username);
                                             let msg_obj = Message::with_tags(Some(tags), Some(&prefix), "PRIVMSG", vec![&channel_list[0], &sent_msg_content]).unwrap();
@@ -192,33 +201,164 @@ async fn handle_msg(mut msg: TwitchMsg, data: &Arc<Mutex<SharedData>>) {
         msg.payload = msg.payload.trim_end_matches('\u{e0000}').to_owned();
     }
     let mut shared_data = data.lock().await;
-    shared_data.msg_list.push_back(msg.clone());
-    if let Some(Ok(p)) = &shared_data.log {
-        match OpenOptions::new().create(true).append(true).open(p).await {
-            Ok(mut f) => f.write_all(msg_to_str(&msg).as_bytes()).await.unwrap(),
-            Err(e) => shared_data.log = Some(Err(e)),
+    if let Some(store) = &shared_data.store {
+        store.write(&msg);
+    }
+    let now = Utc::now();
+    let duplicate = if shared_data.duplicates.enabled {
+        find_duplicate(
+            &shared_data.msg_list,
+            &msg,
+            msg.sent_time().unwrap_or(now),
+            shared_data.duplicates.window_secs,
+        )
+    } else {
+        None
+    };
+    // Collapsed duplicates only go through rules and filters if they are set to count
+    let counted = duplicate.is_none() || shared_data.duplicates.count_duplicates;
+    let alert_sound = shared_data.alert_sound.clone();
+    let alert_settings = shared_data.alert_settings;
+    let mut hide = false;
+    let mut urgent = vec![];
+    for (rule_idx, rule) in shared_data.rules.iter_mut().enumerate() {
+        if !counted || !rule.test(&msg) {
+            continue;
+        }
+        if let Some(color) = rule.state().highlight {
+            msg.highlight = Some(color);
+        }
+        hide |= rule.state().hide;
+        let sound = rule
+            .state()
+            .sound_file
+            .clone()
+            .or_else(|| alert_sound.clone());
+        let label = format!("{}/{}", msg.channel(), rule.state().name);
+        // Cooldowns are keyed by the rule index, rule names are not unique
+        let (source, settings) = match rule.state().alert_settings {
+            Some(settings) => (format!("{}/#{}", msg.channel(), rule_idx), settings),
+            None => (msg.channel().to_owned(), alert_settings),
+        };
+        if let Some(player) = rule.alert() {
+            player.alert(&source, &label, sound.as_deref(), settings);
+        }
+        if let Some(repeat_secs) = rule.state().urgent {
+            msg.urgent = true;
+            urgent.push(UrgentAlert {
+                msg_id: msg.id().to_owned(),
+                rule_name: rule.state().name.clone(),
+                rule_condition: rule.state().condition.clone(),
+                player: rule.alert().cloned(),
+                source,
+                label,
+                sound,
+                settings,
+                repeat: chrono::Duration::seconds(repeat_secs.max(1) as i64),
+                last_alert: now,
+            });
+        }
+        if let Some(log) = rule.log() {
+            log.write(&msg);
+        }
+    }
+    let collapsed = duplicate.map(|idx| {
+        let base = &mut shared_data.msg_list[idx];
+        base.add_repeat(&msg, msg.sent_time().unwrap_or(now));
+        base.urgent |= msg.urgent;
+        base.clone()
+    });
+    // The alerts of a collapsed duplicate are acknowledged on the displayed message
+    for alert in urgent.iter_mut() {
+        if let Some(collapsed) = &collapsed {
+            alert.msg_id = collapsed.id().to_owned();
+        }
+    }
+    shared_data.urgent.extend(urgent);
+    if !hide {
+        if collapsed.is_none() {
+            shared_data.msg_list.push_back(msg.clone());
+        }
+        if let Some(log) = &shared_data.log {
+            log.write(&msg);
         }
     }
     while shared_data.msg_list.len() > shared_data.max_msg_count {
         shared_data.msg_list.pop_front();
     }
-    if shared_data.filter.test(&msg) {
-        if let Some(Ok(p)) = &shared_data.log_filtered {
-            match OpenOptions::new().create(true).append(true).open(p).await {
-                Ok(mut f) => f.write_all(msg_to_str(&msg).as_bytes()).await.unwrap(),
-                Err(e) => shared_data.log = Some(Err(e)),
+    let max_msg_count = shared_data.max_msg_count;
+    let matches: Vec<bool> = {
+        let library = shared_data.library.read().unwrap();
+        shared_data
+            .views
+            .iter()
+            .map(|view| counted && view.test(&library, &msg))
+            .collect()
+    };
+    // The command runs once per message, for the first matching view
+    if let Some(view_idx) = matches.iter().position(|m| *m) {
+        shared_data
+            .command
+            .run(&msg, &shared_data.views[view_idx].name);
+    }
+    for (view, matched) in shared_data.views.iter_mut().zip(matches) {
+        let mut in_view = false;
+        if let Some(collapsed) = &collapsed {
+            if let Some(entry) = view.msg_list.iter_mut().find(|m| m.id() == collapsed.id()) {
+                *entry = collapsed.clone();
+                in_view = true;
             }
         }
+        if !matched {
+            continue;
+        }
+        if let Some(log) = &view.log {
+            log.write(&msg);
+        }
 
-        shared_data.filtered_msg_list.push_back(msg.clone());
-        while shared_data.filtered_msg_list.len() > shared_data.max_msg_count {
-            shared_data.filtered_msg_list.pop_front();
+        if !in_view {
+            view.msg_list
+                .push_back(collapsed.clone().unwrap_or_else(|| msg.clone()));
+        }
+        while view.msg_list.len() > max_msg_count {
+            view.msg_list.pop_front();
         }
-        if let Some(player) = &shared_data.alert {
-            player.play().unwrap();
+        if view.alert_tracker.on_match(now) {
+            if let Some(player) = &view.alert {
+                player.alert(
+                    msg.channel(),
+                    msg.channel(),
+                    alert_sound.as_deref(),
+                    alert_settings,
+                );
+            }
         }
 
-        shared_data.has_unread_filtered_msg = true;
+        view.has_unread_msg = true;
+    }
+    shared_data.prune_urgent();
+}
+
+/// Repeat the alerts of the urgent messages until they are acknowledged
+async fn repeat_urgent_alerts(data: Arc<Mutex<SharedData>>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        for alert in data.lock().await.urgent.iter_mut() {
+            if now - alert.last_alert < alert.repeat {
+                continue;
+            }
+            if let Some(player) = &alert.player {
+                player.alert(
+                    &alert.source,
+                    &alert.label,
+                    alert.sound.as_deref(),
+                    alert.settings,
+                );
+            }
+            alert.last_alert = now;
+        }
     }
 }
 
@@ -259,17 +399,171 @@ pub enum ChannelConnectionState {
     Joined,
 }
 
+/// Lowercased words of a message, ignoring punctuation, symbols and spacing
+fn normalize_text(text: &str) -> String {
+    let normalized = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if normalized.is_empty() {
+        text.trim().to_owned()
+    } else {
+        normalized
+    }
+}
+
+/// Index of the newest message repeated by `msg` within `window_secs` of its last occurrence
+fn find_duplicate(
+    msg_list: &VecDeque<TwitchMsg>,
+    msg: &TwitchMsg,
+    time: DateTime<Utc>,
+    window_secs: u64,
+) -> Option<usize> {
+    let start = time - chrono::Duration::seconds(window_secs as i64);
+    let text = msg.normalized_text();
+    msg_list
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, m)| m.last_seen().map(|t| t >= start).unwrap_or(false))
+        .find(|(_, m)| m.normalized_text() == text)
+        .map(|(idx, _)| idx)
+}
+
+/// How repeated messages of a channel are collapsed
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct DuplicateSettings {
+    pub enabled: bool,
+    /// Seconds since the last repeat during which a message counts as a duplicate
+    pub window_secs: u64,
+    /// Whether collapsed duplicates still go through rules, filters and alerts
+    pub count_duplicates: bool,
+}
+
+impl Default for DuplicateSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: 30,
+            count_duplicates: false,
+        }
+    }
+}
+
+/// A named filtered message list of a channel
+#[derive(Debug)]
+struct FilterView {
+    name: String,
+    filter: Filter,
+    msg_list: VecDeque<TwitchMsg>,
+    log: Option<LogTarget>,
+    alert: Option<AlertPlayer>,
+    alert_tracker: AlertTracker,
+    /// Names of the library filters applied on top of `filter`
+    library_refs: Vec<String>,
+    has_unread_msg: bool,
+}
+
+impl FilterView {
+    fn new(name: impl ToString, filter: Filter) -> Self {
+        Self {
+            name: name.to_string(),
+            filter,
+            msg_list: VecDeque::new(),
+            log: None,
+            alert: None,
+            alert_tracker: AlertTracker::default(),
+            library_refs: vec![],
+            has_unread_msg: false,
+        }
+    }
+
+    fn test(&self, library: &[(String, Filter)], msg: &TwitchMsg) -> bool {
+        self.filter
+            .test_with_library(library, &self.library_refs, msg)
+    }
+}
+
+/// A message matched by an urgent rule, alerting until acknowledged
+#[derive(Debug)]
+struct UrgentAlert {
+    msg_id: String,
+    /// Name and condition of the rule, the alert stops when the rule is removed
+    rule_name: String,
+    rule_condition: String,
+    /// `None` if the rule has no sound, the channel entry still flashes
+    player: Option<AlertPlayer>,
+    source: String,
+    label: String,
+    sound: Option<PathBuf>,
+    settings: AlertSettings,
+    repeat: chrono::Duration,
+    last_alert: DateTime<Utc>,
+}
+
 #[derive(Debug)]
 struct SharedData {
     msg_list: VecDeque<TwitchMsg>,
-    filtered_msg_list: VecDeque<TwitchMsg>,
-    filter: Filter,
+    views: Vec<FilterView>,
+    rules: Vec<Rule>,
+    duplicates: DuplicateSettings,
+    library: FilterLibrary,
+    /// Sound file of the alerts of this channel, the default sound if `None`
+    alert_sound: Option<PathBuf>,
+    alert_settings: AlertSettings,
+    command: CommandRunner,
+    urgent: Vec<UrgentAlert>,
     max_msg_count: usize,
     state: ChannelConnectionState,
-    log: Option<Result<PathBuf, std::io::Error>>,
-    log_filtered: Option<Result<PathBuf, std::io::Error>>,
-    alert: Option<AlertPlayer>,
-    has_unread_filtered_msg: bool,
+    log: Option<LogTarget>,
+    log_settings: SharedLogSettings,
+    store: Option<MessageStore>,
+}
+
+impl SharedData {
+    /// `None` is the unfiltered message list, `Some(idx)` is the list of a filter view
+    fn msg_list(&self, view: Option<usize>) -> &VecDeque<TwitchMsg> {
+        match view {
+            None => &self.msg_list,
+            Some(idx) => &self.views[idx].msg_list,
+        }
+    }
+
+    /// Acknowledge the urgent alert of a message, or all of them if `msg_id` is `None`
+    fn acknowledge(&mut self, msg_id: Option<&str>) {
+        self.urgent
+            .retain(|a| msg_id.map(|id| id != a.msg_id).unwrap_or(false));
+        let lists = std::iter::once(&mut self.msg_list)
+            .chain(self.views.iter_mut().map(|v| &mut v.msg_list));
+        for list in lists {
+            for msg in list.iter_mut() {
+                if msg_id.map(|id| id == msg.id()).unwrap_or(true) {
+                    msg.urgent = false;
+                }
+            }
+        }
+    }
+
+    /// Drop the urgent alerts whose message left every message list, they cannot be acknowledged
+    fn prune_urgent(&mut self) {
+        let lists: Vec<&VecDeque<TwitchMsg>> = std::iter::once(&self.msg_list)
+            .chain(self.views.iter().map(|v| &v.msg_list))
+            .collect();
+        self.urgent.retain(|a| {
+            lists
+                .iter()
+                .any(|list| list.iter().any(|msg| msg.id() == a.msg_id))
+        });
+    }
+
+    fn log_mut(&mut self, view: Option<usize>) -> &mut Option<LogTarget> {
+        match view {
+            None => &mut self.log,
+            Some(idx) => &mut self.views[idx].log,
+        }
+    }
 }
 
 pub struct ChannelManager {
@@ -277,6 +571,13 @@ pub struct ChannelManager {
     shared_data: Arc<Mutex<SharedData>>,
     connected: bool,
     client: Arc<tokio::sync::Mutex<IrcClient>>,
+    urgent_task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for ChannelManager {
+    fn drop(&mut self) {
+        self.urgent_task.abort();
+    }
 }
 
 impl ChannelManager {
@@ -285,23 +586,31 @@ impl ChannelManager {
         channel: impl ToString,
         max_msg_count: usize,
         filter: Filter,
+        library: FilterLibrary,
     ) -> ChannelManager {
         let shared_data = Arc::new(Mutex::new(SharedData {
             msg_list: VecDeque::new(),
-            filtered_msg_list: VecDeque::new(),
-            filter,
+            views: vec![FilterView::new(DEFAULT_VIEW_NAME, filter)],
+            rules: vec![],
+            duplicates: DuplicateSettings::default(),
+            library,
+            alert_sound: None,
+            alert_settings: AlertSettings::default(),
+            command: CommandRunner::default(),
+            urgent: vec![],
             max_msg_count,
             state: ChannelConnectionState::Uninitialized,
             log: None,
-            log_filtered: None,
-            alert: None,
-            has_unread_filtered_msg: false,
+            log_settings: SharedLogSettings::default(),
+            store: None,
         }));
+        let urgent_task = ASYNC_RUNTIME.spawn(repeat_urgent_alerts(shared_data.clone()));
         Self {
             channel_name: channel.to_string().to_lowercase(),
             shared_data,
             client,
             connected: false,
+            urgent_task,
         }
     }
 
@@ -332,15 +641,11 @@ impl ChannelManager {
         self.connected
     }
 
-    pub fn get_n_msg(&self, range: std::ops::Range<usize>, filtered: bool) -> Vec<TwitchMsg> {
+    pub fn get_n_msg(&self, range: std::ops::Range<usize>, view: Option<usize>) -> Vec<TwitchMsg> {
         ASYNC_RUNTIME.block_on(async {
             let mut result = vec![];
             let lock = self.shared_data.lock().await;
-            let msg_list = if filtered {
-                &lock.filtered_msg_list
-            } else {
-                &lock.msg_list
-            };
+            let msg_list = lock.msg_list(view);
             for i in range {
                 result.push(msg_list[i].clone());
             }
@@ -348,15 +653,11 @@ impl ChannelManager {
         })
     }
 
-    pub fn get_msg(&self, filtered: bool) -> Vec<TwitchMsg> {
+    pub fn get_msg(&self, view: Option<usize>) -> Vec<TwitchMsg> {
         ASYNC_RUNTIME.block_on(async {
             let mut result = vec![];
             let lock = self.shared_data.lock().await;
-            let msg_list = if filtered {
-                &lock.filtered_msg_list
-            } else {
-                &lock.msg_list
-            };
+            let msg_list = lock.msg_list(view);
             for msg in msg_list.iter() {
                 result.push(msg.clone());
             }
@@ -364,100 +665,524 @@ impl ChannelManager {
         })
     }
 
-    pub fn get_msg_count(&self, filtered: bool) -> usize {
+    /// The reply thread of `root_id` in the message buffers, completed with `stored`,
+    /// the messages of [`Self::stored_thread`]
+    pub fn get_thread(&self, root_id: &str, stored: &[TwitchMsg]) -> Vec<TwitchMsg> {
         ASYNC_RUNTIME.block_on(async {
-            if filtered {
-                self.shared_data.lock().await.filtered_msg_list.len()
-            } else {
-                self.shared_data.lock().await.msg_list.len()
-            }
+            let lock = self.shared_data.lock().await;
+            // Buffered messages come first, they have the highlights of the rules
+            collect_thread(
+                lock.msg_list
+                    .iter()
+                    .chain(lock.views.iter().flat_map(|v| v.msg_list.iter()))
+                    .chain(stored.iter()),
+                root_id,
+            )
         })
     }
 
+    pub fn get_msg_count(&self, view: Option<usize>) -> usize {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.msg_list(view).len() })
+    }
+
     pub fn state(&self) -> ChannelConnectionState {
         ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.state })
     }
 
-    pub fn mut_filter<F>(&mut self, op: F)
+    pub fn mut_filter<F, R>(&mut self, view: usize, op: F) -> R
     where
-        F: FnOnce(&mut Filter),
+        F: FnOnce(&mut Filter) -> R,
     {
-        ASYNC_RUNTIME.block_on(async {
-            op(&mut self.shared_data.lock().await.filter);
-        })
+        ASYNC_RUNTIME.block_on(async { op(&mut self.shared_data.lock().await.views[view].filter) })
     }
 
-    pub fn get_filter_state(&self) -> FilterState {
-        ASYNC_RUNTIME.block_on(async { (&self.shared_data.lock().await.filter).into() })
+    pub fn get_filter_state(&self, view: usize) -> FilterState {
+        ASYNC_RUNTIME.block_on(async { (&self.shared_data.lock().await.views[view].filter).into() })
     }
 
-    pub fn set_filter(&mut self, state: &FilterState) -> Result<(), regex::Error> {
+    pub fn set_filter(&mut self, view: usize, state: &FilterState) -> Result<(), FilterError> {
         ASYNC_RUNTIME.block_on(async {
             match state.try_into() {
-                Ok(f) => self.shared_data.lock().await.filter = f,
+                Ok(f) => self.shared_data.lock().await.views[view].filter = f,
                 Err(e) => return Err(e),
             }
             Ok(())
         })
     }
 
+    /// Rebuild the message list of a view by running its current filter over the message buffer.
+    /// No alert is played, and matching messages are written to the view log only if `log` is set.
+    pub fn reapply_filter(&mut self, view: usize, log: bool) {
+        ASYNC_RUNTIME.block_on(async {
+            let mut lock = self.shared_data.lock().await;
+            let shared_data = &mut *lock;
+            let view = &mut shared_data.views[view];
+            view.msg_list = {
+                let library = shared_data.library.read().unwrap();
+                shared_data
+                    .msg_list
+                    .iter()
+                    .filter(|msg| view.test(&library, msg))
+                    .cloned()
+                    .collect()
+            };
+            if log {
+                if let Some(log) = &view.log {
+                    log.write_all(&view.msg_list).await;
+                }
+            }
+            shared_data.prune_urgent();
+        })
+    }
+
+    pub fn library_refs(&self, view: usize) -> Vec<String> {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.views[view]
+                .library_refs
+                .clone()
+        })
+    }
+
+    pub fn set_library_refs(&mut self, view: usize, refs: Vec<String>) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.views[view].library_refs = refs;
+        });
+    }
+
+    /// Follow the changes of the library: each old name maps to its new name,
+    /// or to `None` if the library filter was removed
+    pub fn update_library_refs(&mut self, renames: &HashMap<String, Option<String>>) {
+        ASYNC_RUNTIME.block_on(async {
+            for view in self.shared_data.lock().await.views.iter_mut() {
+                view.library_refs = view
+                    .library_refs
+                    .iter()
+                    .filter_map(|name| match renames.get(name) {
+                        Some(new) => new.clone(),
+                        None => Some(name.clone()),
+                    })
+                    .collect();
+            }
+        });
+    }
+
+    pub fn rule_states(&self) -> Vec<RuleState> {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data
+                .lock()
+                .await
+                .rules
+                .iter()
+                .map(|r| r.state().clone())
+                .collect()
+        })
+    }
+
+    pub fn rule_log_errors(&self) -> Vec<Option<String>> {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data
+                .lock()
+                .await
+                .rules
+                .iter()
+                .map(|r| r.log_error())
+                .collect()
+        })
+    }
+
+    /// `alert` is used by the rules with sound enabled
+    pub fn set_rules(
+        &mut self,
+        states: &[RuleState],
+        alert: &AlertPlayer,
+    ) -> Result<(), FilterError> {
+        let mut rules = vec![];
+        for state in states.iter() {
+            let mut rule = Rule::try_from(state)?;
+            rule.set_alert(Some(alert.clone()));
+            rules.push(rule);
+        }
+        ASYNC_RUNTIME.block_on(async {
+            let mut lock = self.shared_data.lock().await;
+            let mut old_rules = std::mem::take(&mut lock.rules);
+            for rule in rules.iter_mut() {
+                let state = rule.state();
+                // A log writing the same file keeps its writer, two writers would race on it
+                let kept = old_rules
+                    .iter_mut()
+                    .find(|old| {
+                        old.state().name == state.name
+                            && old.state().log == state.log
+                            && old.state().log_format == state.log_format
+                    })
+                    .and_then(|old| old.take_log());
+                let log = kept.or_else(|| {
+                    state.log.clone().map(|t| {
+                        LogTarget::new(
+                            t,
+                            state.log_format,
+                            &state.name,
+                            &self.channel_name,
+                            lock.log_settings.clone(),
+                        )
+                    })
+                });
+                rule.set_log(log);
+            }
+            lock.rules = rules;
+            // The alerts of removed or no longer urgent rules stop,
+            // their messages are acknowledged unless another rule still alerts
+            let (kept, removed): (Vec<UrgentAlert>, Vec<UrgentAlert>) =
+                std::mem::take(&mut lock.urgent).into_iter().partition(|a| {
+                    states.iter().any(|s| {
+                        s.name == a.rule_name
+                            && s.condition == a.rule_condition
+                            && s.urgent.is_some()
+                    })
+                });
+            lock.urgent = kept;
+            for alert in removed {
+                if !lock.urgent.iter().any(|a| a.msg_id == alert.msg_id) {
+                    lock.acknowledge(Some(&alert.msg_id));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn view_count(&self) -> usize {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.views.len() })
+    }
+
+    pub fn view_names(&self) -> Vec<String> {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data
+                .lock()
+                .await
+                .views
+                .iter()
+                .map(|v| v.name.clone())
+                .collect()
+        })
+    }
+
+    pub fn add_view(&mut self, name: impl ToString, filter: Filter) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data
+                .lock()
+                .await
+                .views
+                .push(FilterView::new(name, filter));
+        });
+    }
+
+    pub fn remove_view(&mut self, view: usize) {
+        ASYNC_RUNTIME.block_on(async {
+            let mut lock = self.shared_data.lock().await;
+            lock.views.remove(view);
+            lock.prune_urgent();
+        });
+    }
+
+    pub fn rename_view(&mut self, view: usize, name: impl ToString) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.views[view].name = name.to_string();
+        });
+    }
+
     pub fn channel_name(&self) -> &str {
         &self.channel_name
     }
 
-    pub fn set_log(&mut self, p: Option<PathBuf>) {
+    /// `template` is a fixed path or a path template, see [`crate::chat_log`]
+    pub fn set_log(&mut self, view: Option<usize>, template: Option<PathBuf>, format: LogFormat) {
         ASYNC_RUNTIME.block_on(async {
-            self.shared_data.lock().await.log = p.map(|p| Ok(p));
+            let mut lock = self.shared_data.lock().await;
+            let name = match view {
+                None => ALL_MSG_LOG_NAME.to_owned(),
+                Some(idx) => lock.views[idx].name.clone(),
+            };
+            let settings = lock.log_settings.clone();
+            *lock.log_mut(view) =
+                template.map(|t| LogTarget::new(t, format, &name, &self.channel_name, settings));
         });
     }
 
-    pub fn log_status(&self) -> Option<Result<PathBuf, String>> {
+    pub fn log_format(&self, view: Option<usize>) -> LogFormat {
         ASYNC_RUNTIME.block_on(async {
             self.shared_data
                 .lock()
                 .await
-                .log
+                .log_mut(view)
                 .as_ref()
-                .map(|r: &Result<PathBuf, std::io::Error>| {
-                    r.as_ref().map_err(|e| e.to_string()).map(|p| p.clone())
+                .map(|l| l.format())
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn log_template(&self, view: Option<usize>) -> Option<PathBuf> {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data
+                .lock()
+                .await
+                .log_mut(view)
+                .as_ref()
+                .map(|l| l.template().to_owned())
+        })
+    }
+
+    /// The file written and the count of messages dropped because the writer was behind,
+    /// or the error which stopped the log
+    pub fn log_status(&self, view: Option<usize>) -> Option<Result<(PathBuf, usize), String>> {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data
+                .lock()
+                .await
+                .log_mut(view)
+                .as_ref()
+                .map(|l| match l.error() {
+                    Some(e) => Err(e),
+                    None => Ok((l.path(), l.dropped())),
                 })
         })
     }
 
-    pub fn set_filtered_log(&mut self, p: Option<PathBuf>) {
+    pub fn log_settings(&self) -> LogSettings {
         ASYNC_RUNTIME.block_on(async {
-            self.shared_data.lock().await.log_filtered = p.map(|p| Ok(p));
+            self.shared_data
+                .lock()
+                .await
+                .log_settings
+                .read()
+                .unwrap()
+                .clone()
+        })
+    }
+
+    pub fn set_log_settings(&mut self, settings: LogSettings) {
+        ASYNC_RUNTIME.block_on(async {
+            *self.shared_data.lock().await.log_settings.write().unwrap() = settings;
+        });
+    }
+
+    /// Open the message store of the channel, or close it if the store is disabled.
+    /// The store is kept if its settings did not change.
+    pub fn set_store(&mut self, settings: &StoreSettings) {
+        ASYNC_RUNTIME.block_on(async {
+            let mut lock = self.shared_data.lock().await;
+            let current = lock.store.as_ref().map(|s| s.settings());
+            if current == Some(settings) || (current.is_none() && !settings.enabled) {
+                return;
+            }
+            // The new store indexes the files once everything is written
+            if let Some(store) = lock.store.take() {
+                store.flush().await;
+            }
+            if settings.enabled {
+                lock.store = Some(MessageStore::open(settings, &self.channel_name));
+            }
         });
     }
 
-    pub fn filtered_log_status(&self) -> Option<Result<PathBuf, String>> {
+    /// Messages of the message store in the reply thread of `root_id`, its root included.
+    /// This reads the store files, so it is done once per thread rather than every frame.
+    pub fn stored_thread(&self, root_id: &str) -> Vec<TwitchMsg> {
+        [
+            QuerySpec {
+                id: Some(root_id.to_owned()),
+                ..Default::default()
+            },
+            QuerySpec {
+                thread: Some(root_id.to_owned()),
+                ..Default::default()
+            },
+        ]
+        .into_iter()
+        .flat_map(|spec| self.query(spec).unwrap_or_default())
+        .collect()
+    }
+
+    /// Messages of the message store matching `spec`, oldest first.
+    /// Empty if the store is disabled. The files are read after the channel is unlocked.
+    pub fn query(&self, spec: QuerySpec) -> std::io::Result<Vec<TwitchMsg>> {
+        let store = ASYNC_RUNTIME.block_on(async {
+            let store = self.shared_data.lock().await.store.clone();
+            if let Some(store) = &store {
+                store.flush().await;
+            }
+            store
+        });
+        match store {
+            Some(store) => store.query(&spec),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Number of messages of a user in the message store, `None` if the store is disabled
+    pub fn user_msg_count(&self, login: &str) -> Option<usize> {
         ASYNC_RUNTIME.block_on(async {
             self.shared_data
                 .lock()
                 .await
-                .log_filtered
+                .store
                 .as_ref()
-                .map(|r| r.as_ref().map_err(|e| e.to_string()).map(|p| p.clone()))
+                .map(|s| s.user_msg_count(login))
+        })
+    }
+
+    /// Write the buffered messages of every log and of the message store of the channel to disk
+    pub fn flush_logs(&self) {
+        ASYNC_RUNTIME.block_on(async {
+            let lock = self.shared_data.lock().await;
+            if let Some(store) = &lock.store {
+                store.flush().await;
+            }
+            let logs = std::iter::once(&lock.log)
+                .chain(lock.views.iter().map(|v| &v.log))
+                .flatten()
+                .chain(lock.rules.iter().filter_map(|r| r.log()));
+            for log in logs {
+                log.flush().await;
+            }
+        });
+    }
+
+    /// Log files written for this channel by the default template or by one of its logs,
+    /// including rotated and compressed files
+    pub fn log_files(&self) -> Vec<PathBuf> {
+        ASYNC_RUNTIME.block_on(async {
+            let lock = self.shared_data.lock().await;
+            let settings = lock.log_settings.read().unwrap().clone();
+            let templates = std::iter::once(&lock.log)
+                .chain(lock.views.iter().map(|v| &v.log))
+                .flatten()
+                .chain(lock.rules.iter().filter_map(|r| r.log()))
+                .map(|l| l.template().to_owned());
+            let mut files: Vec<PathBuf> = std::iter::once(settings.template.clone().into())
+                .chain(templates)
+                .flat_map(|t: PathBuf| find_log_files(&t, &settings, &self.channel_name))
+                .collect();
+            files.sort();
+            files.dedup();
+            files
+        })
+    }
+
+    pub fn is_logging(&self) -> bool {
+        ASYNC_RUNTIME.block_on(async {
+            let lock = self.shared_data.lock().await;
+            lock.log.is_some() || lock.views.iter().any(|v| v.log.is_some())
         })
     }
 
-    pub fn alert(&self) -> bool {
-        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.alert.is_some() })
+    pub fn alert(&self, view: usize) -> bool {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.views[view].alert.is_some() })
     }
 
-    pub fn set_alert(&mut self, alert: Option<AlertPlayer>) {
+    pub fn set_alert(&mut self, view: usize, alert: Option<AlertPlayer>) {
         ASYNC_RUNTIME.block_on(async {
-            self.shared_data.lock().await.alert = alert;
+            self.shared_data.lock().await.views[view].alert = alert;
         });
     }
 
+    pub fn alert_sound(&self) -> Option<PathBuf> {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.alert_sound.clone() })
+    }
+
+    pub fn set_alert_sound(&mut self, path: Option<PathBuf>) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.alert_sound = path;
+        });
+    }
+
+    pub fn alert_settings(&self) -> AlertSettings {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.alert_settings })
+    }
+
+    pub fn set_alert_settings(&mut self, settings: AlertSettings) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.alert_settings = settings;
+        });
+    }
+
+    pub fn command_settings(&self) -> CommandSettings {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.command.settings().clone() })
+    }
+
+    pub fn set_command_settings(&mut self, settings: CommandSettings) -> Result<(), String> {
+        ASYNC_RUNTIME
+            .block_on(async { self.shared_data.lock().await.command.set_settings(settings) })
+    }
+
+    pub fn command_status(&self) -> CommandStatus {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.command.status() })
+    }
+
+    pub fn alert_condition(&self, view: usize) -> AlertCondition {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.views[view]
+                .alert_tracker
+                .condition()
+        })
+    }
+
+    pub fn set_alert_condition(&mut self, view: usize, condition: AlertCondition) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.views[view]
+                .alert_tracker
+                .set_condition(condition)
+        })
+    }
+
+    /// Number of alerts, the counts behind the last one and the messages in the current burst window
+    pub fn alert_summary(&self, view: usize) -> String {
+        ASYNC_RUNTIME.block_on(async {
+            let lock = self.shared_data.lock().await;
+            let tracker = &lock.views[view].alert_tracker;
+            let mut summary = format!("Alerts: {}", tracker.alert_count());
+            if let AlertCondition::Burst { count, .. } = tracker.condition() {
+                summary += &format!(
+                    ", in window: {}/{}",
+                    tracker.window_count(Utc::now()),
+                    count
+                );
+            }
+            if let Some(record) = tracker.last_alert() {
+                summary += &format!("\nLast alert {}", record);
+            }
+            summary
+        })
+    }
+
+    /// Unacknowledged urgent messages count as unread even after the channel is viewed
     pub fn has_unread_filtered_msg(&self) -> bool {
-        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.has_unread_filtered_msg })
+        ASYNC_RUNTIME.block_on(async {
+            let lock = self.shared_data.lock().await;
+            !lock.urgent.is_empty() || lock.views.iter().any(|v| v.has_unread_msg)
+        })
+    }
+
+    pub fn has_urgent_alert(&self) -> bool {
+        ASYNC_RUNTIME.block_on(async { !self.shared_data.lock().await.urgent.is_empty() })
+    }
+
+    pub fn acknowledge(&mut self, msg_id: &str) {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.acknowledge(Some(msg_id)) });
+    }
+
+    pub fn acknowledge_all(&mut self) {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.acknowledge(None) });
     }
 
-    pub fn read(&mut self) {
+    pub fn view_has_unread_msg(&self, view: usize) -> bool {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.views[view].has_unread_msg })
+    }
+
+    pub fn read(&mut self, view: usize) {
         ASYNC_RUNTIME.block_on(async {
-            self.shared_data.lock().await.has_unread_filtered_msg = false;
+            if let Some(v) = self.shared_data.lock().await.views.get_mut(view) {
+                v.has_unread_msg = false;
+            }
         });
     }
 
@@ -471,13 +1196,24 @@ impl ChannelManager {
         });
     }
 
-    pub fn clear_msg(&mut self, filtered: bool) {
+    pub fn clear_msg(&mut self, view: Option<usize>) {
         ASYNC_RUNTIME.block_on(async {
-            if filtered {
-                self.shared_data.lock().await.filtered_msg_list.clear();
-            } else {
-                self.shared_data.lock().await.msg_list.clear();
+            let mut lock = self.shared_data.lock().await;
+            match view {
+                None => lock.msg_list.clear(),
+                Some(idx) => lock.views[idx].msg_list.clear(),
             }
+            lock.prune_urgent();
+        });
+    }
+
+    pub fn duplicate_settings(&self) -> DuplicateSettings {
+        ASYNC_RUNTIME.block_on(async { self.shared_data.lock().await.duplicates })
+    }
+
+    pub fn set_duplicate_settings(&mut self, settings: DuplicateSettings) {
+        ASYNC_RUNTIME.block_on(async {
+            self.shared_data.lock().await.duplicates = settings;
         });
     }
 
@@ -488,19 +1224,6 @@ impl ChannelManager {
     }
 }
 
-fn msg_to_str(msg: &TwitchMsg) -> String {
-    format!(
-        "{} {}({}): {}\n",
-        msg.sent_time()
-            .unwrap()
-            .with_timezone(&chrono::Local)
-            .format("%Y-%m-%d %H:%M:%S"),
-        msg.sender_display(),
-        msg.sender_login(),
-        msg.payload()
-    )
-}
-
 #[derive(Debug, Clone)]
 pub struct TwitchMsg {
     _source: Message,
@@ -511,6 +1234,14 @@ pub struct TwitchMsg {
     id: String,
     #[allow(dead_code)]
     paid_info: Option<PaidInfo>,
+    highlight: Option<[u8; 3]>,
+    /// Matched by an urgent rule and not acknowledged yet
+    urgent: bool,
+    /// Display names of the senders of collapsed duplicates
+    repeat_senders: Vec<String>,
+    last_repeat: Option<DateTime<Utc>>,
+    /// Text compared to find duplicates, computed once
+    normalized: OnceCell<String>,
 }
 
 impl TwitchMsg {
@@ -530,6 +1261,60 @@ impl TwitchMsg {
         &self.channel
     }
 
+    /// Number of times this message was sent, including collapsed duplicates
+    pub fn repeat_count(&self) -> usize {
+        self.repeat_senders.len() + 1
+    }
+
+    /// Lowercase words of the text, used to compare duplicates
+    fn normalized_text(&self) -> &str {
+        self.normalized
+            .get_or_init(|| normalize_text(&self.payload))
+    }
+
+    pub fn repeat_senders(&self) -> &[String] {
+        &self.repeat_senders
+    }
+
+    /// Time of the last collapsed duplicate, or the sent time
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        self.last_repeat.or_else(|| self.sent_time())
+    }
+
+    fn add_repeat(&mut self, duplicate: &TwitchMsg, time: DateTime<Utc>) {
+        self.repeat_senders
+            .push(duplicate.sender_display().to_owned());
+        self.last_repeat = Some(time);
+    }
+
+    /// Background color set by a matching rule
+    pub fn highlight(&self) -> Option<[u8; 3]> {
+        self.highlight
+    }
+
+    pub fn is_urgent(&self) -> bool {
+        self.urgent
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.tag("user-id").map(|s| s.as_str())
+    }
+
+    /// Every tag of the message, in the order they were received
+    pub fn tags(&self) -> Vec<(&str, Option<&str>)> {
+        self._source
+            .tags
+            .iter()
+            .flatten()
+            .map(|Tag(name, value)| (name.as_str(), value.as_deref()))
+            .collect()
+    }
+
+    /// The IRC line of the message, with its tags
+    pub fn raw_line(&self) -> String {
+        self._source.to_string()
+    }
+
     pub fn tag(&self, tag_name: &str) -> Option<&String> {
         if let Some(tags) = &self._source.tags {
             search_tag(tag_name, tags)
@@ -565,12 +1350,10 @@ impl TwitchMsg {
         None
     }
 
+    /// `None` if the `tmi-sent-ts` tag is missing or invalid
     pub fn sent_time(&self) -> Option<DateTime<Utc>> {
-        self.tag("tmi-sent-ts").map(|ts| {
-            chrono::Utc
-                .timestamp_millis_opt(ts.trim().parse::<i64>().unwrap())
-                .unwrap()
-        })
+        let ts = self.tag("tmi-sent-ts")?.trim().parse::<i64>().ok()?;
+        chrono::Utc.timestamp_millis_opt(ts).single()
     }
 
     pub fn name_color(&self) -> Option<[u8; 3]> {
@@ -591,6 +1374,114 @@ impl TwitchMsg {
     pub fn id(&self) -> &str {
         &self.id
     }
+
+    pub fn reply_parent_id(&self) -> Option<&String> {
+        self.tag("reply-parent-msg-id")
+    }
+
+    pub fn reply_thread_root_id(&self) -> Option<&String> {
+        self.tag("reply-thread-parent-msg-id")
+            .or_else(|| self.reply_parent_id())
+    }
+
+    /// Rebuild the replied message from the `reply-parent-*` tags.
+    /// Used when the parent itself is no longer in the message buffer.
+    pub fn reply_parent(&self) -> Option<TwitchMsg> {
+        let id = self.reply_parent_id()?;
+        let login = self.tag("reply-parent-user-login")?;
+        let display = self.tag("reply-parent-display-name").unwrap_or(login);
+        let body = self
+            .tag("reply-parent-msg-body")
+            .map(|s| s.as_str())
+            .unwrap_or("");
+        build_placeholder_msg(&self.channel, id, login, display, body)
+    }
+
+    /// Rebuild the root message of the reply thread from the tags.
+    /// The body is only known when the root is the direct parent.
+    pub fn reply_thread_root(&self) -> Option<TwitchMsg> {
+        let root_id = self.reply_thread_root_id()?;
+        if Some(root_id) == self.reply_parent_id() {
+            return self.reply_parent();
+        }
+        let login = self.tag("reply-thread-parent-user-login")?;
+        let display = self
+            .tag("reply-thread-parent-display-name")
+            .unwrap_or(login);
+        build_placeholder_msg(&self.channel, root_id, login, display, "")
+    }
+}
+
+/// Build a message for testing filters. A raw IRC `PRIVMSG` line is parsed with its tags,
+/// any other text is used as the body of a message sent by `login`.
+pub fn build_sample_msg(channel: &str, login: &str, text: &str) -> Option<TwitchMsg> {
+    let text = text.trim_end();
+    if text.starts_with('@') || text.starts_with(':') {
+        if let Some(msg) = parse_irc_line(text) {
+            return Some(msg);
+        }
+    }
+    let login = if login.is_empty() { "sample" } else { login };
+    build_placeholder_msg(channel, "sample", login, login, text)
+}
+
+/// Parse a raw IRC `PRIVMSG` line with its tags
+pub fn parse_irc_line(line: &str) -> Option<TwitchMsg> {
+    line.parse::<Message>()
+        .ok()
+        .and_then(|m| TwitchMsg::try_from(m).ok())
+}
+
+/// Build a message from its parts. `tags` need at least `id` and `display-name`.
+pub fn build_msg(
+    channel: &str,
+    login: &str,
+    tags: Vec<(String, Option<String>)>,
+    body: &str,
+) -> Option<TwitchMsg> {
+    let prefix = format!("{}!{}@{}.tmi.twitch.tv", login, login, login);
+    let tags = tags
+        .into_iter()
+        .map(|(name, value)| Tag(name, value))
+        .collect();
+    Message::with_tags(Some(tags), Some(&prefix), "PRIVMSG", vec![channel, body])
+        .ok()
+        .and_then(|m| TwitchMsg::try_from(m).ok())
+}
+
+fn build_placeholder_msg(
+    channel: &str,
+    id: &str,
+    login: &str,
+    display: &str,
+    body: &str,
+) -> Option<TwitchMsg> {
+    let tags = vec![
+        ("id".to_string(), Some(id.to_string())),
+        ("display-name".to_string(), Some(display.to_string())),
+    ];
+    build_msg(channel, login, tags, body)
+}
+
+/// Collect the root and every reply of a reply thread from the message buffers.
+/// If the root has been evicted, it is rebuilt from the reply tags.
+fn collect_thread<'a>(lists: impl Iterator<Item = &'a TwitchMsg>, root_id: &str) -> Vec<TwitchMsg> {
+    let mut root = None;
+    let mut replies: Vec<TwitchMsg> = vec![];
+    for msg in lists {
+        if msg.id() == root_id {
+            root.get_or_insert_with(|| msg.clone());
+        } else if msg.reply_thread_root_id().map(|s| s.as_str()) == Some(root_id)
+            && !replies.iter().any(|m| m.id() == msg.id())
+        {
+            replies.push(msg.clone());
+        }
+    }
+    replies.sort_by_key(|m| m.sent_time());
+    if root.is_none() {
+        root = replies.iter().find_map(|m| m.reply_thread_root());
+    }
+    root.into_iter().chain(replies).collect()
 }
 
 impl TryFrom<Message> for TwitchMsg {
@@ -624,36 +1515,7 @@ impl TryFrom<Message> for TwitchMsg {
         } else {
             return Err(());
         };
-        let paid_info = if let Some(tags) = &value.tags {
-            if let Some(amount) = search_tag("pinned-chat-paid-amount", tags) {
-                let amount = amount.parse::<f32>().unwrap();
-                let canonical_amount = search_tag("pinned-chat-paid-canonical-amount", tags)
-                    .unwrap()
-                    .parse::<f32>()
-                    .unwrap();
-                let currency = search_tag("pinned-chat-paid-currency", tags)
-                    .unwrap()
-                    .to_owned();
-                let exponent = search_tag("pinned-chat-paid-exponent", tags)
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap();
-                let paid_level = search_tag("pinned-chat-paid-level", tags)
-                    .unwrap()
-                    .to_owned();
-                Some(PaidInfo {
-                    amount,
-                    canonical_amount,
-                    currency,
-                    exponent,
-                    paid_level,
-                })
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+        let paid_info = value.tags.as_deref().and_then(paid_info);
 
         Ok(Self {
             _source: value,
@@ -663,10 +1525,30 @@ impl TryFrom<Message> for TwitchMsg {
             channel,
             id,
             paid_info,
+            highlight: None,
+            urgent: false,
+            repeat_senders: vec![],
+            last_repeat: None,
+            normalized: OnceCell::new(),
         })
     }
 }
 
+/// `None` unless every paid tag is present and valid
+fn paid_info(tags: &[Tag]) -> Option<PaidInfo> {
+    Some(PaidInfo {
+        amount: search_tag("pinned-chat-paid-amount", tags)?.parse().ok()?,
+        canonical_amount: search_tag("pinned-chat-paid-canonical-amount", tags)?
+            .parse()
+            .ok()?,
+        currency: search_tag("pinned-chat-paid-currency", tags)?.to_owned(),
+        exponent: search_tag("pinned-chat-paid-exponent", tags)?
+            .parse()
+            .ok()?,
+        paid_level: search_tag("pinned-chat-paid-level", tags)?.to_owned(),
+    })
+}
+
 fn search_tag<'a>(target: &str, tags: &'a [Tag]) -> Option<&'a String> {
     for tag in tags.iter() {
         if target == tag.0 {
@@ -686,3 +1568,59 @@ pub struct PaidInfo {
     exponent: usize,
     paid_level: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAID_TAGS: &str = "pinned-chat-paid-amount=3500;pinned-chat-paid-canonical-amount=3500;\
+        pinned-chat-paid-currency=TWD;pinned-chat-paid-exponent=2;pinned-chat-paid-level=ONE";
+
+    fn line(tags: &str) -> String {
+        format!(
+            "@display-name=Foo;id=abc-1;user-id=42;{} :foo!foo@foo.tmi.twitch.tv PRIVMSG #chan :hello",
+            tags
+        )
+    }
+
+    #[test]
+    fn sent_time() {
+        let msg = parse_irc_line(&line("tmi-sent-ts=1700000000000")).unwrap();
+        assert_eq!(msg.sent_time().unwrap().timestamp(), 1_700_000_000);
+        for tags in [
+            "tmi-sent-ts=abc",
+            "tmi-sent-ts=",
+            "tmi-sent-ts=99999999999999999",
+            "turbo=0",
+        ] {
+            let msg = parse_irc_line(&line(tags)).unwrap();
+            assert!(msg.sent_time().is_none(), "{}", tags);
+        }
+    }
+
+    #[test]
+    fn paid_info() {
+        let msg = parse_irc_line(&line(PAID_TAGS)).unwrap();
+        assert!(msg.paid_info.is_some());
+        let bad_amount = PAID_TAGS.replace("amount=3500;", "amount=lots;");
+        for tags in ["pinned-chat-paid-amount=5", &bad_amount, "turbo=0"] {
+            let msg = parse_irc_line(&line(tags)).unwrap();
+            assert!(msg.paid_info.is_none(), "{}", tags);
+        }
+    }
+
+    #[test]
+    fn sample_msg() {
+        let msg = build_sample_msg(
+            "chan",
+            "",
+            &line("tmi-sent-ts=abc;pinned-chat-paid-amount=5"),
+        )
+        .unwrap();
+        assert_eq!(msg.payload(), "hello");
+        assert!(msg.sent_time().is_none());
+        let msg = build_sample_msg("chan", "", "just text").unwrap();
+        assert_eq!(msg.payload(), "just text");
+        assert_eq!(msg.sender_login(), "sample");
+    }
+}
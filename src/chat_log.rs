@@ -0,0 +1,1085 @@
+//! Chat log files: the format of each logged message, and the files written from path
+//! templates with their rotation.
+//!
+//! A template like `{dir}/{channel}/{view}/{yyyy}-{mm}-{dd}.{ext}` is expanded for each message,
+//! so date placeholders start a new file every day. `{view}` is the filter view or rule name,
+//! `all` for the unfiltered messages, and `{ext}` is the extension of the log format.
+//! A template without placeholders is a fixed file.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWriteExt, BufWriter},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+};
+
+use crate::{
+    chat_client::{build_msg, parse_irc_line, TwitchMsg},
+    template::expand,
+    ASYNC_RUNTIME,
+};
+
+/// `{view}` of the log of the unfiltered messages
+pub const ALL_MSG_LOG_NAME: &str = "all";
+
+const CSV_HEADER: &str =
+    "time,channel,id,login,display,user_id,color,badges,bits,reply_parent_id,reply_parent_login,text\n";
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default, Debug)]
+pub enum LogFormat {
+    /// `time display(login): message` lines
+    #[default]
+    Plain,
+    /// A JSON object per message with the parsed fields and all the tags
+    JsonLines,
+    /// Comma separated values, with a header line at the start of the file
+    Csv,
+    /// The IRC lines with their tags, they can be parsed back as messages
+    Irc,
+}
+
+impl LogFormat {
+    pub const ALL: [LogFormat; 4] = [
+        LogFormat::Plain,
+        LogFormat::JsonLines,
+        LogFormat::Csv,
+        LogFormat::Irc,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogFormat::Plain => "Plain text",
+            LogFormat::JsonLines => "JSON Lines",
+            LogFormat::Csv => "CSV",
+            LogFormat::Irc => "Raw IRC",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Plain => "log",
+            LogFormat::JsonLines => "jsonl",
+            LogFormat::Csv => "csv",
+            LogFormat::Irc => "irc",
+        }
+    }
+
+    /// The format of a log file from its extension, plain text if it is unknown
+    pub fn from_path(path: &Path) -> Self {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = name.trim_end_matches(".gz");
+        let ext = Path::new(name).extension().unwrap_or_default();
+        Self::ALL
+            .into_iter()
+            .find(|f| ext == f.extension())
+            .unwrap_or_default()
+    }
+
+    /// Written before the first message of an empty file
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            LogFormat::Csv => Some(CSV_HEADER),
+            _ => None,
+        }
+    }
+
+    /// The log line of a message, ending with a newline
+    pub fn format(&self, msg: &TwitchMsg) -> String {
+        match self {
+            LogFormat::Plain => format!(
+                "{} {}({}): {}\n",
+                sent_time(msg)
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S"),
+                msg.sender_display(),
+                msg.sender_login(),
+                msg.payload()
+            ),
+            LogFormat::JsonLines => to_json(msg),
+            LogFormat::Csv => to_csv(msg),
+            LogFormat::Irc => msg.raw_line().trim_end().to_owned() + "\n",
+        }
+    }
+
+    /// Parse a log line back into a message. Plain text lines have no message id,
+    /// their line number is used instead.
+    pub fn parse(&self, line: &str, channel: &str, line_number: usize) -> Option<TwitchMsg> {
+        match self {
+            LogFormat::Plain => from_plain(line, channel, line_number),
+            LogFormat::JsonLines => from_json(line),
+            LogFormat::Csv => from_csv(line),
+            LogFormat::Irc => parse_irc_line(line),
+        }
+    }
+}
+
+fn sent_time(msg: &TwitchMsg) -> DateTime<Utc> {
+    msg.sent_time().unwrap_or_else(Utc::now)
+}
+
+fn bits(msg: &TwitchMsg) -> Option<u64> {
+    msg.tag("bits").and_then(|b| b.parse().ok())
+}
+
+fn color(msg: &TwitchMsg) -> Option<String> {
+    msg.name_color()
+        .map(|[r, g, b]| format!("#{:02X}{:02X}{:02X}", r, g, b))
+}
+
+/// A message of the JSON Lines format
+#[derive(Deserialize, Serialize)]
+struct JsonRecord {
+    #[serde(default)]
+    time: String,
+    channel: String,
+    #[serde(default)]
+    id: String,
+    login: String,
+    #[serde(default)]
+    display: String,
+    user_id: Option<String>,
+    color: Option<String>,
+    #[serde(
+        default,
+        serialize_with = "ordered_map",
+        deserialize_with = "map_entries"
+    )]
+    badges: Vec<(String, String)>,
+    bits: Option<u64>,
+    reply_parent_id: Option<String>,
+    reply_parent_login: Option<String>,
+    text: String,
+    #[serde(
+        default,
+        serialize_with = "ordered_map",
+        deserialize_with = "map_entries"
+    )]
+    tags: Vec<(String, Option<String>)>,
+}
+
+/// Write entries as a JSON object, keeping their order
+fn ordered_map<S: Serializer, V: Serialize>(
+    entries: &[(String, V)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(entries.iter().map(|(key, value)| (key, value)))
+}
+
+fn map_entries<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, V)>, D::Error> {
+    Ok(BTreeMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .collect())
+}
+
+fn to_json(msg: &TwitchMsg) -> String {
+    let record = JsonRecord {
+        time: sent_time(msg).to_rfc3339_opts(SecondsFormat::Millis, true),
+        channel: msg.channel().to_owned(),
+        id: msg.id().to_owned(),
+        login: msg.sender_login().to_owned(),
+        display: msg.sender_display().to_owned(),
+        user_id: msg.user_id().map(|s| s.to_owned()),
+        color: color(msg),
+        badges: msg.badges(),
+        bits: bits(msg),
+        reply_parent_id: msg.reply_parent_id().cloned(),
+        reply_parent_login: msg.tag("reply-parent-user-login").cloned(),
+        text: msg.payload().to_owned(),
+        tags: msg
+            .tags()
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.map(|v| v.to_owned())))
+            .collect(),
+    };
+    serde_json::to_string(&record).unwrap() + "\n"
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn to_csv(msg: &TwitchMsg) -> String {
+    let fields = [
+        sent_time(msg).to_rfc3339_opts(SecondsFormat::Millis, true),
+        msg.channel().to_owned(),
+        msg.id().to_owned(),
+        msg.sender_login().to_owned(),
+        msg.sender_display().to_owned(),
+        msg.user_id().unwrap_or_default().to_owned(),
+        color(msg).unwrap_or_default(),
+        msg.tag("badges").cloned().unwrap_or_default(),
+        bits(msg).map(|b| b.to_string()).unwrap_or_default(),
+        msg.reply_parent_id().cloned().unwrap_or_default(),
+        msg.tag("reply-parent-user-login")
+            .cloned()
+            .unwrap_or_default(),
+        msg.payload().to_owned(),
+    ];
+    let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+    fields.join(",") + "\n"
+}
+
+fn tag(name: &str, value: impl ToString) -> (String, Option<String>) {
+    (name.to_owned(), Some(value.to_string()))
+}
+
+static PLAIN_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}) (.*?)\(([^()]*)\): (.*)$").unwrap()
+});
+
+fn from_plain(line: &str, channel: &str, line_number: usize) -> Option<TwitchMsg> {
+    let captures = PLAIN_LINE.captures(line)?;
+    let time = NaiveDateTime::parse_from_str(&captures[1], "%Y-%m-%d %H:%M:%S").ok()?;
+    let time = Local.from_local_datetime(&time).earliest()?;
+    let tags = vec![
+        tag("id", format!("line-{}", line_number)),
+        tag("display-name", &captures[2]),
+        tag("tmi-sent-ts", time.timestamp_millis()),
+    ];
+    let channel = format!("#{}", channel.trim_start_matches('#'));
+    build_msg(&channel, &captures[3], tags, &captures[4])
+}
+
+/// Split a CSV line, the fields written by [`to_csv`] have no line break
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            (true, '"') => quoted = false,
+            (false, '"') => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (_, c) => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn from_csv(line: &str) -> Option<TwitchMsg> {
+    let fields: [String; 12] = csv_fields(line).try_into().ok()?;
+    let [time, channel, id, login, display, user_id, color, badges, bits, reply_id, reply_login, text] =
+        fields;
+    // The header line stops here
+    let time = DateTime::parse_from_rfc3339(&time).ok()?;
+    let mut tags = vec![
+        tag("id", id),
+        tag("display-name", display),
+        tag("tmi-sent-ts", time.timestamp_millis()),
+    ];
+    let optional = [
+        ("user-id", user_id),
+        ("color", color),
+        ("badges", badges),
+        ("bits", bits),
+        ("reply-parent-msg-id", reply_id),
+        ("reply-parent-user-login", reply_login),
+    ];
+    tags.extend(
+        optional
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| tag(name, value)),
+    );
+    build_msg(&channel, &login, tags, &text)
+}
+
+fn from_json(line: &str) -> Option<TwitchMsg> {
+    let record: JsonRecord = serde_json::from_str(line).ok()?;
+    build_msg(&record.channel, &record.login, record.tags, &record.text)
+}
+
+/// When a log file is closed and a new one started
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    /// Only when the expanded template changes
+    Never,
+    /// Also on the first message of a new day
+    Daily,
+    /// Also before the file would grow beyond `max_mb` megabytes
+    Size { max_mb: u64 },
+}
+
+impl Rotation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rotation::Never => "Never",
+            Rotation::Daily => "Daily",
+            Rotation::Size { .. } => "By size",
+        }
+    }
+}
+
+/// Log file settings of a channel
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct LogSettings {
+    /// `{dir}` of the templates
+    pub dir: PathBuf,
+    /// Template of the logs started with the Log button
+    pub template: String,
+    pub rotation: Rotation,
+    /// Compress the files that are no longer written with gzip
+    pub gzip: bool,
+    /// Finished files kept in the directory of the current file, 0 keeps all of them
+    pub keep_files: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("logs"),
+            template: "{dir}/{channel}/{view}/{yyyy}-{mm}-{dd}.{ext}".to_owned(),
+            rotation: Rotation::Never,
+            gzip: false,
+            keep_files: 0,
+        }
+    }
+}
+
+fn template_fields(
+    settings: &LogSettings,
+    channel: &str,
+    view: &str,
+    ext: &str,
+    date: [String; 3],
+) -> Vec<(&'static str, String)> {
+    let [yyyy, mm, dd] = date;
+    vec![
+        ("dir", settings.dir.to_string_lossy().into_owned()),
+        ("channel", sanitize(channel)),
+        ("view", view.to_owned()),
+        ("ext", ext.to_owned()),
+        ("yyyy", yyyy),
+        ("mm", mm),
+        ("dd", dd),
+    ]
+}
+
+/// Marker of a placeholder matching any value in [`name_regex`]
+fn any_value(placeholder: &str) -> String {
+    format!("\0{}\0", placeholder)
+}
+
+/// Regex of a file or directory name from an expanded template, where placeholders replaced by
+/// [`any_value`] match any value. `rotated` also matches the rotation suffix and gzip extension.
+fn name_regex(name: &str, rotated: bool) -> Option<Regex> {
+    let pattern = match name.rfind('.').filter(|_| rotated) {
+        Some(dot) => format!(
+            r"^{}(\.[0-9-]+)?{}(\.gz)?$",
+            regex::escape(&name[..dot]),
+            regex::escape(&name[dot..])
+        ),
+        None if rotated => format!(r"^{}(\.[0-9-]+)?(\.gz)?$", regex::escape(name)),
+        None => format!("^{}$", regex::escape(name)),
+    };
+    let extensions: Vec<&str> = LogFormat::ALL.iter().map(|f| f.extension()).collect();
+    let pattern = pattern
+        .replace(&any_value("yyyy"), r"\d{4}")
+        .replace(&any_value("mm"), r"\d{2}")
+        .replace(&any_value("dd"), r"\d{2}")
+        .replace(&any_value("view"), ".+")
+        .replace(&any_value("ext"), &format!("(?:{})", extensions.join("|")));
+    Regex::new(&pattern).ok()
+}
+
+/// Make a name safe to use in a file name, `.` and `..` included
+pub(crate) fn sanitize(name: &str) -> String {
+    let name: String = name
+        .trim_start_matches('#')
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '{' | '}' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    if name.chars().all(|c| c == '.') {
+        name.replace('.', "_")
+    } else {
+        name
+    }
+}
+
+/// Name of a file closed by a rotation: the suffix goes before the extension
+fn rotated_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+/// Log settings of a channel, shared with the writers of its logs
+pub type SharedLogSettings = Arc<RwLock<LogSettings>>;
+
+/// Messages waiting to be written to a log, more are dropped
+const WRITER_QUEUE_LEN: usize = 4096;
+/// Buffered messages are written to disk at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum WriterCommand {
+    Msg(Box<TwitchMsg>),
+    /// Write the buffered messages to disk, then notify
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug)]
+struct WriterStatus {
+    /// The file written last
+    path: PathBuf,
+    /// The writer stops after the first error
+    error: Option<String>,
+    /// Messages dropped because the queue was full
+    dropped: usize,
+}
+
+struct CurrentFile {
+    path: PathBuf,
+    /// Day of the last write, for the daily rotation
+    day: NaiveDate,
+    len: u64,
+    writer: BufWriter<tokio::fs::File>,
+}
+
+/// The files of a log, owned by its writer task
+struct LogFile {
+    template: PathBuf,
+    format: LogFormat,
+    /// `{view}` of the template
+    name: String,
+    channel: String,
+    settings: SharedLogSettings,
+    current: Option<CurrentFile>,
+    /// Compression and retention of the last closed file, finished files are handled one by one
+    finishing: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl LogFile {
+    fn expand(&self, settings: &LogSettings, day: NaiveDate) -> PathBuf {
+        let date = ["%Y", "%m", "%d"].map(|f| day.format(f).to_string());
+        let fields = template_fields(
+            settings,
+            &self.channel,
+            &sanitize(&self.name),
+            self.format.extension(),
+            date,
+        );
+        PathBuf::from(expand(&self.template.to_string_lossy(), &fields))
+    }
+
+    /// Names of the files of this log in a directory, whatever their date or rotation
+    fn file_name_pattern(&self, settings: &LogSettings) -> Option<Regex> {
+        let name = self.template.file_name()?.to_string_lossy().into_owned();
+        let fields = template_fields(
+            settings,
+            &self.channel,
+            &sanitize(&self.name),
+            self.format.extension(),
+            ["yyyy", "mm", "dd"].map(any_value),
+        );
+        name_regex(&expand(&name, &fields), true)
+    }
+
+    fn current_path(&self) -> Option<PathBuf> {
+        self.current.as_ref().map(|c| c.path.clone())
+    }
+
+    async fn open(path: &Path, day: NaiveDate) -> std::io::Result<CurrentFile> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let metadata = file.metadata().await?;
+        // An existing file continues the day it was last written
+        let modified = metadata
+            .modified()
+            .ok()
+            .filter(|_| metadata.len() > 0)
+            .map(|t| DateTime::<Local>::from(t).date_naive());
+        Ok(CurrentFile {
+            path: path.to_owned(),
+            day: modified.unwrap_or(day),
+            len: metadata.len(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Write the buffer of the current file and close it
+    async fn close(&mut self) -> std::io::Result<()> {
+        if let Some(mut current) = self.current.take() {
+            current.writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some(current) => current.writer.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Append a message, rotating files as needed
+    async fn write_msg(&mut self, msg: &TwitchMsg) -> std::io::Result<()> {
+        let settings = self.settings.read().unwrap().clone();
+        let day = sent_time(msg).with_timezone(&Local).date_naive();
+        let path = self.expand(&settings, day);
+        let text = self.format.format(msg);
+        if let Some(old) = self.current_path().filter(|p| *p != path) {
+            self.close().await?;
+            self.finish(&settings, old, &path).await;
+        }
+        if self.current.is_none() {
+            self.current = Some(Self::open(&path, day).await?);
+        }
+        let current = self.current.as_ref().unwrap();
+        let suffix = match settings.rotation {
+            Rotation::Daily if day > current.day => {
+                Some(current.day.format("%Y-%m-%d").to_string())
+            }
+            Rotation::Size { max_mb }
+                if current.len > 0
+                    && current.len + text.len() as u64 > max_mb.max(1) * 1024 * 1024 =>
+            {
+                Some(Local::now().format("%Y%m%d-%H%M%S%3f").to_string())
+            }
+            _ => None,
+        };
+        if let Some(suffix) = suffix {
+            self.close().await?;
+            let rotated = rotated_path(&path, &suffix);
+            tokio::fs::rename(&path, &rotated).await?;
+            self.finish(&settings, rotated, &path).await;
+            self.current = Some(Self::open(&path, day).await?);
+        }
+        let current = self.current.as_mut().unwrap();
+        if current.len == 0 {
+            if let Some(header) = self.format.header() {
+                current.writer.write_all(header.as_bytes()).await?;
+                current.len += header.len() as u64;
+            }
+        }
+        current.writer.write_all(text.as_bytes()).await?;
+        current.len += text.len() as u64;
+        current.day = current.day.max(day);
+        Ok(())
+    }
+
+    /// Compress a file that is no longer written and remove the files beyond the retention limit,
+    /// after the previous file is finished but without waiting for this one.
+    /// `active` is the file written next, it is always kept.
+    async fn finish(&mut self, settings: &LogSettings, path: PathBuf, active: &Path) {
+        self.wait_finished().await;
+        let gzip = settings.gzip;
+        let keep_files = settings.keep_files;
+        let pattern = self.file_name_pattern(settings);
+        let active = active.to_owned();
+        self.finishing = Some(tokio::task::spawn_blocking(move || {
+            if gzip {
+                if let Err(e) = compress(&path) {
+                    log::error!("Failed to compress {}: {}", path.display(), e);
+                }
+            }
+            if keep_files > 0 {
+                if let (Some(dir), Some(pattern)) = (path.parent(), pattern) {
+                    if let Err(e) = remove_old_files(dir, &pattern, &active, keep_files) {
+                        log::error!("Failed to remove old logs in {}: {}", dir.display(), e);
+                    }
+                }
+            }
+        }));
+    }
+
+    async fn wait_finished(&mut self) {
+        if let Some(finishing) = self.finishing.take() {
+            let _ = finishing.await;
+        }
+    }
+}
+
+async fn run_writer(
+    mut file: LogFile,
+    mut rx: mpsc::Receiver<WriterCommand>,
+    status: Arc<Mutex<WriterStatus>>,
+) {
+    let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut failed = false;
+    loop {
+        let result = tokio::select! {
+            command = rx.recv() => match command {
+                Some(WriterCommand::Msg(msg)) if !failed => file.write_msg(&msg).await,
+                Some(WriterCommand::Msg(_)) => Ok(()),
+                Some(WriterCommand::Flush(done)) => {
+                    let result = file.flush().await;
+                    let _ = done.send(());
+                    result
+                }
+                None => break,
+            },
+            _ = flush_interval.tick() => file.flush().await,
+        };
+        let mut status = status.lock().unwrap();
+        if let Some(path) = file.current_path() {
+            status.path = path;
+        }
+        if let Err(e) = result {
+            if !failed {
+                status.error = Some(e.to_string());
+                failed = true;
+            }
+        }
+    }
+    if let Err(e) = file.close().await {
+        log::error!("Failed to write {}: {}", file.template.display(), e);
+    }
+    file.wait_finished().await;
+}
+
+/// A log written from a template by its own task.
+/// Messages are buffered and written at least every second, and when the log is dropped.
+#[derive(Debug)]
+pub struct LogTarget {
+    template: PathBuf,
+    format: LogFormat,
+    tx: mpsc::Sender<WriterCommand>,
+    status: Arc<Mutex<WriterStatus>>,
+}
+
+impl LogTarget {
+    /// Start the writer of a log. `name` is the `{view}` of the template.
+    pub fn new(
+        template: PathBuf,
+        format: LogFormat,
+        name: &str,
+        channel: &str,
+        settings: SharedLogSettings,
+    ) -> Self {
+        let file = LogFile {
+            template: template.clone(),
+            format,
+            name: name.to_owned(),
+            channel: channel.to_owned(),
+            settings,
+            current: None,
+            finishing: None,
+        };
+        let path = file.expand(&file.settings.read().unwrap(), Local::now().date_naive());
+        let status = Arc::new(Mutex::new(WriterStatus {
+            path,
+            error: None,
+            dropped: 0,
+        }));
+        let (tx, rx) = mpsc::channel(WRITER_QUEUE_LEN);
+        ASYNC_RUNTIME.spawn(run_writer(file, rx, status.clone()));
+        Self {
+            template,
+            format,
+            tx,
+            status,
+        }
+    }
+
+    pub fn template(&self) -> &Path {
+        &self.template
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    /// The file written last, or the file of today if nothing was written yet
+    pub fn path(&self) -> PathBuf {
+        self.status.lock().unwrap().path.clone()
+    }
+
+    /// Writing stops after the first error
+    pub fn error(&self) -> Option<String> {
+        self.status.lock().unwrap().error.clone()
+    }
+
+    /// Messages dropped because the writer was too far behind
+    pub fn dropped(&self) -> usize {
+        self.status.lock().unwrap().dropped
+    }
+
+    /// Queue a message without waiting. It is dropped if the writer is too far behind.
+    pub fn write(&self, msg: &TwitchMsg) {
+        if let Err(TrySendError::Full(_)) =
+            self.tx.try_send(WriterCommand::Msg(Box::new(msg.clone())))
+        {
+            let mut status = self.status.lock().unwrap();
+            status.dropped += 1;
+            if status.dropped % 1000 == 1 {
+                log::warn!(
+                    "Log {} is behind, {} messages dropped",
+                    status.path.display(),
+                    status.dropped
+                );
+            }
+        }
+    }
+
+    /// Queue messages, waiting for room in the queue
+    pub async fn write_all<'a>(&self, msgs: impl IntoIterator<Item = &'a TwitchMsg>) {
+        for msg in msgs {
+            if self
+                .tx
+                .send(WriterCommand::Msg(Box::new(msg.clone())))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Wait until the queued messages are written to disk
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(WriterCommand::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+/// Replace a file with its gzip compressed copy
+fn compress(path: &Path) -> std::io::Result<()> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(
+        std::io::BufWriter::new(File::create(&gz_name)?),
+        Compression::default(),
+    );
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::remove_file(path)
+}
+
+/// Keep the `keep` most recently modified files matching `pattern`, besides `active`
+fn remove_old_files(
+    dir: &Path,
+    pattern: &Regex,
+    active: &Path,
+    keep: usize,
+) -> std::io::Result<()> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path == active || !pattern.is_match(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        files.push((modified, path));
+    }
+    files.sort();
+    let count = files.len().saturating_sub(keep);
+    for (_, path) in files.into_iter().take(count) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Log files that a template may have written for a channel, whatever their view, date,
+/// format or rotation
+pub fn find_log_files(template: &Path, settings: &LogSettings, channel: &str) -> Vec<PathBuf> {
+    let fields = template_fields(
+        settings,
+        channel,
+        &any_value("view"),
+        &any_value("ext"),
+        ["yyyy", "mm", "dd"].map(any_value),
+    );
+    let path = PathBuf::from(expand(&template.to_string_lossy(), &fields));
+    let components: Vec<_> = path.components().collect();
+    // The directory before the first placeholder, then a pattern per level below it
+    let mut dir = PathBuf::new();
+    let mut patterns = vec![];
+    for (idx, component) in components.iter().enumerate() {
+        let name = component.as_os_str().to_string_lossy();
+        let last = idx + 1 == components.len();
+        if patterns.is_empty() && !last && !name.contains('\0') {
+            dir.push(component);
+            continue;
+        }
+        match name_regex(&name, last) {
+            Some(pattern) => patterns.push(pattern),
+            None => return vec![],
+        }
+    }
+    if dir.as_os_str().is_empty() {
+        dir.push(".");
+    }
+    let mut files = vec![];
+    walk_log_dir(&dir, &patterns, &mut files);
+    files
+}
+
+fn walk_log_dir(dir: &Path, patterns: &[Regex], files: &mut Vec<PathBuf>) {
+    let (pattern, rest) = match patterns.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !pattern.is_match(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        if rest.is_empty() {
+            if path.is_file() {
+                files.push(path);
+            }
+        } else if path.is_dir() {
+            walk_log_dir(&path, rest, files);
+        }
+    }
+}
+
+/// Read the messages of a log file, which may be compressed with gzip.
+/// Lines that are not messages, like the CSV header, are skipped.
+pub fn read_log_file(path: &Path, channel: &str) -> std::io::Result<Vec<TwitchMsg>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = if path.extension().map(|e| e == "gz").unwrap_or(false) {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    let format = LogFormat::from_path(path);
+    let mut msgs = vec![];
+    for (idx, line) in reader.lines().enumerate() {
+        if let Some(msg) = format.parse(&line?, channel, idx + 1) {
+            msgs.push(msg);
+        }
+    }
+    Ok(msgs)
+}
+
+/// Search results stop at this many messages
+pub const MAX_LOG_HITS: usize = 1000;
+
+/// Criteria of a search in log files, empty criteria match every message
+#[derive(Clone, Default, Debug)]
+pub struct LogQuery {
+    pub text: String,
+    /// `text` is a regex instead of a case insensitive substring
+    pub regex: bool,
+    /// Login or display name, case insensitive
+    pub user: String,
+    /// First and last day, inclusive
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogHit {
+    pub path: PathBuf,
+    /// Position of the message in the messages of the file
+    pub index: usize,
+    pub msg: TwitchMsg,
+}
+
+/// Search the messages of log files, in the order of `files`
+pub fn search_logs(
+    files: &[PathBuf],
+    channel: &str,
+    query: &LogQuery,
+) -> Result<Vec<LogHit>, String> {
+    let pattern = if query.regex {
+        query.text.clone()
+    } else {
+        format!("(?i){}", regex::escape(&query.text))
+    };
+    let text = Regex::new(&pattern).map_err(|e| e.to_string())?;
+    let user = query.user.trim().to_lowercase();
+    let mut hits = vec![];
+    for path in files {
+        let msgs = match read_log_file(path, channel) {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                log::error!("Failed to read {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        for (index, msg) in msgs.into_iter().enumerate() {
+            let day = sent_time(&msg).with_timezone(&Local).date_naive();
+            let matched = text.is_match(msg.payload())
+                && (user.is_empty()
+                    || msg.sender_login().to_lowercase() == user
+                    || msg.sender_display().to_lowercase() == user)
+                && query.from.map(|from| day >= from).unwrap_or(true)
+                && query.to.map(|to| day <= to).unwrap_or(true);
+            if matched {
+                hits.push(LogHit {
+                    path: path.clone(),
+                    index,
+                    msg,
+                });
+                if hits.len() >= MAX_LOG_HITS {
+                    return Ok(hits);
+                }
+            }
+        }
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(text: &str) -> TwitchMsg {
+        let line = format!(
+            "@badges=moderator/1,subscriber/12;color=#1E90FF;display-name=Foo;id=abc-1;\
+             tmi-sent-ts=1700000000000;user-id=42 :foo!foo@foo.tmi.twitch.tv PRIVMSG #chan :{}",
+            text
+        );
+        parse_irc_line(&line).unwrap()
+    }
+
+    #[test]
+    fn format_parse_round_trip() {
+        let original = msg("hello, \"world\" \\ {msg} é");
+        for format in LogFormat::ALL {
+            let line = format.format(&original);
+            assert!(line.ends_with('\n'), "{:?}", format);
+            let parsed = format
+                .parse(line.trim_end_matches('\n'), "chan", 7)
+                .unwrap_or_else(|| panic!("{:?} cannot parse {}", format, line));
+            assert_eq!(parsed.payload(), original.payload(), "{:?}", format);
+            assert_eq!(parsed.sender_login(), "foo", "{:?}", format);
+            assert_eq!(parsed.sender_display(), "Foo", "{:?}", format);
+            assert_eq!(parsed.channel(), "#chan", "{:?}", format);
+            assert_eq!(parsed.sent_time(), original.sent_time(), "{:?}", format);
+            let id = match format {
+                LogFormat::Plain => "line-7",
+                _ => "abc-1",
+            };
+            assert_eq!(parsed.id(), id, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let line = LogFormat::Csv.format(&msg("a, \"b\""));
+        assert!(line.ends_with(",\"a, \"\"b\"\"\"\n"), "{}", line);
+        let fields = csv_fields(line.trim_end());
+        assert_eq!(fields.len(), 12);
+        assert_eq!(fields[7], "moderator/1,subscriber/12");
+        assert_eq!(fields[11], "a, \"b\"");
+        assert!(LogFormat::Csv
+            .parse(CSV_HEADER.trim_end(), "chan", 1)
+            .is_none());
+    }
+
+    #[test]
+    fn json_escapes() {
+        let line = LogFormat::JsonLines.format(&msg("say \"hi\" \\o/\u{1}"));
+        assert!(
+            line.contains(r#""text":"say \"hi\" \\o/\u0001""#),
+            "{}",
+            line
+        );
+        assert!(line.contains(r#""badges":{"moderator":"1","subscriber":"12"}"#));
+        let parsed = LogFormat::JsonLines
+            .parse(
+                r##"{"channel":"#chan","login":"foo","text":"tab\there \u00e9 \"q\"",
+                    "tags":{"id":"x","display-name":"Foo","tmi-sent-ts":"1700000000000","flags":null}}"##,
+                "chan",
+                1,
+            )
+            .unwrap();
+        assert_eq!(parsed.payload(), "tab\there é \"q\"");
+        assert_eq!(parsed.id(), "x");
+        assert!(LogFormat::JsonLines
+            .parse("{\"text\":", "chan", 1)
+            .is_none());
+    }
+
+    #[test]
+    fn malformed_timestamps() {
+        let dir = std::env::temp_dir().join(format!("chat-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("log.jsonl");
+        std::fs::write(
+            &json,
+            concat!(
+                r##"{"channel":"#chan","login":"foo","text":"bad","tags":{"id":"1","display-name":"Foo","tmi-sent-ts":"abc"}}"##,
+                "\n",
+                r##"{"channel":"#chan","login":"foo","text":"huge","tags":{"id":"2","display-name":"Foo","tmi-sent-ts":"99999999999999999"}}"##,
+                "\n",
+                r##"{"channel":"#chan","login":"foo","text":"missing","tags":{"id":"3","display-name":"Foo"}}"##,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let irc = dir.join("log.irc");
+        std::fs::write(
+            &irc,
+            "@display-name=Foo;id=4;tmi-sent-ts=12:30 :foo!foo@foo.tmi.twitch.tv PRIVMSG #chan :irc\n",
+        )
+        .unwrap();
+        let msgs = read_log_file(&json, "chan").unwrap();
+        assert_eq!(msgs.len(), 3);
+        assert!(msgs.iter().all(|m| m.sent_time().is_none()));
+        for format in LogFormat::ALL {
+            format.format(&msgs[0]);
+        }
+        let files = [json, irc];
+        let hits = search_logs(&files, "chan", &LogQuery::default()).unwrap();
+        assert_eq!(hits.len(), 4);
+        let query = LogQuery {
+            to: NaiveDate::from_ymd_opt(2000, 1, 1),
+            ..LogQuery::default()
+        };
+        let hits = search_logs(&files, "chan", &query);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(hits.unwrap().is_empty());
+    }
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!(sanitize("#chan"), "chan");
+        assert_eq!(sanitize("a/b:c"), "a_b_c");
+        assert_eq!(sanitize(".."), "__");
+        assert_eq!(sanitize("."), "_");
+        assert_eq!(sanitize("v1.2"), "v1.2");
+    }
+}
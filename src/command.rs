@@ -0,0 +1,308 @@
+//! External commands run on filtered messages.
+//!
+//! A template like `espeak "{display}: {msg}"` is split into arguments the way a shell would,
+//! but without any shell: placeholders are replaced inside each argument and the program is
+//! run directly. The placeholders are `{msg}`, `{login}`, `{display}`, `{user_id}`, `{id}`,
+//! `{channel}` and `{view}`, also passed as the environment variables `TWITCH_MSG`,
+//! `TWITCH_LOGIN`, `TWITCH_DISPLAY`, `TWITCH_USER_ID`, `TWITCH_ID`, `TWITCH_CHANNEL`
+//! and `TWITCH_VIEW`. They are only allowed in the arguments, never in the program name.
+
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{chat_client::TwitchMsg, template::expand};
+
+/// Stderr kept from the last run, in bytes
+const MAX_STDERR_LEN: usize = 4000;
+/// Placeholders replaced in the arguments
+const PLACEHOLDERS: [&str; 7] = [
+    "msg", "login", "display", "user_id", "id", "channel", "view",
+];
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct CommandSettings {
+    pub enabled: bool,
+    pub template: String,
+    /// Commands running at the same time, messages matching beyond that are skipped
+    pub max_running: usize,
+    /// Commands still running after this are killed
+    pub timeout_secs: u64,
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            template: String::new(),
+            max_running: 2,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Split a template into arguments. Double and single quotes group words,
+/// a backslash escapes the next character outside single quotes.
+/// The program, the first argument, cannot contain placeholders.
+pub fn parse_template(template: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => arg.push(c),
+            (_, '\\') => match chars.next() {
+                Some(c) => {
+                    arg.push(c);
+                    in_arg = true;
+                }
+                None => return Err("Command ends with a backslash".to_owned()),
+            },
+            (Some(_), c) => arg.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("Unterminated {} quote in command", q));
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    if args.is_empty() {
+        return Err("Command is empty".to_owned());
+    }
+    if let Some(name) = PLACEHOLDERS
+        .iter()
+        .find(|name| args[0].contains(&format!("{{{}}}", name)))
+    {
+        return Err(format!(
+            "The program cannot contain {{{}}}, placeholders are only allowed in its arguments",
+            name
+        ));
+    }
+    Ok(args)
+}
+
+fn msg_fields(msg: &TwitchMsg, view: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("msg", msg.payload().to_owned()),
+        ("login", msg.sender_login().to_owned()),
+        ("display", msg.sender_display().to_owned()),
+        ("user_id", msg.user_id().unwrap_or_default().to_owned()),
+        ("id", msg.id().to_owned()),
+        ("channel", msg.channel().trim_start_matches('#').to_owned()),
+        ("view", view.to_owned()),
+    ]
+}
+
+/// Results of the commands of a channel
+#[derive(Clone, Default, Debug)]
+pub struct CommandStatus {
+    pub runs: usize,
+    /// Matches skipped because too many commands were running
+    pub skipped: usize,
+    pub running: usize,
+    /// Exit status or error of the last finished command
+    pub last_result: Option<String>,
+    /// Stderr of the last finished command
+    pub stderr: String,
+}
+
+#[derive(Debug, Default)]
+pub struct CommandRunner {
+    settings: CommandSettings,
+    args: Vec<String>,
+    /// Shared with the running commands, which count themselves in `running`. Commands
+    /// started before a settings change still count toward the new `max_running`.
+    status: Arc<Mutex<CommandStatus>>,
+}
+
+impl CommandRunner {
+    pub fn settings(&self) -> &CommandSettings {
+        &self.settings
+    }
+
+    /// The template is only checked when the command is enabled
+    pub fn set_settings(&mut self, settings: CommandSettings) -> Result<(), String> {
+        if settings.max_running == 0 {
+            return Err("At least one command must be allowed to run".to_owned());
+        }
+        self.args = if settings.enabled {
+            parse_template(&settings.template)?
+        } else {
+            vec![]
+        };
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn status(&self) -> CommandStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Start the command for a message matching `view`, without waiting for it.
+    /// Must be called inside the async runtime.
+    pub fn run(&self, msg: &TwitchMsg, view: &str) {
+        if !self.settings.enabled || self.args.is_empty() {
+            return;
+        }
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.running >= self.settings.max_running {
+                status.skipped += 1;
+                return;
+            }
+            status.running += 1;
+            status.runs += 1;
+        }
+        let fields = msg_fields(msg, view);
+        let mut command = tokio::process::Command::new(&self.args[0]);
+        command
+            .args(self.args[1..].iter().map(|a| expand(a, &fields)))
+            .envs(
+                fields
+                    .iter()
+                    .map(|(name, value)| (format!("TWITCH_{}", name.to_uppercase()), value)),
+            )
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let status = self.status.clone();
+        let timeout_secs = self.settings.timeout_secs;
+        tokio::spawn(async move {
+            let (result, stderr) = match command.spawn() {
+                Ok(child) => match tokio::time::timeout(
+                    Duration::from_secs(timeout_secs),
+                    child.wait_with_output(),
+                )
+                .await
+                {
+                    Ok(Ok(output)) => (
+                        output.status.to_string(),
+                        String::from_utf8_lossy(&output.stderr).into_owned(),
+                    ),
+                    Ok(Err(e)) => (format!("Failed: {}", e), String::new()),
+                    Err(_) => (
+                        format!("Killed after the {} s timeout", timeout_secs),
+                        String::new(),
+                    ),
+                },
+                Err(e) => (format!("Failed to start: {}", e), String::new()),
+            };
+            let mut status = status.lock().unwrap();
+            status.running -= 1;
+            status.last_result = Some(result);
+            let mut start = stderr.len().saturating_sub(MAX_STDERR_LEN);
+            while !stderr.is_char_boundary(start) {
+                start += 1;
+            }
+            status.stderr = stderr[start..].to_owned();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat_client::build_sample_msg, ASYNC_RUNTIME};
+
+    fn args(template: &str) -> Vec<String> {
+        parse_template(template).unwrap()
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            args(r#"espeak  "{display}: {msg}" 'it''s \n' a\ b "say \"hi\"" '' x"#),
+            vec![
+                "espeak",
+                "{display}: {msg}",
+                r"its \n",
+                "a b",
+                r#"say "hi""#,
+                "",
+                "x"
+            ]
+        );
+        assert!(parse_template(r#"echo "open"#).is_err());
+        assert!(parse_template("echo 'open").is_err());
+        assert!(parse_template(r"echo \").is_err());
+        assert!(parse_template("  ").is_err());
+    }
+
+    #[test]
+    fn placeholder_in_program() {
+        assert!(parse_template("{msg}").is_err());
+        assert!(parse_template("/usr/bin/{login} x").is_err());
+        assert!(parse_template(r#""{display}" x"#).is_err());
+        // Unknown names are not placeholders
+        assert_eq!(args("{other} {msg}"), vec!["{other}", "{msg}"]);
+    }
+
+    #[test]
+    fn no_shell_interpolation() {
+        assert_eq!(
+            args("echo $(id) `id` $HOME; ls | cat > out &"),
+            vec!["echo", "$(id)", "`id`", "$HOME;", "ls", "|", "cat", ">", "out", "&"]
+        );
+        let fields = vec![
+            ("msg", "$(rm -rf ~); {login} \"' `id`".to_owned()),
+            ("login", "foo".to_owned()),
+        ];
+        // A placeholder is one argument whatever its value, and values are not expanded again
+        assert_eq!(expand("{msg}", &fields), "$(rm -rf ~); {login} \"' `id`");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn max_running() {
+        let mut runner = CommandRunner::default();
+        let settings = |max_running| CommandSettings {
+            enabled: true,
+            template: "sleep 5".to_owned(),
+            max_running,
+            timeout_secs: 1,
+        };
+        assert!(runner.set_settings(settings(0)).is_err());
+        runner.set_settings(settings(1)).unwrap();
+        let msg = build_sample_msg("chan", "foo", "hi").unwrap();
+        ASYNC_RUNTIME.block_on(async {
+            runner.run(&msg, "view");
+            runner.run(&msg, "view");
+        });
+        let status = runner.status();
+        assert_eq!((status.runs, status.skipped, status.running), (1, 1, 1));
+        // The running command counts toward the new limit
+        runner.set_settings(settings(2)).unwrap();
+        ASYNC_RUNTIME.block_on(async {
+            runner.run(&msg, "view");
+            runner.run(&msg, "view");
+        });
+        let status = runner.status();
+        assert_eq!((status.runs, status.skipped, status.running), (2, 2, 2));
+        runner.set_settings(settings(1)).unwrap();
+        ASYNC_RUNTIME.block_on(async { runner.run(&msg, "view") });
+        assert_eq!(runner.status().skipped, 3);
+    }
+}
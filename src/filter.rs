@@ -1,96 +1,641 @@
-use regex::Regex;
-use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    sync::{Arc, RwLock},
+};
+
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::chat_client::TwitchMsg;
 
+pub mod expr;
+pub mod heuristic;
+pub mod preset;
+pub mod script;
+
+use expr::{quote, Expression, ParseError};
+use heuristic::Heuristic;
+use script::ScriptRule;
+
 pub const BROADCASTER_BADGE_NAME: &str = "broadcaster";
 pub const MODERATOR_BADGE_NAME: &str = "moderator";
 pub const VIP_BADGE_NAME: &str = "vip";
 pub const PARTNER_BADGE_NAME: &str = "partner";
 
+#[derive(Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq, Debug)]
+pub enum MatchMode {
+    #[default]
+    Regex,
+    Text,
+    Word,
+    Prefix,
+}
+
+impl MatchMode {
+    pub const ALL: [MatchMode; 4] = [
+        MatchMode::Text,
+        MatchMode::Word,
+        MatchMode::Prefix,
+        MatchMode::Regex,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatchMode::Regex => "Regex",
+            MatchMode::Text => "Contains",
+            MatchMode::Word => "Whole word",
+            MatchMode::Prefix => "Starts with",
+        }
+    }
+}
+
+/// Which user property an author pattern is tested against
+#[derive(Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq, Debug)]
+pub enum AuthorField {
+    #[default]
+    Login,
+    Display,
+    UserId,
+}
+
+impl AuthorField {
+    pub const ALL: [AuthorField; 3] = [
+        AuthorField::Login,
+        AuthorField::Display,
+        AuthorField::UserId,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthorField::Login => "User id",
+            AuthorField::Display => "Nickname",
+            AuthorField::UserId => "Numeric id",
+        }
+    }
+
+    /// Field name in filter expressions
+    fn expr_field(&self) -> &'static str {
+        match self {
+            AuthorField::Login => "login",
+            AuthorField::Display => "display",
+            AuthorField::UserId => "user_id",
+        }
+    }
+
+    pub fn value<'a>(&self, msg: &'a TwitchMsg) -> &'a str {
+        match self {
+            AuthorField::Login => msg.sender_login(),
+            AuthorField::Display => msg.sender_display(),
+            AuthorField::UserId => msg.user_id().unwrap_or(""),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct PatternState {
+    pub text: String,
+    #[serde(default)]
+    pub mode: MatchMode,
+    #[serde(default = "default_case_sensitive")]
+    pub case_sensitive: bool,
+    /// Only used by author patterns
+    #[serde(default)]
+    pub target: AuthorField,
+}
+
+fn default_case_sensitive() -> bool {
+    true
+}
+
+impl Default for PatternState {
+    fn default() -> Self {
+        Self {
+            text: String::new(),
+            mode: MatchMode::Text,
+            case_sensitive: false,
+            target: AuthorField::Login,
+        }
+    }
+}
+
+impl PatternState {
+    pub fn regex(pattern: impl ToString) -> Self {
+        Self {
+            text: pattern.to_string(),
+            mode: MatchMode::Regex,
+            case_sensitive: true,
+            target: AuthorField::Login,
+        }
+    }
+
+    /// The regex equivalent to this pattern
+    pub fn to_regex_str(&self) -> String {
+        let text = self.text.trim();
+        let pattern = match self.mode {
+            MatchMode::Regex => text.to_owned(),
+            MatchMode::Text => regex::escape(text),
+            MatchMode::Word => format!(r"(?:^|\W){}(?:\W|$)", regex::escape(text)),
+            MatchMode::Prefix => format!("^{}", regex::escape(text)),
+        };
+        if self.case_sensitive {
+            pattern
+        } else {
+            format!("(?i){}", pattern)
+        }
+    }
+
+    /// Regex used in a `RegexSet` and whether a set match has to be confirmed by the full
+    /// regex. The word boundaries of whole word patterns make sets too large to compile.
+    fn to_set_regex_str(&self) -> (String, bool) {
+        match self.mode {
+            MatchMode::Word => {
+                let pattern = regex::escape(self.text.trim());
+                if self.case_sensitive {
+                    (pattern, true)
+                } else {
+                    (format!("(?i){}", pattern), true)
+                }
+            }
+            _ => (self.to_regex_str(), false),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.trim().is_empty()
+    }
+}
+
+/// Accepts both the pattern list and the newline separated regexes of old save states
+fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Vec<PatternState>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct PatternsVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for PatternsVisitor {
+        type Value = Vec<PatternState>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a pattern list or newline separated regexes")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(v.split('\n')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(PatternState::regex)
+                .collect())
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut v = vec![];
+            while let Some(p) = seq.next_element()? {
+                v.push(p);
+            }
+            Ok(v)
+        }
+    }
+
+    deserializer.deserialize_any(PatternsVisitor)
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
 pub struct FilterState {
-    pub inc_msg: String,
-    pub inc_author: String,
-    pub exc_msg: String,
-    pub exc_author: String,
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub inc_msg: Vec<PatternState>,
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub inc_author: Vec<PatternState>,
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub exc_msg: Vec<PatternState>,
+    #[serde(deserialize_with = "deserialize_patterns")]
+    pub exc_author: Vec<PatternState>,
     pub broadcaster: bool,
     pub moderator: bool,
     pub vip: bool,
     pub partner: bool,
+    #[serde(default)]
+    pub use_expression: bool,
+    #[serde(default)]
+    pub expression: String,
+    #[serde(default)]
+    pub inc_script: Vec<ScriptRule>,
+    #[serde(default)]
+    pub exc_script: Vec<ScriptRule>,
+    #[serde(default)]
+    pub inc_heuristic: Vec<Heuristic>,
+    #[serde(default)]
+    pub exc_heuristic: Vec<Heuristic>,
+}
+
+impl FilterState {
+    /// Convert the pattern lists into an equivalent filter expression
+    pub fn to_expression(&self) -> String {
+        let exc: Vec<String> = pattern_exprs(Some("msg"), &self.exc_msg)
+            .chain(pattern_exprs(None, &self.exc_author))
+            .chain(self.exc_script.iter().map(|s| s.to_expression()))
+            .chain(self.exc_heuristic.iter().map(|h| h.to_expression()))
+            .collect();
+        let mut inc: Vec<String> = pattern_exprs(Some("msg"), &self.inc_msg)
+            .chain(pattern_exprs(None, &self.inc_author))
+            .chain(self.inc_script.iter().map(|s| s.to_expression()))
+            .chain(self.inc_heuristic.iter().map(|h| h.to_expression()))
+            .collect();
+        for (enabled, badge) in [
+            (self.broadcaster, BROADCASTER_BADGE_NAME),
+            (self.moderator, MODERATOR_BADGE_NAME),
+            (self.vip, VIP_BADGE_NAME),
+            (self.partner, PARTNER_BADGE_NAME),
+        ] {
+            if enabled {
+                inc.push(format!("badge = {}", quote(badge)));
+            }
+        }
+        let inc = if inc.is_empty() {
+            "false".to_owned()
+        } else {
+            inc.join(" or ")
+        };
+        if exc.is_empty() {
+            inc
+        } else {
+            format!("not ({}) and ({})", exc.join(" or "), inc)
+        }
+    }
+}
+
+/// Clause of an expression matching exactly the author of a pattern
+fn author_clause(pat: &PatternState) -> String {
+    format!("{} = {}", pat.target.expr_field(), quote(pat.text.trim()))
+}
+
+/// `field` is `None` for author patterns, which use their own target field
+fn pattern_exprs<'a>(
+    field: Option<&'a str>,
+    patterns: &'a [PatternState],
+) -> impl Iterator<Item = String> + 'a {
+    patterns.iter().filter(|p| !p.is_empty()).map(move |p| {
+        format!(
+            "{} ~ {}",
+            field.unwrap_or(p.target.expr_field()),
+            quote(&p.to_regex_str())
+        )
+    })
+}
+
+/// Filter step that decided whether a message is shown
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FilterStage {
+    Expression,
+    ExcludeMessage,
+    ExcludeAuthor,
+    ExcludeScript,
+    ExcludeHeuristic,
+    IncludeMessage,
+    IncludeAuthor,
+    IncludeScript,
+    IncludeHeuristic,
+    Badge,
+    /// No inclusion rule matched
+    Fallthrough,
+}
+
+impl FilterStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterStage::Expression => "Expression",
+            FilterStage::ExcludeMessage => "Excluded messages",
+            FilterStage::ExcludeAuthor => "Excluded authors",
+            FilterStage::ExcludeScript => "Excluded scripts",
+            FilterStage::ExcludeHeuristic => "Excluded heuristics",
+            FilterStage::IncludeMessage => "Messages",
+            FilterStage::IncludeAuthor => "Authors",
+            FilterStage::IncludeScript => "Scripts",
+            FilterStage::IncludeHeuristic => "Heuristics",
+            FilterStage::Badge => "Badges",
+            FilterStage::Fallthrough => "Nothing matched",
+        }
+    }
+}
+
+/// Result of [`Filter::explain`]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Explanation {
+    pub matched: bool,
+    pub stage: FilterStage,
+    /// Description of the deciding pattern, badge or expression
+    pub rule: Option<String>,
+    /// Name of the library filter that decided, `None` for the filter of the view itself
+    pub library: Option<String>,
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            if self.matched { "Shown" } else { "Hidden" },
+            self.stage.name()
+        )?;
+        if let Some(library) = &self.library {
+            write!(f, " in library filter {}", quote(library))?;
+        }
+        if let Some(rule) = &self.rule {
+            write!(f, ": {}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+/// Named filters shared by every channel. Views reference them by name,
+/// so an edited library filter takes effect in all channels at once.
+pub type FilterLibrary = Arc<RwLock<Vec<(String, Filter)>>>;
+
+fn library_filters<'a>(
+    library: &'a [(String, Filter)],
+    refs: &'a [String],
+) -> impl Iterator<Item = &'a (String, Filter)> + 'a {
+    library.iter().filter(|(name, _)| refs.contains(name))
+}
+
+#[derive(Debug, Clone)]
+pub enum FilterError {
+    Regex(regex::Error),
+    Expression(ParseError),
+}
+
+impl Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterError::Regex(e) => write!(f, "{}", e),
+            FilterError::Expression(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+impl From<regex::Error> for FilterError {
+    fn from(value: regex::Error) -> Self {
+        Self::Regex(value)
+    }
+}
+
+impl From<ParseError> for FilterError {
+    fn from(value: ParseError) -> Self {
+        Self::Expression(value)
+    }
 }
 
 impl std::convert::From<&Filter> for FilterState {
     fn from(value: &Filter) -> Self {
-        let msg_vec: Vec<&str> = value.inc_msg_pat.iter().map(|r| r.as_str()).collect();
-        let author_vec: Vec<&str> = value.inc_author_pat.iter().map(|r| r.as_str()).collect();
-        let exc_msg_vec: Vec<&str> = value.exc_msg_pat.iter().map(|r| r.as_str()).collect();
-        let exc_author_vec: Vec<&str> = value.exc_author_pat.iter().map(|r| r.as_str()).collect();
+        let states = |g: &PatternGroup| g.patterns.iter().map(|p| p.state.clone()).collect();
         Self {
-            inc_msg: msg_vec.join("\n"),
-            inc_author: author_vec.join("\n"),
-            exc_msg: exc_msg_vec.join("\n"),
-            exc_author: exc_author_vec.join("\n"),
+            inc_msg: states(&value.inc_msg_pat),
+            inc_author: states(&value.inc_author_pat),
+            exc_msg: states(&value.exc_msg_pat),
+            exc_author: states(&value.exc_author_pat),
             broadcaster: value
                 .badge_pat
                 .contains(&BROADCASTER_BADGE_NAME.to_string()),
             moderator: value.badge_pat.contains(&MODERATOR_BADGE_NAME.to_string()),
             vip: value.badge_pat.contains(&VIP_BADGE_NAME.to_string()),
             partner: value.badge_pat.contains(&PARTNER_BADGE_NAME.to_string()),
+            use_expression: value.expression.is_some(),
+            expression: value.expression_src.clone(),
+            inc_script: value.inc_script.clone(),
+            exc_script: value.exc_script.clone(),
+            inc_heuristic: value.inc_heuristic.clone(),
+            exc_heuristic: value.exc_heuristic.clone(),
         }
     }
 }
 
-#[derive(Clone, Default, Debug)]
-pub struct Filter {
-    inc_msg_pat: Vec<Regex>,
-    inc_author_pat: Vec<Regex>,
-    badge_pat: Vec<String>,
-    exc_msg_pat: Vec<Regex>,
-    exc_author_pat: Vec<Regex>,
+#[derive(Clone, Debug)]
+struct Pattern {
+    state: PatternState,
+    regex: Regex,
 }
 
-impl TryFrom<&FilterState> for Filter {
+impl TryFrom<&PatternState> for Pattern {
     type Error = regex::Error;
-    fn try_from(value: &FilterState) -> Result<Self, Self::Error> {
-        let mut msg = vec![];
-        let mut author = vec![];
-        let mut exc_msg = vec![];
-        let mut exc_author = vec![];
-        let mut badge_pat = vec![];
+    fn try_from(value: &PatternState) -> Result<Self, Self::Error> {
+        Ok(Self {
+            state: value.clone(),
+            regex: Regex::new(&value.to_regex_str())?,
+        })
+    }
+}
 
-        for line in value
-            .inc_msg
-            .split('\n')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-        {
-            msg.push(Regex::new(line)?);
+impl From<Regex> for Pattern {
+    fn from(value: Regex) -> Self {
+        Self {
+            state: PatternState::regex(value.as_str()),
+            regex: value,
         }
-        for line in value
-            .inc_author
-            .split('\n')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-        {
-            author.push(Regex::new(line)?);
+    }
+}
+
+impl Pattern {
+    fn describe(&self, author: bool) -> String {
+        let mut s = String::new();
+        if author {
+            s.push_str(self.state.target.name());
+            s.push(' ');
         }
-        for line in value
-            .exc_msg
-            .split('\n')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-        {
-            exc_msg.push(Regex::new(line)?);
+        s.push_str(&format!(
+            "{} {}",
+            self.state.mode.name(),
+            quote(&self.state.text)
+        ));
+        if !self.state.case_sensitive {
+            s.push_str(" (ignore case)");
         }
-        for line in value
-            .exc_author
-            .split('\n')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-        {
-            exc_author.push(Regex::new(line)?);
+        s
+    }
+}
+
+/// Upper bound of the compiled size of a `RegexSet`, large enough for thousands of patterns
+const PATTERN_SET_SIZE_LIMIT: usize = 256 * (1 << 20);
+
+/// The patterns of a group tested against one field
+#[derive(Clone, Debug)]
+struct PatternSet {
+    field: AuthorField,
+    /// Patterns decided by the set alone
+    exact: RegexSet,
+    /// Patterns whose set match is confirmed by their own regex
+    candidates: RegexSet,
+    /// Index in the group of each candidate pattern
+    candidate_idx: Vec<usize>,
+}
+
+/// The patterns of one filter list. They are matched together through `RegexSet`s per
+/// tested field, the individual regexes are kept for [`Filter::explain`].
+#[derive(Clone, Debug)]
+struct PatternGroup {
+    author: bool,
+    patterns: Vec<Pattern>,
+    /// `None` if the sets could not be built, the patterns are then tested one by one
+    sets: Option<Vec<PatternSet>>,
+}
+
+impl PatternGroup {
+    fn new(patterns: Vec<Pattern>, author: bool) -> Self {
+        let mut group = Self {
+            author,
+            patterns,
+            sets: None,
+        };
+        group.build_sets();
+        group
+    }
+
+    /// Message patterns are compiled into a single set, author patterns into one set per
+    /// target field
+    fn build_sets(&mut self) {
+        let fields: &[AuthorField] = if self.author {
+            &AuthorField::ALL
+        } else {
+            &[AuthorField::Login]
+        };
+        let build = |regexes: Vec<String>| {
+            RegexSetBuilder::new(regexes)
+                .size_limit(PATTERN_SET_SIZE_LIMIT)
+                .build()
+        };
+        let mut sets = vec![];
+        for field in fields {
+            let mut exact = vec![];
+            let mut candidates = vec![];
+            let mut candidate_idx = vec![];
+            for (idx, pattern) in self.patterns.iter().enumerate() {
+                if self.author && pattern.state.target != *field {
+                    continue;
+                }
+                match pattern.state.to_set_regex_str() {
+                    (regex, false) => exact.push(regex),
+                    (regex, true) => {
+                        candidates.push(regex);
+                        candidate_idx.push(idx);
+                    }
+                }
+            }
+            if exact.is_empty() && candidates.is_empty() {
+                continue;
+            }
+            match (build(exact), build(candidates)) {
+                (Ok(exact), Ok(candidates)) => sets.push(PatternSet {
+                    field: *field,
+                    exact,
+                    candidates,
+                    candidate_idx,
+                }),
+                (Err(e), _) | (_, Err(e)) => {
+                    log::warn!(
+                        "Matching {} patterns one by one, their set failed to build: {}",
+                        self.patterns.len(),
+                        e
+                    );
+                    self.sets = None;
+                    return;
+                }
+            }
+        }
+        self.sets = Some(sets);
+    }
+
+    fn push(&mut self, pattern: Pattern) {
+        self.patterns.push(pattern);
+        self.build_sets();
+    }
+
+    fn subject<'a>(&self, field: AuthorField, msg: &'a TwitchMsg) -> &'a str {
+        if self.author {
+            field.value(msg)
+        } else {
+            msg.payload()
         }
+    }
+
+    fn is_match(&self, msg: &TwitchMsg) -> bool {
+        let sets = match &self.sets {
+            Some(sets) => sets,
+            None => return self.find(msg).is_some(),
+        };
+        sets.iter().any(|set| {
+            let subject = self.subject(set.field, msg);
+            set.exact.is_match(subject)
+                || (!set.candidates.is_empty()
+                    && set
+                        .candidates
+                        .matches(subject)
+                        .iter()
+                        .any(|i| self.patterns[set.candidate_idx[i]].regex.is_match(subject)))
+        })
+    }
+
+    /// The first pattern matching the message
+    fn find(&self, msg: &TwitchMsg) -> Option<&Pattern> {
+        self.patterns
+            .iter()
+            .find(|pat| pat.regex.is_match(self.subject(pat.state.target, msg)))
+    }
+}
+
+fn build_patterns(states: &[PatternState], author: bool) -> Result<PatternGroup, regex::Error> {
+    let patterns = states
+        .iter()
+        .filter(|p| !p.is_empty())
+        .map(Pattern::try_from)
+        .collect::<Result<_, _>>()?;
+    Ok(PatternGroup::new(patterns, author))
+}
+
+#[derive(Clone, Debug)]
+pub struct Filter {
+    inc_msg_pat: PatternGroup,
+    inc_author_pat: PatternGroup,
+    badge_pat: Vec<String>,
+    exc_msg_pat: PatternGroup,
+    exc_author_pat: PatternGroup,
+    inc_script: Vec<ScriptRule>,
+    exc_script: Vec<ScriptRule>,
+    inc_heuristic: Vec<Heuristic>,
+    exc_heuristic: Vec<Heuristic>,
+    expression_src: String,
+    expression: Option<Expression>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            inc_msg_pat: PatternGroup::new(vec![], false),
+            inc_author_pat: PatternGroup::new(vec![], true),
+            badge_pat: vec![],
+            exc_msg_pat: PatternGroup::new(vec![], false),
+            exc_author_pat: PatternGroup::new(vec![], true),
+            inc_script: vec![],
+            exc_script: vec![],
+            inc_heuristic: vec![],
+            exc_heuristic: vec![],
+            expression_src: String::new(),
+            expression: None,
+        }
+    }
+}
+
+impl TryFrom<&FilterState> for Filter {
+    type Error = FilterError;
+    fn try_from(value: &FilterState) -> Result<Self, Self::Error> {
+        let msg = build_patterns(&value.inc_msg, false)?;
+        let author = build_patterns(&value.inc_author, true)?;
+        let exc_msg = build_patterns(&value.exc_msg, false)?;
+        let exc_author = build_patterns(&value.exc_author, true)?;
+        let mut badge_pat = vec![];
+
         if value.broadcaster {
             badge_pat.push(BROADCASTER_BADGE_NAME.to_owned())
         }
@@ -103,6 +648,11 @@ impl TryFrom<&FilterState> for Filter {
         if value.partner {
             badge_pat.push(PARTNER_BADGE_NAME.to_owned())
         }
+        let expression = if value.use_expression {
+            Some(Expression::parse(&value.expression)?)
+        } else {
+            None
+        };
 
         Ok(Self {
             inc_msg_pat: msg,
@@ -110,62 +660,272 @@ impl TryFrom<&FilterState> for Filter {
             badge_pat,
             exc_msg_pat: exc_msg,
             exc_author_pat: exc_author,
+            inc_script: value.inc_script.clone(),
+            exc_script: value.exc_script.clone(),
+            inc_heuristic: value.inc_heuristic.clone(),
+            exc_heuristic: value.exc_heuristic.clone(),
+            expression_src: value.expression.clone(),
+            expression,
         })
     }
 }
 
 impl Filter {
     pub fn set_msg_pat(&mut self, pat: Vec<Regex>) {
-        self.inc_msg_pat = pat;
+        self.inc_msg_pat = PatternGroup::new(pat.into_iter().map(Pattern::from).collect(), false);
     }
 
     pub fn set_author_pat(&mut self, pat: Vec<Regex>) {
-        self.inc_author_pat = pat;
+        self.inc_author_pat = PatternGroup::new(pat.into_iter().map(Pattern::from).collect(), true);
     }
 
     pub fn set_badge_pat(&mut self, pat: Vec<String>) {
         self.badge_pat = pat;
     }
 
-    pub fn add_author_pat(&mut self, pat: Regex) {
-        self.inc_author_pat.push(pat);
+    /// In expression mode, the author is added to the expression as an equality clause
+    pub fn add_author_pat(&mut self, pat: &PatternState) -> Result<(), FilterError> {
+        if self.expression.is_some() {
+            let source = format!("({}) or {}", self.expression_src, author_clause(pat));
+            return self.set_expression(source);
+        }
+        self.inc_author_pat.push(pat.try_into()?);
+        Ok(())
+    }
+
+    /// In expression mode, the author is excluded in front of the expression
+    pub fn add_exc_author_pat(&mut self, pat: &PatternState) -> Result<(), FilterError> {
+        if self.expression.is_some() {
+            let source = format!("not {} and ({})", author_clause(pat), self.expression_src);
+            return self.set_expression(source);
+        }
+        self.exc_author_pat.push(pat.try_into()?);
+        Ok(())
     }
 
-    pub fn add_exc_author_pat(&mut self, pat: Regex) {
-        self.exc_author_pat.push(pat);
+    fn set_expression(&mut self, source: String) -> Result<(), FilterError> {
+        self.expression = Some(Expression::parse(&source)?);
+        self.expression_src = source;
+        Ok(())
     }
 
     pub fn test(&self, msg: &TwitchMsg) -> bool {
-        for pat in self.exc_msg_pat.iter() {
-            if pat.is_match(msg.payload()) {
-                return false;
+        self.verdict(msg).unwrap_or(false)
+    }
+
+    /// `Some(false)` if an exclusion matches, `Some(true)` if an inclusion or the expression
+    /// matches, `None` otherwise
+    fn verdict(&self, msg: &TwitchMsg) -> Option<bool> {
+        if let Some(expression) = &self.expression {
+            return expression.test(msg).then_some(true);
+        }
+
+        if self.exc_msg_pat.is_match(msg)
+            || self.exc_author_pat.is_match(msg)
+            || self.exc_script.iter().any(|s| s.test(msg))
+            || self.exc_heuristic.iter().any(|h| h.test(msg))
+        {
+            return Some(false);
+        }
+
+        if self.inc_msg_pat.is_match(msg)
+            || self.inc_author_pat.is_match(msg)
+            || self.inc_script.iter().any(|s| s.test(msg))
+            || self.inc_heuristic.iter().any(|h| h.test(msg))
+        {
+            return Some(true);
+        }
+
+        for (badge, _) in msg.badges().iter() {
+            for target in self.badge_pat.iter() {
+                if badge == target {
+                    return Some(true);
+                }
             }
         }
+        None
+    }
 
-        for pat in self.exc_author_pat.iter() {
-            if pat.is_match(msg.sender_login()) {
-                return false;
+    /// Test with the library filters in `library` named in `refs` on top of this filter.
+    /// An exclusion of any of the filters wins over the inclusions of the others.
+    pub fn test_with_library(
+        &self,
+        library: &[(String, Filter)],
+        refs: &[String],
+        msg: &TwitchMsg,
+    ) -> bool {
+        let mut included = false;
+        for filter in std::iter::once(self).chain(library_filters(library, refs).map(|(_, f)| f)) {
+            match filter.verdict(msg) {
+                Some(false) => return false,
+                Some(true) => included = true,
+                None => {}
             }
         }
+        included
+    }
 
-        for pat in self.inc_msg_pat.iter() {
-            if pat.is_match(msg.payload()) {
-                return true;
+    /// Same decision as [`Filter::test_with_library`], explained
+    pub fn explain_with_library(
+        &self,
+        library: &[(String, Filter)],
+        refs: &[String],
+        msg: &TwitchMsg,
+    ) -> Explanation {
+        let mut inclusion = None;
+        for (name, filter) in std::iter::once((None, self)).chain(
+            library_filters(library, refs).map(|(name, filter)| (Some(name.to_owned()), filter)),
+        ) {
+            let explanation = Explanation {
+                library: name,
+                ..filter.explain(msg)
+            };
+            match explanation.stage {
+                FilterStage::ExcludeMessage
+                | FilterStage::ExcludeAuthor
+                | FilterStage::ExcludeScript
+                | FilterStage::ExcludeHeuristic => return explanation,
+                _ if explanation.matched && inclusion.is_none() => inclusion = Some(explanation),
+                _ => {}
             }
         }
+        inclusion.unwrap_or_else(|| self.explain(msg))
+    }
+
+    /// Same decision as [`Filter::test`], along with the stage and rule that made it
+    pub fn explain(&self, msg: &TwitchMsg) -> Explanation {
+        let explanation = |matched, stage, rule| Explanation {
+            matched,
+            stage,
+            rule,
+            library: None,
+        };
+        if let Some(expression) = &self.expression {
+            return explanation(
+                expression.test(msg),
+                FilterStage::Expression,
+                Some(expression.source().to_owned()),
+            );
+        }
+
+        if let Some(pat) = self.exc_msg_pat.find(msg) {
+            return explanation(
+                false,
+                FilterStage::ExcludeMessage,
+                Some(pat.describe(false)),
+            );
+        }
+
+        if let Some(pat) = self.exc_author_pat.find(msg) {
+            return explanation(false, FilterStage::ExcludeAuthor, Some(pat.describe(true)));
+        }
+
+        if let Some(rule) = self.exc_script.iter().find(|s| s.test(msg)) {
+            return explanation(false, FilterStage::ExcludeScript, Some(rule.describe()));
+        }
+
+        if let Some(h) = self.exc_heuristic.iter().find(|h| h.test(msg)) {
+            return explanation(false, FilterStage::ExcludeHeuristic, Some(h.describe()));
+        }
+
+        if let Some(pat) = self.inc_msg_pat.find(msg) {
+            return explanation(true, FilterStage::IncludeMessage, Some(pat.describe(false)));
+        }
+
+        if let Some(pat) = self.inc_author_pat.find(msg) {
+            return explanation(true, FilterStage::IncludeAuthor, Some(pat.describe(true)));
+        }
+
+        if let Some(rule) = self.inc_script.iter().find(|s| s.test(msg)) {
+            return explanation(true, FilterStage::IncludeScript, Some(rule.describe()));
+        }
+
+        if let Some(h) = self.inc_heuristic.iter().find(|h| h.test(msg)) {
+            return explanation(true, FilterStage::IncludeHeuristic, Some(h.describe()));
+        }
+
+        if let Some((badge, _)) = msg
+            .badges()
+            .into_iter()
+            .find(|(badge, _)| self.badge_pat.contains(badge))
+        {
+            return explanation(true, FilterStage::Badge, Some(badge));
+        }
+        explanation(false, FilterStage::Fallthrough, None)
+    }
+}
 
-        for pat in self.inc_author_pat.iter() {
-            if pat.is_match(msg.sender_login()) {
-                return true;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_client::build_msg;
+
+    const PATTERN_COUNT: usize = 2000;
+
+    fn pattern(idx: usize) -> PatternState {
+        let mode = MatchMode::ALL[idx % 4];
+        let text = match mode {
+            MatchMode::Regex => format!("w{}[a-c]+", idx),
+            MatchMode::Text => format!("w{}x", idx),
+            _ => format!("w{}", idx),
+        };
+        PatternState {
+            text,
+            mode,
+            case_sensitive: idx % 3 < 2,
+            target: AuthorField::ALL[idx % 7 % 3],
+        }
+    }
+
+    fn msg(login: &str, display: &str, user_id: &str, text: &str) -> TwitchMsg {
+        let tags = vec![
+            ("display-name".to_owned(), Some(display.to_owned())),
+            ("id".to_owned(), Some(format!("{}-{}", login, text))),
+            ("user-id".to_owned(), Some(user_id.to_owned())),
+        ];
+        build_msg("#chan", login, tags, text).unwrap()
+    }
+
+    fn samples() -> Vec<TwitchMsg> {
+        let mut samples = vec![];
+        for idx in (0..PATTERN_COUNT + 10).step_by(41) {
+            let w = format!("w{}", idx);
+            let upper = w.to_uppercase();
+            for text in [
+                format!("hello {} there", w),
+                w.clone(),
+                format!("{}.", upper),
+                format!("{}x and more", upper),
+                format!("a{}b", w),
+                format!("{}abc", w),
+                format!("{}0", w),
+                format!("x{}", w),
+            ] {
+                samples.push(msg(&text.to_lowercase(), &text, &text, &text));
             }
+            samples.push(msg(&w, &upper, &format!("{}b", w), "plain"));
         }
-        for (badge, _) in msg.badges().iter() {
-            for target in self.badge_pat.iter() {
-                if badge == target {
-                    return true;
-                }
+        samples
+    }
+
+    /// The sets of thousands of patterns decide like the patterns tested one by one
+    #[test]
+    fn pattern_sets() {
+        let states: Vec<_> = (0..PATTERN_COUNT).map(pattern).collect();
+        let samples = samples();
+        for author in [false, true] {
+            let group = build_patterns(&states, author).unwrap();
+            assert_eq!(
+                group.sets.as_ref().map(|s| s.len()),
+                Some(if author { 3 } else { 1 })
+            );
+            let mut matched = 0;
+            for msg in samples.iter() {
+                let expected = group.find(msg).is_some();
+                assert_eq!(group.is_match(msg), expected, "{:?}", msg.payload());
+                matched += expected as usize;
             }
+            assert!(matched > 0 && matched < samples.len());
         }
-        false
     }
 }
@@ -0,0 +1,614 @@
+//! A small boolean expression language for filters.
+//!
+//! ```text
+//! expr    := or
+//! or      := and ("or" and)*
+//! and     := unary ("and" unary)*
+//! unary   := "not" unary | primary
+//! primary := "(" expr ")" | "true" | "false" | "first" | field op value
+//! ```
+//!
+//! Fields are `msg`, `login`, `display`, `user_id`, `badge`, `color` (text) and `bits` (number).
+//! `letters` is the number of letters of the message outside of emotes, and `latin`, `cyrillic`,
+//! `greek`, `arabic`, `hebrew`, `thai`, `han`, `kana`, `hangul` and `cjk` the percentage of them
+//! in that script. `length` (characters), `caps` (percentage of uppercase letters outside of
+//! emotes), `urls`, `emotes`, `mentions` and `run` (longest run of the same character) are
+//! numbers too.
+//! Text fields support `~` (regex match), `=` and `!=` (ignoring ASCII case);
+//! number fields support `=`, `!=`, `<`, `<=`, `>` and `>=`. `first` is true for the first message of a user.
+//! Strings are double quoted, `\"` and `\\` are the only escape sequences.
+
+use std::fmt::Display;
+
+use regex::Regex;
+
+use super::{
+    heuristic::Measure,
+    script::{self, Script},
+};
+use crate::chat_client::TwitchMsg;
+
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    /// 1-based character column in the source
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Expression error at column {}: {}",
+            self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Msg,
+    Login,
+    Display,
+    UserId,
+    Badge,
+    Bits,
+    Color,
+    Letters,
+    Script(Script),
+    Measure(Measure),
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "msg" => Some(Self::Msg),
+            "login" => Some(Self::Login),
+            "display" => Some(Self::Display),
+            "user_id" => Some(Self::UserId),
+            "badge" => Some(Self::Badge),
+            "bits" => Some(Self::Bits),
+            "color" => Some(Self::Color),
+            "letters" => Some(Self::Letters),
+            _ => Script::from_expr_field(name)
+                .map(Self::Script)
+                .or_else(|| Measure::from_expr_field(name).map(Self::Measure)),
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Self::Bits | Self::Letters | Self::Script(_) | Self::Measure(_)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Match,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Const(bool),
+    First,
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    TextEq(Field, String, bool),
+    TextMatch(Field, Regex),
+    Number(Field, Op, u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end_column: source.chars().count() + 1,
+        };
+        let root = parser.parse_or()?;
+        if let Some(t) = parser.peek() {
+            return Err(ParseError {
+                column: t.column,
+                message: format!("unexpected {}", t.kind),
+            });
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            root,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn test(&self, msg: &TwitchMsg) -> bool {
+        eval(&self.root, msg)
+    }
+}
+
+fn eval(node: &Node, msg: &TwitchMsg) -> bool {
+    match node {
+        Node::Const(b) => *b,
+        Node::First => msg.tag("first-msg").map(|s| s == "1").unwrap_or(false),
+        Node::Not(n) => !eval(n, msg),
+        Node::And(a, b) => eval(a, msg) && eval(b, msg),
+        Node::Or(a, b) => eval(a, msg) || eval(b, msg),
+        Node::TextEq(field, value, equal) => {
+            let found = text_values(*field, msg)
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(value));
+            found == *equal
+        }
+        Node::TextMatch(field, regex) => text_values(*field, msg).iter().any(|v| regex.is_match(v)),
+        Node::Number(field, op, value) => {
+            let actual = number_value(*field, msg);
+            match op {
+                Op::Eq => actual == *value,
+                Op::Ne => actual != *value,
+                Op::Lt => actual < *value,
+                Op::Le => actual <= *value,
+                Op::Gt => actual > *value,
+                Op::Ge => actual >= *value,
+                Op::Match => false,
+            }
+        }
+    }
+}
+
+fn text_values(field: Field, msg: &TwitchMsg) -> Vec<String> {
+    match field {
+        Field::Msg => vec![msg.payload().to_owned()],
+        Field::Login => vec![msg.sender_login().to_owned()],
+        Field::Display => vec![msg.sender_display().to_owned()],
+        Field::UserId => msg.user_id().map(|s| s.to_owned()).into_iter().collect(),
+        Field::Badge => msg.badges().into_iter().map(|(name, _)| name).collect(),
+        Field::Color => msg.tag("color").cloned().into_iter().collect(),
+        Field::Bits | Field::Letters | Field::Script(_) | Field::Measure(_) => vec![],
+    }
+}
+
+fn number_value(field: Field, msg: &TwitchMsg) -> u64 {
+    match field {
+        Field::Bits => msg
+            .tag("bits")
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0),
+        Field::Letters => script::letter_count(&script::text_without_emotes(msg)) as u64,
+        Field::Script(s) => script::script_percent(&script::text_without_emotes(msg), s),
+        Field::Measure(m) => m.value(msg),
+        _ => 0,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    Str(String),
+    Num(u64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::Ident(s) => write!(f, "'{}'", s),
+            TokenKind::Str(s) => write!(f, "string \"{}\"", s),
+            TokenKind::Num(n) => write!(f, "number {}", n),
+            TokenKind::Op(_) => write!(f, "operator"),
+            TokenKind::And => write!(f, "'and'"),
+            TokenKind::Or => write!(f, "'or'"),
+            TokenKind::Not => write!(f, "'not'"),
+            TokenKind::LParen => write!(f, "'('"),
+            TokenKind::RParen => write!(f, "')'"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let (kind, len) = match c {
+            '(' => (TokenKind::LParen, 1),
+            ')' => (TokenKind::RParen, 1),
+            '~' => (TokenKind::Op(Op::Match), 1),
+            '=' if chars.get(i + 1) == Some(&'=') => (TokenKind::Op(Op::Eq), 2),
+            '=' => (TokenKind::Op(Op::Eq), 1),
+            '!' if chars.get(i + 1) == Some(&'=') => (TokenKind::Op(Op::Ne), 2),
+            '!' => (TokenKind::Not, 1),
+            '<' if chars.get(i + 1) == Some(&'=') => (TokenKind::Op(Op::Le), 2),
+            '<' => (TokenKind::Op(Op::Lt), 1),
+            '>' if chars.get(i + 1) == Some(&'=') => (TokenKind::Op(Op::Ge), 2),
+            '>' => (TokenKind::Op(Op::Gt), 1),
+            '&' if chars.get(i + 1) == Some(&'&') => (TokenKind::And, 2),
+            '|' if chars.get(i + 1) == Some(&'|') => (TokenKind::Or, 2),
+            '"' => {
+                let mut s = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => {
+                            return Err(ParseError {
+                                column,
+                                message: "unterminated string".to_owned(),
+                            })
+                        }
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.get(j + 1), Some('"') | Some('\\')) => {
+                            s.push(chars[j + 1]);
+                            j += 2;
+                        }
+                        Some(c) => {
+                            s.push(*c);
+                            j += 1;
+                        }
+                    }
+                }
+                (TokenKind::Str(s), j + 1 - i)
+            }
+            c if c.is_ascii_digit() => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+                let text: String = chars[i..i + len].iter().collect();
+                match text.parse() {
+                    Ok(n) => (TokenKind::Num(n), len),
+                    Err(_) => {
+                        return Err(ParseError {
+                            column,
+                            message: "number is too large".to_owned(),
+                        })
+                    }
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_alphanumeric() || **c == '_')
+                    .count();
+                let word: String = chars[i..i + len].iter().collect();
+                let kind = match word.to_lowercase().as_str() {
+                    "and" => TokenKind::And,
+                    "or" => TokenKind::Or,
+                    "not" => TokenKind::Not,
+                    _ => TokenKind::Ident(word.to_lowercase()),
+                };
+                (kind, len)
+            }
+            c => {
+                return Err(ParseError {
+                    column,
+                    message: format!("unexpected character '{}'", c),
+                })
+            }
+        };
+        tokens.push(Token { kind, column });
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    end_column: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, ParseError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t.clone())
+            }
+            None => Err(ParseError {
+                column: self.end_column,
+                message: "unexpected end of expression".to_owned(),
+            }),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.parse_and()?;
+        while self.peek().map(|t| &t.kind) == Some(&TokenKind::Or) {
+            self.pos += 1;
+            node = Node::Or(Box::new(node), Box::new(self.parse_and()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.parse_unary()?;
+        while self.peek().map(|t| &t.kind) == Some(&TokenKind::And) {
+            self.pos += 1;
+            node = Node::And(Box::new(node), Box::new(self.parse_unary()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ParseError> {
+        if self.peek().map(|t| &t.kind) == Some(&TokenKind::Not) {
+            self.pos += 1;
+            return Ok(Node::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Node, ParseError> {
+        let token = self.next()?;
+        match token.kind {
+            TokenKind::LParen => {
+                let node = self.parse_or()?;
+                let close = self.next()?;
+                if close.kind != TokenKind::RParen {
+                    return Err(ParseError {
+                        column: close.column,
+                        message: format!("expected ')', found {}", close.kind),
+                    });
+                }
+                Ok(node)
+            }
+            TokenKind::Ident(name) => match name.as_str() {
+                "true" => Ok(Node::Const(true)),
+                "false" => Ok(Node::Const(false)),
+                "first" => Ok(Node::First),
+                _ => match Field::from_name(&name) {
+                    Some(field) => self.parse_comparison(field),
+                    None => Err(ParseError {
+                        column: token.column,
+                        message: format!("unknown field '{}'", name),
+                    }),
+                },
+            },
+            kind => Err(ParseError {
+                column: token.column,
+                message: format!("expected a condition, found {}", kind),
+            }),
+        }
+    }
+
+    fn parse_comparison(&mut self, field: Field) -> Result<Node, ParseError> {
+        let op_token = self.next()?;
+        let op = match op_token.kind {
+            TokenKind::Op(op) => op,
+            kind => {
+                return Err(ParseError {
+                    column: op_token.column,
+                    message: format!("expected an operator, found {}", kind),
+                })
+            }
+        };
+        let value = self.next()?;
+        match (field.is_numeric(), op, value.kind) {
+            (true, Op::Match, _) => Err(ParseError {
+                column: op_token.column,
+                message: "'~' can not be used on a number field".to_owned(),
+            }),
+            (true, op, TokenKind::Num(n)) => Ok(Node::Number(field, op, n)),
+            (true, _, kind) => Err(ParseError {
+                column: value.column,
+                message: format!("expected a number, found {}", kind),
+            }),
+            (false, Op::Match, TokenKind::Str(s)) => match Regex::new(&s) {
+                Ok(r) => Ok(Node::TextMatch(field, r)),
+                Err(e) => Err(ParseError {
+                    column: value.column,
+                    message: e.to_string(),
+                }),
+            },
+            (false, Op::Eq, TokenKind::Str(s)) => Ok(Node::TextEq(field, s, true)),
+            (false, Op::Ne, TokenKind::Str(s)) => Ok(Node::TextEq(field, s, false)),
+            (false, Op::Match | Op::Eq | Op::Ne, kind) => Err(ParseError {
+                column: value.column,
+                message: format!("expected a string, found {}", kind),
+            }),
+            (false, _, _) => Err(ParseError {
+                column: op_token.column,
+                message: "only '~', '=' and '!=' can be used on a text field".to_owned(),
+            }),
+        }
+    }
+}
+
+/// Quote a string so it can be used as a value in an expression
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chat_client::parse_irc_line,
+        filter::{
+            heuristic::Heuristic,
+            script::{ScriptCondition, ScriptRule},
+            AuthorField, Filter, FilterState, MatchMode, PatternState,
+        },
+    };
+
+    fn msg(login: &str, display: &str, user_id: &str, badges: &str, text: &str) -> TwitchMsg {
+        parse_irc_line(&format!(
+            "@badges={};display-name={};id=1;tmi-sent-ts=1700000000000;user-id={} \
+             :{}!{}@{}.tmi.twitch.tv PRIVMSG #chan :{}",
+            badges, display, user_id, login, login, login, text
+        ))
+        .unwrap()
+    }
+
+    fn eval_str(source: &str, msg: &TwitchMsg) -> bool {
+        Expression::parse(source).unwrap().test(msg)
+    }
+
+    fn error_column(source: &str) -> usize {
+        Expression::parse(source).unwrap_err().column
+    }
+
+    #[test]
+    fn precedence() {
+        let m = msg("foo", "Foo", "1", "", "hi");
+        // `and` binds tighter than `or`
+        assert!(eval_str("true or false and false", &m));
+        assert!(eval_str("false and true or true", &m));
+        assert!(!eval_str("(true or false) and false", &m));
+        // `not` only applies to the next condition
+        assert!(eval_str("not true or true", &m));
+        assert!(!eval_str("not false and false", &m));
+        assert!(!eval_str("not (true or true)", &m));
+        assert!(eval_str("not not true", &m));
+        assert!(eval_str("!false && (false || true)", &m));
+        assert!(eval_str("TRUE AND Not FALSE", &m));
+    }
+
+    #[test]
+    fn comparisons() {
+        let m = msg("foo", "Foo", "42", "moderator/1", "Hello World 123");
+        assert!(eval_str(r#"login = "FOO""#, &m));
+        assert!(eval_str(r#"display != "bar""#, &m));
+        assert!(eval_str(r#"msg ~ "^Hello\s""#, &m));
+        assert!(!eval_str(r#"msg ~ "^hello""#, &m));
+        assert!(eval_str(r#"badge = "moderator" and user_id == "42""#, &m));
+        assert!(eval_str(
+            "length = 15 and letters >= 10 and latin = 100",
+            &m
+        ));
+        assert!(eval_str("bits = 0 and caps < 30 and run <= 2", &m));
+    }
+
+    #[test]
+    fn quoting() {
+        let m = msg("foo", "Foo", "1", "", r#"say "hi" \o/"#);
+        assert!(eval_str(r#"msg = "say \"hi\" \\o/""#, &m));
+        let text = r#"a "quoted" \ backslash"#;
+        let quoted = quote(text);
+        assert_eq!(quoted, r#""a \"quoted\" \\ backslash""#);
+        assert!(eval_str(
+            &format!("msg = {}", quoted),
+            &msg("foo", "Foo", "1", "", text)
+        ));
+        // Other backslashes are kept, so regexes need no double escaping
+        assert!(eval_str(r#"msg ~ "\d""#, &msg("foo", "Foo", "1", "", "a1")));
+    }
+
+    #[test]
+    fn error_columns() {
+        assert_eq!(error_column("msg ~ "), 7);
+        assert_eq!(error_column(r#"msg ? "x""#), 5);
+        assert_eq!(error_column(r#"nick = "x""#), 1);
+        assert_eq!(error_column(r#"bits ~ "1""#), 6);
+        assert_eq!(error_column(r#"bits > "1""#), 8);
+        assert_eq!(error_column(r#"msg > "x""#), 5);
+        assert_eq!(error_column(r#"msg = 1"#), 7);
+        assert_eq!(error_column(r#"msg = "abc"#), 7);
+        assert_eq!(error_column("(true"), 6);
+        assert_eq!(error_column("true true"), 6);
+        assert_eq!(error_column("true and"), 9);
+        assert_eq!(error_column(r#"msg ~ "(""#), 7);
+        assert_eq!(error_column("é and ?"), 7);
+    }
+
+    #[test]
+    fn to_expression_is_lossless() {
+        let pattern = |text: &str, mode, case_sensitive, target| PatternState {
+            text: text.to_owned(),
+            mode,
+            case_sensitive,
+            target,
+        };
+        let state = FilterState {
+            inc_msg: vec![
+                pattern("hello", MatchMode::Text, false, AuthorField::Login),
+                pattern("gg", MatchMode::Word, true, AuthorField::Login),
+                pattern("!cmd", MatchMode::Prefix, false, AuthorField::Login),
+                pattern(r"^\d+$", MatchMode::Regex, true, AuthorField::Login),
+            ],
+            inc_author: vec![
+                pattern("mod_x", MatchMode::Text, false, AuthorField::Login),
+                pattern("Bob", MatchMode::Word, true, AuthorField::Display),
+                pattern(r"^4\d$", MatchMode::Regex, true, AuthorField::UserId),
+            ],
+            exc_msg: vec![pattern("spam", MatchMode::Text, false, AuthorField::Login)],
+            exc_author: vec![pattern("bot", MatchMode::Prefix, true, AuthorField::Login)],
+            moderator: true,
+            inc_script: vec![ScriptRule {
+                condition: ScriptCondition::Mostly,
+                script: crate::filter::script::Script::Cyrillic,
+            }],
+            exc_heuristic: vec![Heuristic::Urls { min: 1 }],
+            ..Default::default()
+        };
+        let filter = Filter::try_from(&state).unwrap();
+        let expression = Expression::parse(&state.to_expression()).unwrap();
+        let msgs = [
+            msg("a", "A", "1", "", "HELLO there"),
+            msg("a", "A", "1", "", "gg wp"),
+            msg("a", "A", "1", "", "GG wp"),
+            msg("a", "A", "1", "", "eggs"),
+            msg("a", "A", "1", "", "!CMD run"),
+            msg("a", "A", "1", "", "12345"),
+            msg("a", "A", "1", "", "hello spam"),
+            msg("a", "A", "1", "", "hello https://x.y"),
+            msg("a", "A", "1", "", "привет мир"),
+            msg("a", "A", "1", "", "nothing here"),
+            msg("xmod_xx", "X", "1", "", "nothing here"),
+            msg("b", "Bob", "1", "", "nothing here"),
+            msg("b", "Bobby", "1", "", "nothing here"),
+            msg("c", "C", "42", "", "nothing here"),
+            msg("c", "C", "420", "", "nothing here"),
+            msg("m", "M", "1", "moderator/1", "nothing here"),
+            msg("m", "M", "1", "vip/1", "nothing here"),
+            msg("botter", "B", "1", "", "hello"),
+            msg("abot", "B", "1", "", "hello"),
+        ];
+        for m in msgs.iter() {
+            assert_eq!(
+                expression.test(m),
+                filter.test(m),
+                "{} {}: {}",
+                m.sender_login(),
+                m.sender_display(),
+                m.payload()
+            );
+        }
+        assert!(filter.test(&msgs[0]) && !filter.test(&msgs[6]));
+    }
+}
@@ -0,0 +1,217 @@
+//! Built-in message predicates, mostly useful to find spam without writing regexes.
+
+use serde::{Deserialize, Serialize};
+
+use super::script::{letter_count, text_without_emotes};
+use crate::chat_client::TwitchMsg;
+
+/// A number computed from a message
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum Measure {
+    /// Characters of the message
+    Length,
+    /// Percentage of uppercase letters outside of emotes
+    Caps,
+    Urls,
+    /// Emotes from the `emotes` tag
+    Emotes,
+    Mentions,
+    /// Longest run of the same character
+    Run,
+}
+
+impl Measure {
+    pub const ALL: [Measure; 6] = [
+        Measure::Length,
+        Measure::Caps,
+        Measure::Urls,
+        Measure::Emotes,
+        Measure::Mentions,
+        Measure::Run,
+    ];
+
+    /// Field name in filter expressions
+    pub fn expr_field(&self) -> &'static str {
+        match self {
+            Measure::Length => "length",
+            Measure::Caps => "caps",
+            Measure::Urls => "urls",
+            Measure::Emotes => "emotes",
+            Measure::Mentions => "mentions",
+            Measure::Run => "run",
+        }
+    }
+
+    pub fn from_expr_field(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.expr_field() == name)
+    }
+
+    pub fn value(&self, msg: &TwitchMsg) -> u64 {
+        let text = msg.payload();
+        match self {
+            Measure::Length => text.chars().count() as u64,
+            Measure::Caps => uppercase_percent(&text_without_emotes(msg)),
+            Measure::Urls => text.split_whitespace().filter(|w| is_url(w)).count() as u64,
+            Measure::Emotes => msg.tag("emotes").map(|s| emote_count(s)).unwrap_or(0),
+            Measure::Mentions => text
+                .split_whitespace()
+                .filter(|w| w.len() > 1 && w.starts_with('@'))
+                .count() as u64,
+            Measure::Run => longest_run(text),
+        }
+    }
+}
+
+/// Percentage of the letters of `text` in uppercase, 0 if there is no letter
+pub fn uppercase_percent(text: &str) -> u64 {
+    let upper = text.chars().filter(|c| c.is_uppercase()).count() as u64;
+    (upper * 100)
+        .checked_div(letter_count(text) as u64)
+        .unwrap_or(0)
+}
+
+fn is_url(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    word.starts_with("http://") || word.starts_with("https://") || word.starts_with("www.")
+}
+
+/// The tag lists the positions of each emote, like `25:0-4,12-16/1902:6-10`
+fn emote_count(tag: &str) -> u64 {
+    tag.split('/')
+        .filter_map(|emote| emote.split_once(':'))
+        .map(|(_, ranges)| ranges.split(',').filter(|r| !r.is_empty()).count() as u64)
+        .sum()
+}
+
+/// Length of the longest run of the same non whitespace character
+pub fn longest_run(text: &str) -> u64 {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut prev = None;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            run = 0;
+            prev = None;
+            continue;
+        }
+        if prev == Some(c) {
+            run += 1;
+        } else {
+            run = 1;
+            prev = Some(c);
+        }
+        longest = longest.max(run);
+    }
+    longest
+}
+
+/// A tunable filter criterion on a measure of the message
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum Heuristic {
+    /// Length in characters between `min` and `max`, inclusive
+    Length {
+        min: u64,
+        max: u64,
+    },
+    /// At least `percent` uppercase letters, for messages with at least `min_letters` letters
+    Uppercase {
+        percent: u64,
+        min_letters: u64,
+    },
+    Urls {
+        min: u64,
+    },
+    Emotes {
+        min: u64,
+    },
+    Mentions {
+        min: u64,
+    },
+    /// A character repeated at least `min` times in a row
+    RepeatedChars {
+        min: u64,
+    },
+}
+
+impl Default for Heuristic {
+    fn default() -> Self {
+        Heuristic::Uppercase {
+            percent: 70,
+            min_letters: 10,
+        }
+    }
+}
+
+impl Heuristic {
+    /// Each kind of heuristic with default values
+    pub const ALL: [Heuristic; 6] = [
+        Heuristic::Length { min: 200, max: 500 },
+        Heuristic::Uppercase {
+            percent: 70,
+            min_letters: 10,
+        },
+        Heuristic::Urls { min: 1 },
+        Heuristic::Emotes { min: 5 },
+        Heuristic::Mentions { min: 3 },
+        Heuristic::RepeatedChars { min: 10 },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Heuristic::Length { .. } => "Length",
+            Heuristic::Uppercase { .. } => "Uppercase ratio",
+            Heuristic::Urls { .. } => "URLs",
+            Heuristic::Emotes { .. } => "Emotes",
+            Heuristic::Mentions { .. } => "Mentions",
+            Heuristic::RepeatedChars { .. } => "Repeated characters",
+        }
+    }
+
+    pub fn test(&self, msg: &TwitchMsg) -> bool {
+        match *self {
+            Heuristic::Length { min, max } => (min..=max).contains(&Measure::Length.value(msg)),
+            Heuristic::Uppercase {
+                percent,
+                min_letters,
+            } => {
+                letter_count(&text_without_emotes(msg)) as u64 >= min_letters
+                    && Measure::Caps.value(msg) >= percent
+            }
+            Heuristic::Urls { min } => Measure::Urls.value(msg) >= min,
+            Heuristic::Emotes { min } => Measure::Emotes.value(msg) >= min,
+            Heuristic::Mentions { min } => Measure::Mentions.value(msg) >= min,
+            Heuristic::RepeatedChars { min } => Measure::Run.value(msg) >= min,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match *self {
+            Heuristic::Length { min, max } => format!("Length {}-{}", min, max),
+            Heuristic::Uppercase {
+                percent,
+                min_letters,
+            } => format!("{}% uppercase of at least {} letters", percent, min_letters),
+            Heuristic::Urls { min } => format!("At least {} URL(s)", min),
+            Heuristic::Emotes { min } => format!("At least {} emote(s)", min),
+            Heuristic::Mentions { min } => format!("At least {} mention(s)", min),
+            Heuristic::RepeatedChars { min } => {
+                format!("A character repeated {} times", min)
+            }
+        }
+    }
+
+    /// Equivalent filter expression
+    pub fn to_expression(&self) -> String {
+        match *self {
+            Heuristic::Length { min, max } => format!("(length >= {} and length <= {})", min, max),
+            Heuristic::Uppercase {
+                percent,
+                min_letters,
+            } => format!("(letters >= {} and caps >= {})", min_letters, percent),
+            Heuristic::Urls { min } => format!("urls >= {}", min),
+            Heuristic::Emotes { min } => format!("emotes >= {}", min),
+            Heuristic::Mentions { min } => format!("mentions >= {}", min),
+            Heuristic::RepeatedChars { min } => format!("run >= {}", min),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Filter presets shared as files.
+//!
+//! A preset is a RON document holding a [`FilterState`] and its metadata:
+//!
+//! ```ron
+//! (
+//!     version: 1,
+//!     name: "Moderation",
+//!     description: "Links and banned phrases",
+//!     filter: (
+//!         inc_msg: [(text: "https?://", mode: Regex, case_sensitive: false, target: Login)],
+//!         inc_author: [],
+//!         exc_msg: [(text: "banned phrase", mode: Text, case_sensitive: false, target: Login)],
+//!         exc_author: [(text: "nightbot", mode: Word, case_sensitive: false, target: Login)],
+//!         broadcaster: false,
+//!         moderator: true,
+//!         vip: false,
+//!         partner: false,
+//!         use_expression: false,
+//!         expression: "",
+//!         inc_script: [],
+//!         exc_script: [(condition: Mostly, script: Cyrillic)],
+//!         inc_heuristic: [],
+//!         exc_heuristic: [Uppercase(percent: 70, min_letters: 10), Urls(min: 2)],
+//!     ),
+//! )
+//! ```
+//!
+//! `mode` is one of `Text`, `Word`, `Prefix` and `Regex`, `target` one of `Login`, `Display`
+//! and `UserId`. Like in the save state, a pattern list may also be a newline separated
+//! string of regexes. A script rule `condition` is one of `Mostly`, `Contains` and
+//! `NoLetters`, the heuristics are the variants of [`Heuristic`](super::heuristic::Heuristic).
+//! The script and heuristic lists may be left out.
+
+use std::{fmt::Display, path::Path};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::{expr::Expression, FilterState, PatternState};
+
+pub const PRESET_VERSION: u32 = 1;
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FilterPreset {
+    pub version: u32,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub filter: FilterState,
+    /// The RON text of a loaded preset, to locate its problems
+    #[serde(skip)]
+    source: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(std::io::Error),
+    Format(String),
+    /// The preset was written by a newer version of the application
+    Version(u32),
+}
+
+impl Display for PresetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetError::Io(e) => write!(f, "{}", e),
+            PresetError::Format(e) => write!(f, "Invalid preset file: {}", e),
+            PresetError::Version(v) => write!(
+                f,
+                "Preset version {} is newer than the supported version {}",
+                v, PRESET_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<std::io::Error> for PresetError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl FilterPreset {
+    pub fn new(name: impl ToString, filter: FilterState) -> Self {
+        Self {
+            version: PRESET_VERSION,
+            name: name.to_string(),
+            description: String::new(),
+            filter,
+            source: None,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), PresetError> {
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| PresetError::Format(e.to_string()))?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, PresetError> {
+        let text = std::fs::read_to_string(path)?;
+        let mut preset: Self =
+            ron::from_str(&text).map_err(|e| PresetError::Format(e.to_string()))?;
+        if preset.version > PRESET_VERSION {
+            return Err(PresetError::Version(preset.version));
+        }
+        preset.source = Some(text);
+        Ok(preset)
+    }
+
+    /// One message per invalid pattern, with its list and its line in the loaded file
+    /// (its position in the list for a preset that was not loaded), and for an invalid expression
+    pub fn problems(&self) -> Vec<String> {
+        let lists: [(&str, &str, &[PatternState]); 4] = [
+            ("Inclusive messages", "inc_msg", &self.filter.inc_msg),
+            ("Inclusive users", "inc_author", &self.filter.inc_author),
+            ("Exclusive messages", "exc_msg", &self.filter.exc_msg),
+            ("Exclusive users", "exc_author", &self.filter.exc_author),
+        ];
+        let mut problems = vec![];
+        for (list, field, patterns) in lists {
+            let lines = match &self.source {
+                Some(source) => pattern_lines(source, field, patterns),
+                None => vec![None; patterns.len()],
+            };
+            for (idx, (pattern, line)) in patterns.iter().zip(lines).enumerate() {
+                if let Err(e) = Regex::new(&pattern.to_regex_str()) {
+                    let e = e.to_string();
+                    let position = match line {
+                        Some(line) => format!("line {}", line),
+                        None => format!("pattern {}", idx + 1),
+                    };
+                    problems.push(format!(
+                        "{} {} \"{}\": {}",
+                        list,
+                        position,
+                        pattern.text,
+                        e.lines().last().unwrap_or(&e).trim_start_matches("error: ")
+                    ));
+                }
+            }
+        }
+        if self.filter.use_expression {
+            if let Err(e) = Expression::parse(&self.filter.expression) {
+                problems.push(e.to_string());
+            }
+        }
+        problems
+    }
+}
+
+/// Line in `source` of each pattern of the list `field`, in order. A pattern is looked up as
+/// a RON string, or as a line of a newline separated string for the legacy format.
+fn pattern_lines(source: &str, field: &str, patterns: &[PatternState]) -> Vec<Option<usize>> {
+    // Keys are searched in the fields of `filter`, outside of strings and comments
+    let blanked = blank_strings(source);
+    let filter = Regex::new(r"\bfilter\s*:").unwrap();
+    let key = Regex::new(&format!(r"\b{}\s*:", field)).unwrap();
+    let mut cursor = match filter
+        .find(&blanked)
+        .and_then(|f| key.find_at(&blanked, f.end()))
+    {
+        Some(m) => m.end(),
+        None => return vec![None; patterns.len()],
+    };
+    patterns
+        .iter()
+        .map(|pattern| {
+            let quoted = ron::to_string(&pattern.text).ok()?;
+            let (pos, len) = [quoted.as_str(), pattern.text.as_str()]
+                .into_iter()
+                .find_map(|s| source[cursor..].find(s).map(|pos| (cursor + pos, s.len())))?;
+            cursor = pos + len;
+            Some(source[..pos].matches('\n').count() + 1)
+        })
+        .collect()
+}
+
+/// `source` with the content of its strings and comments replaced by spaces, keeping the
+/// byte offsets
+fn blank_strings(source: &str) -> String {
+    let mut blanked = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let blank = |blanked: &mut String, c: char| blanked.extend((0..c.len_utf8()).map(|_| ' '));
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                blanked.push(c);
+                while let Some(c) = chars.next() {
+                    if c == '"' {
+                        blanked.push(c);
+                        break;
+                    }
+                    blank(&mut blanked, c);
+                    if c == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            blank(&mut blanked, escaped);
+                        }
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                blanked.push(c);
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    blank(&mut blanked, c);
+                    chars.next();
+                }
+            }
+            _ => blanked.push(c),
+        }
+    }
+    blanked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(text: &str) -> FilterPreset {
+        let mut preset: FilterPreset = ron::from_str(text).unwrap();
+        preset.source = Some(text.to_owned());
+        preset
+    }
+
+    #[test]
+    fn problem_lines() {
+        let preset = preset(
+            r#"(
+    version: 1,
+    name: "inc_msg: links",
+    // exc_msg: is the list of banned phrases
+    description: "exc_msg: [\"(\"]",
+    filter: (
+        inc_msg: [
+            (text: "ok", mode: Regex, case_sensitive: false, target: Login),
+            (text: "(", mode: Regex, case_sensitive: false, target: Login),
+        ],
+        inc_author: [],
+        exc_msg: "fine\n[",
+        exc_author: [],
+        broadcaster: false,
+        moderator: false,
+        vip: false,
+        partner: false,
+    ),
+)"#,
+        );
+        let problems = preset.problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("Inclusive messages line 9 \"(\""));
+        assert!(problems[1].starts_with("Exclusive messages line 12 \"[\""));
+    }
+
+    #[test]
+    fn documented_format() {
+        let source = include_str!("preset.rs")
+            .lines()
+            .take_while(|l| l.starts_with("//!"))
+            .skip_while(|l| !l.starts_with("//! ```ron"))
+            .skip(1)
+            .take_while(|l| !l.starts_with("//! ```"))
+            .map(|l| l.trim_start_matches("//!").trim_start_matches(' '))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let preset = preset(&source);
+        assert!(preset.problems().is_empty());
+        assert_eq!(preset.filter.exc_script.len(), 1);
+        assert_eq!(preset.filter.exc_heuristic.len(), 2);
+    }
+}
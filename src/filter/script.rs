@@ -0,0 +1,314 @@
+//! Writing system composition of messages, computed from Unicode block ranges.
+
+use serde::{Deserialize, Serialize};
+
+use crate::chat_client::TwitchMsg;
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Thai,
+    /// Chinese characters, also used in Japanese and Korean
+    Han,
+    /// Japanese hiragana and katakana
+    Kana,
+    Hangul,
+    /// Han, kana and hangul together
+    Cjk,
+}
+
+impl Script {
+    pub const ALL: [Script; 10] = [
+        Script::Cjk,
+        Script::Han,
+        Script::Kana,
+        Script::Hangul,
+        Script::Latin,
+        Script::Cyrillic,
+        Script::Greek,
+        Script::Arabic,
+        Script::Hebrew,
+        Script::Thai,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Script::Latin => "Latin",
+            Script::Cyrillic => "Cyrillic",
+            Script::Greek => "Greek",
+            Script::Arabic => "Arabic",
+            Script::Hebrew => "Hebrew",
+            Script::Thai => "Thai",
+            Script::Han => "Han",
+            Script::Kana => "Kana",
+            Script::Hangul => "Hangul",
+            Script::Cjk => "CJK",
+        }
+    }
+
+    /// Field name in filter expressions
+    pub fn expr_field(&self) -> &'static str {
+        match self {
+            Script::Latin => "latin",
+            Script::Cyrillic => "cyrillic",
+            Script::Greek => "greek",
+            Script::Arabic => "arabic",
+            Script::Hebrew => "hebrew",
+            Script::Thai => "thai",
+            Script::Han => "han",
+            Script::Kana => "kana",
+            Script::Hangul => "hangul",
+            Script::Cjk => "cjk",
+        }
+    }
+
+    pub fn from_expr_field(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.expr_field() == name)
+    }
+
+    fn contains(&self, c: char) -> bool {
+        match (self, char_script(c)) {
+            (Script::Cjk, Some(Script::Han | Script::Kana | Script::Hangul)) => true,
+            (_, Some(script)) => script == *self,
+            (_, None) => false,
+        }
+    }
+}
+
+/// Script of a letter. Letters of other scripts and non letters are `None`.
+fn char_script(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    let script = match c as u32 {
+        0x41..=0x5A
+        | 0x61..=0x7A
+        | 0xC0..=0x24F
+        | 0x1E00..=0x1EFF
+        | 0x2C60..=0x2C7F
+        | 0xA720..=0xA7FF
+        | 0xFF21..=0xFF3A
+        | 0xFF41..=0xFF5A => Script::Latin,
+        0x370..=0x3FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x400..=0x52F | 0x2DE0..=0x2DFF | 0xA640..=0xA69F => Script::Cyrillic,
+        0x590..=0x5FF => Script::Hebrew,
+        0x600..=0x6FF | 0x750..=0x77F | 0x8A0..=0x8FF | 0xFB50..=0xFDFF | 0xFE70..=0xFEFF => {
+            Script::Arabic
+        }
+        0xE00..=0xE7F => Script::Thai,
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xA960..=0xA97F | 0xAC00..=0xD7FF | 0xFFA0..=0xFFDC => {
+            Script::Hangul
+        }
+        0x3040..=0x30FF | 0x31F0..=0x31FF | 0xFF66..=0xFF9F => Script::Kana,
+        0x2E80..=0x2FDF
+        | 0x3005
+        | 0x3007
+        | 0x3021..=0x3029
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xF900..=0xFAFF
+        | 0x20000..=0x3134F => Script::Han,
+        _ => return None,
+    };
+    Some(script)
+}
+
+/// The text of `msg` with the character ranges of its `emotes` tag, like `25:0-4,12-16/1902:6-10`,
+/// replaced by spaces. Emote names are words but they are not written text.
+pub fn text_without_emotes(msg: &TwitchMsg) -> String {
+    let text = msg.payload();
+    let ranges: Vec<(usize, usize)> = match msg.tag("emotes") {
+        Some(tag) => tag
+            .split('/')
+            .filter_map(|emote| emote.split_once(':'))
+            .flat_map(|(_, ranges)| ranges.split(','))
+            .filter_map(|range| range.split_once('-'))
+            .filter_map(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+            .collect(),
+        None => return text.to_owned(),
+    };
+    text.chars()
+        .enumerate()
+        .map(|(idx, c)| {
+            if ranges
+                .iter()
+                .any(|&(start, end)| (start..=end).contains(&idx))
+            {
+                ' '
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Number of letters in `text`
+pub fn letter_count(text: &str) -> usize {
+    text.chars().filter(|c| c.is_alphabetic()).count()
+}
+
+/// Percentage of the letters of `text` written in `script`, 0 if there is no letter.
+/// It is rounded up, so a single letter of the script is more than 0%.
+pub fn script_percent(text: &str, script: Script) -> u64 {
+    let mut letters: u64 = 0;
+    let mut matched: u64 = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        if script.contains(c) {
+            matched += 1;
+        }
+    }
+    if letters == 0 {
+        0
+    } else {
+        (matched * 100).div_ceil(letters)
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum ScriptCondition {
+    /// At least one letter
+    Contains,
+    /// More than half of the letters
+    Mostly,
+    /// The message has no letter outside of its emotes, only digits, symbols or emojis.
+    /// The script is ignored.
+    NoLetters,
+}
+
+impl ScriptCondition {
+    pub const ALL: [ScriptCondition; 3] = [
+        ScriptCondition::Mostly,
+        ScriptCondition::Contains,
+        ScriptCondition::NoLetters,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptCondition::Contains => "Contains",
+            ScriptCondition::Mostly => "Mostly",
+            ScriptCondition::NoLetters => "No letters",
+        }
+    }
+}
+
+/// A filter criterion on the writing system of the message text, emotes left out
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub struct ScriptRule {
+    pub condition: ScriptCondition,
+    pub script: Script,
+}
+
+impl Default for ScriptRule {
+    fn default() -> Self {
+        Self {
+            condition: ScriptCondition::Mostly,
+            script: Script::Cjk,
+        }
+    }
+}
+
+impl ScriptRule {
+    pub fn test(&self, msg: &TwitchMsg) -> bool {
+        let text = &text_without_emotes(msg);
+        match self.condition {
+            ScriptCondition::Contains => text.chars().any(|c| self.script.contains(c)),
+            ScriptCondition::Mostly => script_percent(text, self.script) > 50,
+            ScriptCondition::NoLetters => letter_count(text) == 0,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self.condition {
+            ScriptCondition::NoLetters => self.condition.name().to_owned(),
+            _ => format!("{} {}", self.condition.name(), self.script.name()),
+        }
+    }
+
+    /// Equivalent filter expression
+    pub fn to_expression(&self) -> String {
+        match self.condition {
+            ScriptCondition::Contains => format!("{} > 0", self.script.expr_field()),
+            ScriptCondition::Mostly => format!("{} > 50", self.script.expr_field()),
+            ScriptCondition::NoLetters => "letters = 0".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat_client::parse_irc_line, filter::expr::Expression};
+
+    fn msg(emotes: &str, text: &str) -> TwitchMsg {
+        parse_irc_line(&format!(
+            "@display-name=Foo;emotes={};id=1;user-id=1 :foo!foo@foo.tmi.twitch.tv PRIVMSG #chan :{}",
+            emotes, text
+        ))
+        .unwrap()
+    }
+
+    /// The rule and its expression agree
+    fn test(condition: ScriptCondition, script: Script, msg: &TwitchMsg) -> bool {
+        let rule = ScriptRule { condition, script };
+        let verdict = rule.test(msg);
+        let expression = Expression::parse(&rule.to_expression()).unwrap();
+        assert_eq!(expression.test(msg), verdict, "{}", rule.to_expression());
+        verdict
+    }
+
+    #[test]
+    fn without_emotes() {
+        assert_eq!(
+            text_without_emotes(&msg("25:0-4,12-16/1902:6-10", "Kappa Keepo Kappa!")),
+            "                 !"
+        );
+        assert_eq!(text_without_emotes(&msg("", "Kappa")), "Kappa");
+        assert_eq!(text_without_emotes(&msg("25:2-6", "好 Kappa")), "好      ");
+        assert_eq!(text_without_emotes(&msg("25:x-y,0-1", "ab c")), "   c");
+    }
+
+    #[test]
+    fn mostly() {
+        let mostly = |script, m: &TwitchMsg| test(ScriptCondition::Mostly, script, m);
+        assert!(mostly(Script::Cyrillic, &msg("", "привет all")));
+        assert!(!mostly(Script::Latin, &msg("", "привет all")));
+        assert!(!mostly(Script::Cyrillic, &msg("", "привет everyone")));
+        assert!(mostly(Script::Cjk, &msg("", "こんにちは 世界 hi")));
+        assert!(mostly(Script::Kana, &msg("", "こんにちは 世界")));
+        // Emote names are not counted
+        assert!(mostly(
+            Script::Han,
+            &msg("25:3-5/245:7-21", "你好 LUL ResidentSleeper")
+        ));
+        assert!(!mostly(Script::Latin, &msg("", "")));
+    }
+
+    #[test]
+    fn contains() {
+        let contains = |script, m: &TwitchMsg| test(ScriptCondition::Contains, script, m);
+        assert!(contains(Script::Hangul, &msg("", "hello 안녕")));
+        assert!(contains(Script::Cjk, &msg("", "hello 안녕")));
+        assert!(!contains(Script::Han, &msg("", "hello 안녕")));
+        assert!(!contains(Script::Latin, &msg("25:0-4", "Kappa 123")));
+        assert!(contains(Script::Latin, &msg("25:0-4", "Kappa a")));
+    }
+
+    #[test]
+    fn no_letters() {
+        let no_letters = |m: &TwitchMsg| test(ScriptCondition::NoLetters, Script::Latin, m);
+        assert!(no_letters(&msg("", "123 !!! 😀")));
+        assert!(!no_letters(&msg("", "ok 123")));
+        // An emote only message
+        assert!(no_letters(&msg(
+            "25:0-4,6-10/1902:12-16",
+            "Kappa Kappa Keepo"
+        )));
+        assert!(!no_letters(&msg("25:0-4", "Kappa x")));
+        assert!(!no_letters(&msg("", "Kappa Kappa Keepo")));
+    }
+}
@@ -1,14 +1,21 @@
 use once_cell::sync::Lazy;
 use tokio::runtime::Runtime;
 
+pub mod alert;
 pub mod audio_player;
 pub mod chat_client;
+pub mod chat_log;
+pub mod command;
 pub mod filter;
+pub mod msg_store;
+pub mod rule;
+pub mod template;
 pub mod ui_app;
 
 pub const DEFAULT_FONT_SIZE: f32 = 18.0;
 pub const MAX_MESSAGE_COUNT: usize = 1000;
 pub const APP_SAVE_STATE_KEY: &str = "save";
+pub const DEFAULT_VIEW_NAME: &str = "Filtered";
 
 static ASYNC_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
     tokio::runtime::Builder::new_multi_thread()
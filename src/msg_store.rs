@@ -0,0 +1,626 @@
+//! Message store: every message of a channel appended to local files, with indexes to query
+//! them by sent time, user, message id and reply thread.
+//!
+//! Messages are written as raw IRC lines to `{dir}/{channel}/{yyyy}-{mm}-{dd}.irc`, one file per
+//! day of arrival. The indexes are kept in memory and rebuilt from the files when the store is
+//! opened. Retention removes whole days. The store is off until enabled in the settings.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWriteExt, BufWriter},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+};
+
+use crate::{
+    chat_client::{parse_irc_line, TwitchMsg},
+    ASYNC_RUNTIME,
+};
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct StoreSettings {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Days of messages kept, including today. 0 keeps every day, the indexes in memory then
+    /// grow with every stored message.
+    pub keep_days: u32,
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("store"),
+            keep_days: 30,
+        }
+    }
+}
+
+/// Criteria of a store query, unset criteria match every message
+#[derive(Clone, Default, Debug)]
+pub struct QuerySpec {
+    pub id: Option<String>,
+    /// Login of the sender, case insensitive
+    pub user: Option<String>,
+    /// Replies of a thread, by the id of its root message
+    pub thread: Option<String>,
+    /// Sent time, inclusive
+    pub from: Option<DateTime<Utc>>,
+    /// Sent time, exclusive
+    pub to: Option<DateTime<Utc>>,
+    /// Matched against the message text
+    pub text: Option<Regex>,
+    /// Only the newest matching messages
+    pub limit: Option<usize>,
+}
+
+impl QuerySpec {
+    fn matches(&self, msg: &TwitchMsg) -> bool {
+        self.id.as_ref().map(|id| id == msg.id()).unwrap_or(true)
+            && self
+                .user
+                .as_ref()
+                .map(|user| user.eq_ignore_ascii_case(msg.sender_login()))
+                .unwrap_or(true)
+            && self
+                .thread
+                .as_ref()
+                .map(|root| msg.reply_thread_root_id() == Some(root))
+                .unwrap_or(true)
+            && self
+                .text
+                .as_ref()
+                .map(|text| text.is_match(msg.payload()))
+                .unwrap_or(true)
+    }
+}
+
+const STORE_QUEUE_LEN: usize = 4096;
+/// Longest time a message stays in the write buffer, and out of the indexes
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+static DAY_FILE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(\d{4}-\d{2}-\d{2})\.irc$").unwrap());
+
+fn day_path(dir: &Path, day: NaiveDate) -> PathBuf {
+    dir.join(day.format("%Y-%m-%d.irc").to_string())
+}
+
+/// Sent time in milliseconds, and a sequence number for messages sent at the same time
+type Key = (i64, u64);
+
+/// Position of a message line in the files
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    day: NaiveDate,
+    offset: u64,
+    len: u32,
+}
+
+/// The indexed fields of a message
+struct Indexed {
+    time: i64,
+    login: String,
+    id: String,
+    thread: Option<String>,
+    entry: Entry,
+}
+
+impl Indexed {
+    fn new(msg: &TwitchMsg, entry: Entry) -> Self {
+        Self {
+            time: msg.sent_time().unwrap_or_else(Utc::now).timestamp_millis(),
+            login: msg.sender_login().to_lowercase(),
+            id: msg.id().to_owned(),
+            thread: msg.reply_thread_root_id().cloned(),
+            entry,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct StoreIndex {
+    next_seq: u64,
+    by_time: BTreeMap<Key, Entry>,
+    by_user: HashMap<String, Vec<Key>>,
+    by_id: HashMap<String, Key>,
+    by_thread: HashMap<String, Vec<Key>>,
+}
+
+impl StoreIndex {
+    fn insert(&mut self, indexed: Indexed) {
+        if self.by_id.contains_key(&indexed.id) {
+            return;
+        }
+        let key = (indexed.time, self.next_seq);
+        self.next_seq += 1;
+        self.by_time.insert(key, indexed.entry);
+        self.by_user.entry(indexed.login).or_default().push(key);
+        self.by_id.insert(indexed.id, key);
+        if let Some(thread) = indexed.thread {
+            self.by_thread.entry(thread).or_default().push(key);
+        }
+    }
+
+    /// Forget the messages of the days before `first_day`
+    fn remove_before(&mut self, first_day: NaiveDate) {
+        self.by_time.retain(|_, entry| entry.day >= first_day);
+        let by_time = &self.by_time;
+        for keys in self.by_user.values_mut().chain(self.by_thread.values_mut()) {
+            keys.retain(|key| by_time.contains_key(key));
+        }
+        self.by_user.retain(|_, keys| !keys.is_empty());
+        self.by_thread.retain(|_, keys| !keys.is_empty());
+        self.by_id.retain(|_, key| by_time.contains_key(key));
+    }
+
+    /// Positions of the messages the indexes can select for `spec`, oldest first
+    fn candidates(&self, spec: &QuerySpec) -> Vec<Entry> {
+        let from = spec.from.map(|t| t.timestamp_millis());
+        let to = spec.to.map(|t| t.timestamp_millis());
+        let mut keys: Vec<Key> = if let Some(id) = &spec.id {
+            self.by_id.get(id).into_iter().copied().collect()
+        } else if let Some(thread) = &spec.thread {
+            self.by_thread.get(thread).cloned().unwrap_or_default()
+        } else if let Some(user) = &spec.user {
+            self.by_user
+                .get(&user.to_lowercase())
+                .cloned()
+                .unwrap_or_default()
+        } else {
+            let start = from
+                .map(|t| Bound::Included((t, 0)))
+                .unwrap_or(Bound::Unbounded);
+            let end = to
+                .map(|t| Bound::Excluded((t, 0)))
+                .unwrap_or(Bound::Unbounded);
+            return self.by_time.range((start, end)).map(|(_, e)| *e).collect();
+        };
+        keys.retain(|(time, _)| {
+            from.map(|from| *time >= from).unwrap_or(true)
+                && to.map(|to| *time < to).unwrap_or(true)
+        });
+        keys.sort();
+        keys.iter()
+            .filter_map(|key| self.by_time.get(key).copied())
+            .collect()
+    }
+}
+
+/// Remove the files of the days before the kept days, returning the first kept day
+fn remove_old_days(
+    dir: &Path,
+    keep_days: u32,
+    today: NaiveDate,
+) -> std::io::Result<Option<NaiveDate>> {
+    if keep_days == 0 {
+        return Ok(None);
+    }
+    let first_day = today - chrono::Duration::days(keep_days as i64 - 1);
+    for (day, path) in day_files(dir)? {
+        if day < first_day {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(Some(first_day))
+}
+
+fn day_files(dir: &Path) -> std::io::Result<Vec<(NaiveDate, PathBuf)>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let day = DAY_FILE
+            .captures(&name)
+            .and_then(|c| NaiveDate::parse_from_str(&c[1], "%Y-%m-%d").ok());
+        if let Some(day) = day {
+            files.push((day, entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Apply the retention and index the remaining files.
+/// Every kept line is read and parsed again on each start, so opening takes time and memory
+/// in proportion to the stored messages, `keep_days` bounds both.
+fn load_index(dir: &Path, keep_days: u32) -> std::io::Result<StoreIndex> {
+    std::fs::create_dir_all(dir)?;
+    remove_old_days(dir, keep_days, Local::now().date_naive())?;
+    let mut index = StoreIndex::default();
+    for (day, path) in day_files(dir)? {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut line = String::new();
+        let mut offset = 0;
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            let entry = Entry {
+                day,
+                offset,
+                len: line.trim_end().len() as u32,
+            };
+            if let Some(msg) = parse_irc_line(line.trim_end()) {
+                index.insert(Indexed::new(&msg, entry));
+            }
+            offset += read as u64;
+        }
+    }
+    Ok(index)
+}
+
+enum StoreCommand {
+    Msg(Box<TwitchMsg>),
+    Flush(oneshot::Sender<()>),
+}
+
+struct DayFile {
+    day: NaiveDate,
+    len: u64,
+    writer: BufWriter<tokio::fs::File>,
+}
+
+/// Writes messages and adds them to the indexes once they are on disk
+struct StoreWriter {
+    dir: PathBuf,
+    keep_days: u32,
+    index: Arc<RwLock<StoreIndex>>,
+    current: Option<DayFile>,
+    /// Written but not flushed yet
+    pending: Vec<Indexed>,
+}
+
+impl StoreWriter {
+    async fn open(&mut self, day: NaiveDate) -> std::io::Result<()> {
+        self.flush().await?;
+        if let Some(first_day) = remove_old_days(&self.dir, self.keep_days, day)? {
+            self.index.write().unwrap().remove_before(first_day);
+        }
+        let path = day_path(&self.dir, day);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .await?;
+        let mut len = file.metadata().await?.len();
+        // A line cut by a crash is ended so that the next message starts its own line
+        if len > 0 && !ends_with_newline(&path)? {
+            file.write_all(b"\n").await?;
+            len += 1;
+        }
+        self.current = Some(DayFile {
+            day,
+            len,
+            writer: BufWriter::new(file),
+        });
+        Ok(())
+    }
+
+    async fn write_msg(&mut self, msg: &TwitchMsg) -> std::io::Result<()> {
+        if self.index.read().unwrap().by_id.contains_key(msg.id())
+            || self.pending.iter().any(|p| p.id == msg.id())
+        {
+            return Ok(());
+        }
+        let today = Local::now().date_naive();
+        if self.current.as_ref().map(|c| c.day) != Some(today) {
+            self.open(today).await?;
+        }
+        let current = self.current.as_mut().unwrap();
+        let line = msg.raw_line().trim_end().to_owned();
+        current.writer.write_all(line.as_bytes()).await?;
+        current.writer.write_all(b"\n").await?;
+        self.pending.push(Indexed::new(
+            msg,
+            Entry {
+                day: today,
+                offset: current.len,
+                len: line.len() as u32,
+            },
+        ));
+        current.len += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(current) = &mut self.current {
+            current.writer.flush().await?;
+        }
+        if !self.pending.is_empty() {
+            let mut index = self.index.write().unwrap();
+            for indexed in self.pending.drain(..) {
+                index.insert(indexed);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ends_with_newline(path: &Path) -> std::io::Result<bool> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0];
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+async fn run_store(mut writer: StoreWriter, mut rx: mpsc::Receiver<StoreCommand>) {
+    let dir = writer.dir.clone();
+    let keep_days = writer.keep_days;
+    match tokio::task::spawn_blocking(move || load_index(&dir, keep_days)).await {
+        Ok(Ok(index)) => *writer.index.write().unwrap() = index,
+        Ok(Err(e)) => log::error!("Failed to load {}: {}", writer.dir.display(), e),
+        Err(e) => log::error!("Failed to load {}: {}", writer.dir.display(), e),
+    }
+    let mut flush_interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut failed = false;
+    loop {
+        let result = tokio::select! {
+            command = rx.recv() => match command {
+                Some(StoreCommand::Msg(msg)) if !failed => writer.write_msg(&msg).await,
+                Some(StoreCommand::Msg(_)) => Ok(()),
+                Some(StoreCommand::Flush(done)) => {
+                    let result = writer.flush().await;
+                    let _ = done.send(());
+                    result
+                }
+                None => break,
+            },
+            _ = flush_interval.tick() => writer.flush().await,
+        };
+        if let Err(e) = result {
+            if !failed {
+                log::error!("Failed to write {}: {}", writer.dir.display(), e);
+                failed = true;
+            }
+        }
+    }
+    if let Err(e) = writer.flush().await {
+        log::error!("Failed to write {}: {}", writer.dir.display(), e);
+    }
+}
+
+/// The message store of a channel. Messages are written by its own task,
+/// queries read the files of the messages selected by the indexes.
+#[derive(Clone, Debug)]
+pub struct MessageStore {
+    settings: StoreSettings,
+    dir: PathBuf,
+    index: Arc<RwLock<StoreIndex>>,
+    tx: mpsc::Sender<StoreCommand>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl MessageStore {
+    /// Start the writer, which indexes the existing files first
+    pub fn open(settings: &StoreSettings, channel: &str) -> Self {
+        let dir = settings.dir.join(channel.trim_start_matches('#'));
+        let index = Arc::new(RwLock::new(StoreIndex::default()));
+        let (tx, rx) = mpsc::channel(STORE_QUEUE_LEN);
+        let writer = StoreWriter {
+            dir: dir.clone(),
+            keep_days: settings.keep_days,
+            index: index.clone(),
+            current: None,
+            pending: vec![],
+        };
+        ASYNC_RUNTIME.spawn(run_store(writer, rx));
+        Self {
+            settings: settings.clone(),
+            dir,
+            index,
+            tx,
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn settings(&self) -> &StoreSettings {
+        &self.settings
+    }
+
+    /// Queue a message without waiting. It is dropped if the writer is too far behind.
+    pub fn write(&self, msg: &TwitchMsg) {
+        if let Err(TrySendError::Full(_)) =
+            self.tx.try_send(StoreCommand::Msg(Box::new(msg.clone())))
+        {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % 1000 == 1 {
+                log::warn!(
+                    "Message store {} is behind, {} messages dropped",
+                    self.dir.display(),
+                    dropped
+                );
+            }
+        }
+    }
+
+    /// Wait until the queued messages are written to disk and indexed
+    pub async fn flush(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.tx.send(StoreCommand::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    /// Number of indexed messages sent by `login`
+    pub fn user_msg_count(&self, login: &str) -> usize {
+        self.index
+            .read()
+            .unwrap()
+            .by_user
+            .get(&login.to_lowercase())
+            .map(|keys| keys.len())
+            .unwrap_or(0)
+    }
+
+    /// Messages matching `spec`, oldest first. Messages still in the write buffer are not
+    /// indexed yet, see [`MessageStore::flush`].
+    pub fn query(&self, spec: &QuerySpec) -> std::io::Result<Vec<TwitchMsg>> {
+        let candidates = self.index.read().unwrap().candidates(spec);
+        let mut files: HashMap<NaiveDate, File> = HashMap::new();
+        let mut msgs = vec![];
+        for entry in candidates.into_iter().rev() {
+            if spec.limit.map(|limit| msgs.len() >= limit).unwrap_or(false) {
+                break;
+            }
+            let file = match files.get_mut(&entry.day) {
+                Some(file) => file,
+                None => match File::open(day_path(&self.dir, entry.day)) {
+                    Ok(file) => files.entry(entry.day).or_insert(file),
+                    // Removed by the retention since the query started
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+            file.seek(SeekFrom::Start(entry.offset))?;
+            let mut line = vec![0; entry.len as usize];
+            file.read_exact(&mut line)?;
+            if let Some(msg) = parse_irc_line(&String::from_utf8_lossy(&line)) {
+                if spec.matches(&msg) {
+                    msgs.push(msg);
+                }
+            }
+        }
+        msgs.reverse();
+        Ok(msgs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn msg(id: &str, login: &str, ts: i64, extra_tags: &str, text: &str) -> TwitchMsg {
+        parse_irc_line(&format!(
+            "@display-name={};id={};tmi-sent-ts={};user-id=1{} :{}!{}@{}.tmi.twitch.tv PRIVMSG #chan :{}",
+            login, id, ts, extra_tags, login, login, login, text
+        ))
+        .unwrap()
+    }
+
+    fn settings(name: &str, keep_days: u32) -> StoreSettings {
+        let dir = std::env::temp_dir().join(format!("msg-store-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        StoreSettings {
+            enabled: true,
+            dir,
+            keep_days,
+        }
+    }
+
+    fn ids(msgs: &[TwitchMsg]) -> Vec<&str> {
+        msgs.iter().map(|m| m.id()).collect()
+    }
+
+    fn open(settings: &StoreSettings) -> MessageStore {
+        let store = MessageStore::open(settings, "#chan");
+        // The writer handles the flush once the existing files are indexed
+        ASYNC_RUNTIME.block_on(store.flush());
+        store
+    }
+
+    #[test]
+    fn append_query_reopen() {
+        let settings = settings("query", 30);
+        let store = open(&settings);
+        let reply = ";reply-parent-msg-id=a;reply-thread-parent-msg-id=a";
+        store.write(&msg("a", "foo", 1_000, "", "root"));
+        store.write(&msg("b", "Bar", 3_000, reply, "first reply"));
+        store.write(&msg("c", "foo", 2_000, reply, "second reply"));
+        store.write(&msg("a", "foo", 1_000, "", "written twice"));
+        ASYNC_RUNTIME.block_on(store.flush());
+        let reply_text = Regex::new("reply").unwrap();
+        // The second store rebuilds its indexes from the files
+        for store in [store, open(&settings)] {
+            let query = |spec: QuerySpec| store.query(&spec).unwrap();
+            assert_eq!(ids(&query(QuerySpec::default())), vec!["a", "c", "b"]);
+            assert_eq!(query(QuerySpec::default())[0].payload(), "root");
+            let user = |user: &str| QuerySpec {
+                user: Some(user.to_owned()),
+                ..QuerySpec::default()
+            };
+            assert_eq!(ids(&query(user("FOO"))), vec!["a", "c"]);
+            assert_eq!(store.user_msg_count("bar"), 1);
+            let thread = QuerySpec {
+                thread: Some("a".to_owned()),
+                ..QuerySpec::default()
+            };
+            assert_eq!(ids(&query(thread)), vec!["c", "b"]);
+            let id = QuerySpec {
+                id: Some("b".to_owned()),
+                ..QuerySpec::default()
+            };
+            assert_eq!(query(id)[0].payload(), "first reply");
+            let range = QuerySpec {
+                from: Utc.timestamp_millis_opt(2_000).single(),
+                to: Utc.timestamp_millis_opt(3_000).single(),
+                ..QuerySpec::default()
+            };
+            assert_eq!(ids(&query(range)), vec!["c"]);
+            let text = QuerySpec {
+                text: Some(reply_text.clone()),
+                limit: Some(1),
+                ..QuerySpec::default()
+            };
+            assert_eq!(ids(&query(text)), vec!["b"]);
+        }
+        std::fs::remove_dir_all(&settings.dir).unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        for keep_days in [2, 0] {
+            let settings = settings(&format!("retention-{}", keep_days), keep_days);
+            let dir = settings.dir.join("chan");
+            std::fs::create_dir_all(&dir).unwrap();
+            let today = Local::now().date_naive();
+            let days = [
+                today - chrono::Duration::days(2),
+                today - chrono::Duration::days(1),
+                today,
+            ];
+            for (idx, day) in days.into_iter().enumerate() {
+                let line = msg(&idx.to_string(), "foo", idx as i64, "", "hi").raw_line();
+                std::fs::write(day_path(&dir, day), line.trim_end().to_owned() + "\n").unwrap();
+            }
+            let store = open(&settings);
+            let kept = ids(&store.query(&QuerySpec::default()).unwrap())
+                .into_iter()
+                .map(|id| id.to_owned())
+                .collect::<Vec<_>>();
+            let old_file = day_path(&dir, days[0]).exists();
+            std::fs::remove_dir_all(&settings.dir).unwrap();
+            if keep_days == 0 {
+                assert_eq!(kept, vec!["0", "1", "2"]);
+                assert!(old_file);
+            } else {
+                assert_eq!(kept, vec!["1", "2"]);
+                assert!(!old_file);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio_player::{AlertPlayer, AlertSettings},
+    chat_client::TwitchMsg,
+    chat_log::{LogFormat, LogTarget},
+    filter::{expr::Expression, FilterError},
+};
+
+/// A filter expression with the actions to take on matching messages
+#[derive(Clone, Deserialize, Serialize, Default, PartialEq, Eq, Debug)]
+pub struct RuleState {
+    pub name: String,
+    pub condition: String,
+    #[serde(default)]
+    pub highlight: Option<[u8; 3]>,
+    #[serde(default)]
+    pub sound: bool,
+    /// Sound file of the alert, the sound of the channel if `None`
+    #[serde(default)]
+    pub sound_file: Option<PathBuf>,
+    /// Cooldown and queue policy of this rule, shared with the channel if `None`
+    #[serde(default)]
+    pub alert_settings: Option<AlertSettings>,
+    /// Repeat the alert every this many seconds until the message is acknowledged
+    #[serde(default)]
+    pub urgent: Option<u64>,
+    #[serde(default)]
+    pub hide: bool,
+    #[serde(default)]
+    pub log: Option<PathBuf>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    state: RuleState,
+    condition: Expression,
+    alert: Option<AlertPlayer>,
+    log: Option<LogTarget>,
+}
+
+impl TryFrom<&RuleState> for Rule {
+    type Error = FilterError;
+    fn try_from(value: &RuleState) -> Result<Self, Self::Error> {
+        Ok(Self {
+            state: value.clone(),
+            condition: Expression::parse(&value.condition)?,
+            alert: None,
+            log: None,
+        })
+    }
+}
+
+impl Rule {
+    pub fn state(&self) -> &RuleState {
+        &self.state
+    }
+
+    pub fn test(&self, msg: &TwitchMsg) -> bool {
+        self.condition.test(msg)
+    }
+
+    pub fn set_alert(&mut self, alert: Option<AlertPlayer>) {
+        self.alert = alert;
+    }
+
+    pub fn alert(&self) -> Option<&AlertPlayer> {
+        self.alert.as_ref().filter(|_| self.state.sound)
+    }
+
+    /// The log of `state().log`, started by the channel
+    pub fn set_log(&mut self, log: Option<LogTarget>) {
+        self.log = log;
+    }
+
+    pub fn take_log(&mut self) -> Option<LogTarget> {
+        self.log.take()
+    }
+
+    pub fn log(&self) -> Option<&LogTarget> {
+        self.log.as_ref()
+    }
+
+    pub fn log_error(&self) -> Option<String> {
+        self.log.as_ref().and_then(|l| l.error())
+    }
+}
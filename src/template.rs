@@ -0,0 +1,45 @@
+//! `{name}` placeholders shared by the external command arguments and the log paths.
+
+/// Replace the `{name}` placeholders of `arg` in a single pass,
+/// so placeholders inside the replacement values, like a message text, are left as they are
+pub(crate) fn expand(arg: &str, fields: &[(&str, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        result += &rest[..start];
+        rest = &rest[start..];
+        let field = rest.find('}').and_then(|end| {
+            fields
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match field {
+            Some((end, value)) => {
+                result += value;
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result + rest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_pass() {
+        let fields = vec![("msg", "{login} {".to_owned()), ("login", "foo".to_owned())];
+        assert_eq!(
+            expand("<{login}> {msg} {nope} {msg", &fields),
+            "<foo> {login} { {nope} {msg"
+        );
+        assert_eq!(expand("{}{{login}}", &fields), "{}{foo}");
+        assert_eq!(expand("é{login}é", &fields), "éfooé");
+    }
+}
@@ -1,16 +1,29 @@
 use crate::{
-    audio_player::AlertPlayer,
+    alert::AlertCondition,
+    audio_player::{AlertPlayer, AlertSettings, AudioStatus, QueuePolicy, SOUND_EXTENSIONS},
     chat_client::{self, IrcClient, TwitchMsg},
-    filter::Filter,
+    chat_log::{
+        read_log_file, sanitize, search_logs, LogFormat, LogHit, LogQuery, LogSettings, Rotation,
+    },
+    command::{parse_template, CommandSettings},
+    filter::{
+        expr::Expression,
+        heuristic::Heuristic,
+        preset::FilterPreset,
+        script::{Script, ScriptCondition, ScriptRule},
+        AuthorField, Explanation, Filter, FilterError, FilterLibrary, MatchMode, PatternState,
+    },
+    msg_store::{QuerySpec, StoreSettings},
+    rule::RuleState,
 };
 extern crate lab;
 use super::{
-    chat_client::ChannelConnectionState, chat_client::ChannelManager, filter::FilterState,
-    ASYNC_RUNTIME,
+    chat_client::ChannelConnectionState, chat_client::ChannelManager,
+    chat_client::DuplicateSettings, filter::FilterState, ASYNC_RUNTIME, DEFAULT_VIEW_NAME,
 };
 use arboard::Clipboard;
 use cached::proc_macro::cached;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use eframe::{
     egui::{
         self, ComboBox, Context, DragValue, FontData, FontDefinitions, FontFamily::*, FontId,
@@ -47,7 +60,158 @@ pub enum AppState {
     Normal,
     Config,
     ChannelList,
-    ChannelConfig(usize, FilterState),
+    Logs,
+    ChannelConfig(usize, Box<ChannelConfigState>),
+}
+
+/// Edited configuration of a channel, applied when leaving the configuration screen
+#[derive(PartialEq)]
+pub struct ChannelConfigState {
+    views: Vec<ViewConfigState>,
+    rules: Vec<RuleState>,
+    alert_sound: Option<PathBuf>,
+    alert_settings: AlertSettings,
+    command: CommandSettings,
+    log: LogSettings,
+    duplicates: DuplicateSettings,
+}
+
+#[derive(PartialEq)]
+struct ViewConfigState {
+    /// Index of the view in the channel, `None` for a view added in the configuration screen
+    origin: Option<usize>,
+    name: String,
+    filter: FilterState,
+    /// Names of the referenced library filters
+    library: Vec<String>,
+    alert: bool,
+    alert_condition: AlertCondition,
+}
+
+impl From<&ChannelManager> for ChannelConfigState {
+    fn from(value: &ChannelManager) -> Self {
+        Self {
+            views: value
+                .view_names()
+                .into_iter()
+                .enumerate()
+                .map(|(view_idx, name)| ViewConfigState {
+                    origin: Some(view_idx),
+                    name,
+                    filter: value.get_filter_state(view_idx),
+                    library: value.library_refs(view_idx),
+                    alert: value.alert(view_idx),
+                    alert_condition: value.alert_condition(view_idx),
+                })
+                .collect(),
+            rules: value.rule_states(),
+            alert_sound: value.alert_sound(),
+            alert_settings: value.alert_settings(),
+            command: value.command_settings(),
+            log: value.log_settings(),
+            duplicates: value.duplicate_settings(),
+        }
+    }
+}
+
+/// Sound file picked in the channel configuration screen, deferred until the next frame
+#[derive(Clone, Copy)]
+enum SoundTarget {
+    Channel,
+    Rule(usize),
+}
+
+/// Runs an edited filter of the channel configuration screen against a sample message
+/// and the message buffer of the channel
+#[derive(Default)]
+struct TestBench {
+    view: usize,
+    login: String,
+    text: String,
+    picked: Option<TwitchMsg>,
+    only_matched: bool,
+    /// Filter state, library references and referenced library filters
+    /// the filter was built from
+    key: Option<(FilterState, Vec<String>, Vec<FilterState>)>,
+    filter: Option<Result<Filter, FilterError>>,
+    /// Newest buffered message with an explanation in `results`
+    last_id: Option<String>,
+    /// When the buffered messages were last explained
+    updated: Option<std::time::Instant>,
+    results: Vec<(TwitchMsg, Explanation)>,
+}
+
+/// A library filter edited in the configuration screen
+struct LibraryState {
+    /// Index of the filter in the applied library, `None` for a filter added since
+    origin: Option<usize>,
+    name: String,
+    filter: FilterState,
+}
+
+/// Filter state edited in a configuration screen
+#[derive(Clone, Copy)]
+enum FilterTarget {
+    Default,
+    Library(usize),
+    /// A view of the channel in the channel configuration screen
+    View(usize),
+}
+
+/// File dialog of a filter preset, deferred until the next frame
+enum PresetAction {
+    Import(FilterTarget),
+    Export(Box<FilterPreset>),
+}
+
+/// Views whose filter was changed in the channel configuration screen,
+/// asking whether their message lists should be rebuilt
+struct ReapplyPrompt {
+    channel: usize,
+    views: Vec<usize>,
+    log: bool,
+}
+
+/// Newest messages shown in the message history of a user
+const USER_HISTORY_LEN: usize = 500;
+
+/// Messages of a user from the message store of a channel
+struct UserHistory {
+    channel: String,
+    login: String,
+    display: String,
+    /// Days of messages, every stored message if `None`
+    days: Option<i64>,
+    msgs: Vec<TwitchMsg>,
+    error: Option<String>,
+}
+
+/// Messages per page of a log file in the logs screen
+const LOG_PAGE_LEN: usize = 500;
+
+/// Background read of a log file
+type LogReader = tokio::task::JoinHandle<std::io::Result<Vec<TwitchMsg>>>;
+
+/// Log files of a channel browsed in the logs screen
+#[derive(Default)]
+struct LogsState {
+    channel: usize,
+    files: Vec<PathBuf>,
+    /// File shown in the viewer with its messages
+    open: Option<(PathBuf, Vec<TwitchMsg>)>,
+    /// File being read, with the page to show once it is
+    loading: Option<(PathBuf, usize, LogReader)>,
+    page: usize,
+    /// Scroll the viewer to `show_msg_id` on the next frame
+    scroll: bool,
+    text: String,
+    regex: bool,
+    user: String,
+    from: String,
+    to: String,
+    search: Option<tokio::task::JoinHandle<Result<Vec<LogHit>, String>>>,
+    hits: Option<Vec<LogHit>>,
+    error: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
@@ -65,7 +229,14 @@ pub struct EguiApp {
     irc_client: Arc<tokio::sync::Mutex<IrcClient>>,
     channel_list: Vec<ChannelManager>,
     selected_channel: usize,
+    selected_view: usize,
     def_filter: FilterState,
+    /// Log settings of new channels
+    def_log: LogSettings,
+    store: StoreSettings,
+    filter_library: FilterLibrary,
+    /// Library filters edited in the configuration screen
+    library_states: Vec<LibraryState>,
     error_msg: Option<String>,
     font_size: f32,
     textures: HashMap<String, TextureHandle>,
@@ -74,7 +245,16 @@ pub struct EguiApp {
     show_sent_time: bool,
     readable_color_adjustment: bool,
     dark_theme: bool,
-    log_btn: Option<(usize, bool)>,
+    log_btn: Option<(usize, Option<usize>, LogFormat)>,
+    rule_log_btn: Option<(usize, LogFormat)>,
+    log_dir_btn: bool,
+    store_dir_btn: bool,
+    logs: LogsState,
+    sound_btn: Option<SoundTarget>,
+    test_bench: TestBench,
+    reapply_prompt: Option<ReapplyPrompt>,
+    preset_btn: Option<PresetAction>,
+    export_preset: Option<FilterPreset>,
     alert_volume: f32,
     alert_player: AlertPlayer,
     new_msg: String,
@@ -88,6 +268,12 @@ pub struct EguiApp {
     last_time_updated: DateTime<Utc>,
     paused_messages: Option<Vec<TwitchMsg>>,
     paused_filtered_messages: Option<Vec<TwitchMsg>>,
+    thread_root_id: Option<String>,
+    /// Stored messages of the reply thread, read once when the thread is shown
+    thread_stored: Option<(String, Vec<TwitchMsg>)>,
+    thread_msg: String,
+    input_thread_msg: bool,
+    user_history: Option<UserHistory>,
 }
 
 impl Default for EguiApp {
@@ -99,9 +285,14 @@ impl Default for EguiApp {
             new_channel_name: String::new(),
             channel_list: vec![],
             selected_channel: 0,
+            selected_view: 0,
             error_msg: None,
             font_size: super::DEFAULT_FONT_SIZE,
             def_filter: FilterState::default(),
+            def_log: LogSettings::default(),
+            store: StoreSettings::default(),
+            filter_library: FilterLibrary::default(),
+            library_states: vec![],
             textures: HashMap::new(),
             use_twitch_color: true,
             name_display: NameDisplay::Both,
@@ -109,6 +300,15 @@ impl Default for EguiApp {
             readable_color_adjustment: true,
             dark_theme: true,
             log_btn: None,
+            rule_log_btn: None,
+            log_dir_btn: false,
+            store_dir_btn: false,
+            logs: LogsState::default(),
+            sound_btn: None,
+            test_bench: TestBench::default(),
+            reapply_prompt: None,
+            preset_btn: None,
+            export_preset: None,
             alert_volume: 1.0,
             alert_player: AlertPlayer::default(),
             irc_client: Arc::new(tokio::sync::Mutex::new(
@@ -129,6 +329,11 @@ impl Default for EguiApp {
             last_time_updated: Utc::now(),
             paused_messages: None,
             paused_filtered_messages: None,
+            thread_root_id: None,
+            thread_stored: None,
+            thread_msg: String::new(),
+            input_thread_msg: false,
+            user_history: None,
         }
     }
 }
@@ -140,7 +345,10 @@ impl EguiApp {
             channel_name,
             self.max_msg_count,
             filter,
+            self.filter_library.clone(),
         );
+        client.set_log_settings(self.def_log.clone());
+        client.set_store(&self.store);
         client.connect();
         self.channel_list.push(client);
         self.new_channel_name = "".to_owned();
@@ -254,7 +462,20 @@ impl EguiApp {
                         self.alert_player.set_volume(self.alert_volume);
                     }
                     if ui.button("Test").clicked() {
-                        self.alert_player.play().unwrap();
+                        self.alert_player.play();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let status = self.alert_player.status();
+                    let color = match status {
+                        AudioStatus::Ready => Color32::GREEN,
+                        AudioStatus::Starting => ui.style().visuals.text_color(),
+                        _ => ui.style().visuals.warn_fg_color,
+                    };
+                    ui.label(RichText::new(status.to_string()).color(color));
+                    if matches!(status, AudioStatus::Unavailable(_)) && ui.button("Retry").clicked()
+                    {
+                        self.alert_player.retry();
                     }
                 });
                 ui.add_space(10.0);
@@ -264,7 +485,78 @@ impl EguiApp {
                 });
                 ui.add_space(10.0);
                 ui.label("Default filter configurations");
-                draw_filter_config(ui, &mut self.def_filter);
+                match draw_filter_config(ui, &mut self.def_filter) {
+                    Some(PresetRequest::Import) => {
+                        self.preset_btn = Some(PresetAction::Import(FilterTarget::Default))
+                    }
+                    Some(PresetRequest::Export) => {
+                        self.export_preset =
+                            Some(FilterPreset::new("Default filter", self.def_filter.clone()))
+                    }
+                    None => {}
+                }
+                ui.add_space(10.0);
+                ui.label("Default log settings");
+                ui.group(|ui| {
+                    self.log_dir_btn |= draw_log_settings(ui, &mut self.def_log);
+                });
+                ui.add_space(10.0);
+                ui.label("Message store");
+                ui.group(|ui| {
+                    self.store_dir_btn |= draw_store_settings(ui, &mut self.store);
+                });
+                ui.add_space(10.0);
+                ui.separator();
+                ui.label("Filter library");
+                ui.label(
+                    RichText::new(
+                        "Library filters are shared by the views that use them. \
+                         Their exclusions apply even if the view's own filter includes a message.",
+                    )
+                    .small(),
+                );
+                let mut remove_library = None;
+                for (library_idx, library) in self.library_states.iter_mut().enumerate() {
+                    let LibraryState {
+                        name,
+                        filter: filter_state,
+                        ..
+                    } = library;
+                    ui.push_id(("library", library_idx), |ui| {
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                let label = ui.label("Name: ");
+                                ui.text_edit_singleline(name).labelled_by(label.id);
+                                if ui.button("Remove").clicked() {
+                                    remove_library = Some(library_idx);
+                                }
+                            });
+                            match draw_filter_config(ui, filter_state) {
+                                Some(PresetRequest::Import) => {
+                                    self.preset_btn = Some(PresetAction::Import(
+                                        FilterTarget::Library(library_idx),
+                                    ))
+                                }
+                                Some(PresetRequest::Export) => {
+                                    self.export_preset =
+                                        Some(FilterPreset::new(&name, filter_state.clone()))
+                                }
+                                None => {}
+                            }
+                        });
+                    });
+                    ui.add_space(10.0);
+                }
+                if let Some(library_idx) = remove_library {
+                    self.library_states.remove(library_idx);
+                }
+                if ui.button("Add library filter").clicked() {
+                    self.library_states.push(LibraryState {
+                        origin: None,
+                        name: format!("Library filter {}", self.library_states.len() + 1),
+                        filter: FilterState::default(),
+                    });
+                }
                 ui.add_space(10.0);
                 ui.label(format!("Version: {}", git_version!()));
             });
@@ -273,23 +565,35 @@ impl EguiApp {
 
     fn draw_normal(&mut self, app_ui: &mut Ui, compact_mode: bool) {
         let main_area_available_size = app_ui.available_size();
+        let view_count = self.current_channel().map(|c| c.view_count()).unwrap_or(0);
+        if self.selected_view >= view_count {
+            self.selected_view = view_count.saturating_sub(1);
+            self.paused_filtered_messages = None;
+        }
+        let pane_count = if view_count > 0 { 2.0 } else { 1.0 };
         //eprintln!("1 {:?}", main_area_available_size);
         if compact_mode {
             app_ui.vertical(|ui| {
                 //ui.add_space(main_area_available_size.y / 2.0);
                 if !self.channel_list.is_empty() {
+                    if view_count > 0 {
+                        self.draw_chat(
+                            ui,
+                            vec2(main_area_available_size.x, main_area_available_size.y / 2.0),
+                            true,
+                        );
+                    }
                     self.draw_chat(
                         ui,
-                        vec2(main_area_available_size.x, main_area_available_size.y / 2.0),
-                        true,
-                    );
-                    self.draw_chat(
-                        ui,
-                        vec2(main_area_available_size.x, main_area_available_size.y / 2.0),
+                        vec2(
+                            main_area_available_size.x,
+                            main_area_available_size.y / pane_count,
+                        ),
                         false,
                     );
+                    let selected_view = self.selected_view;
                     if let Some(c) = self.current_channel_mut() {
-                        c.read();
+                        c.read(selected_view);
                     }
                 }
             });
@@ -301,17 +605,20 @@ impl EguiApp {
                 if !self.channel_list.is_empty() {
                     self.draw_chat(
                         main_area_ui,
-                        vec2(available_width / 2.0, main_area_available_size.y),
+                        vec2(available_width / pane_count, main_area_available_size.y),
                         false,
                     );
-                    main_area_ui.separator();
-                    self.draw_chat(
-                        main_area_ui,
-                        vec2(available_width / 2.0, main_area_available_size.y),
-                        true,
-                    );
+                    if view_count > 0 {
+                        main_area_ui.separator();
+                        self.draw_chat(
+                            main_area_ui,
+                            vec2(available_width / 2.0, main_area_available_size.y),
+                            true,
+                        );
+                    }
+                    let selected_view = self.selected_view;
                     if let Some(c) = self.current_channel_mut() {
-                        c.read();
+                        c.read(selected_view);
                     }
                 }
             });
@@ -319,7 +626,8 @@ impl EguiApp {
     }
 
     fn draw_channel_config(&mut self, app_ui: &mut Ui) {
-        if let AppState::ChannelConfig(idx, filter_state) = &mut self.state {
+        if let AppState::ChannelConfig(idx, config) = &mut self.state {
+            let views = &mut config.views;
             let available_width = app_ui.available_width();
             ScrollArea::vertical().show(app_ui, |ui| {
                 ui.set_width(available_width);
@@ -327,20 +635,516 @@ impl EguiApp {
                     ui.label(RichText::new(e).color(Color32::RED));
                 }
                 ui.add_space(10.0);
-                let mut alert = self.channel_list[*idx].alert();
-                if ui
-                    .checkbox(&mut alert, "New filtered message alert")
-                    .changed()
-                {
-                    if alert {
-                        self.channel_list[*idx].set_alert(Some(self.alert_player.clone()));
-                    } else {
-                        self.channel_list[*idx].set_alert(None);
+                let duplicates = &mut config.duplicates;
+                ui.checkbox(&mut duplicates.enabled, "Collapse repeated messages");
+                ui.add_enabled_ui(duplicates.enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Repeated within");
+                        ui.add(
+                            DragValue::new(&mut duplicates.window_secs)
+                                .clamp_range(1..=3600)
+                                .suffix(" s"),
+                        );
+                    });
+                    ui.checkbox(
+                        &mut duplicates.count_duplicates,
+                        "Repeated messages count toward filters and alerts",
+                    );
+                });
+                ui.add_space(10.0);
+                ui.label("Alert sound of this channel");
+                if draw_sound_file(
+                    ui,
+                    &self.alert_player,
+                    &mut config.alert_sound,
+                    "Default sound",
+                    &mut self.error_msg,
+                ) {
+                    self.sound_btn = Some(SoundTarget::Channel);
+                }
+                draw_alert_settings(ui, &mut config.alert_settings);
+                ui.add_space(10.0);
+                ui.label("Log files");
+                ui.group(|ui| {
+                    self.log_dir_btn |= draw_log_settings(ui, &mut config.log);
+                });
+                ui.add_space(10.0);
+                ui.group(|ui| {
+                    draw_command_settings(ui, &mut config.command);
+                    let status = self.channel_list[*idx].command_status();
+                    ui.label(format!(
+                        "Runs: {}, running: {}, skipped: {}",
+                        status.runs, status.running, status.skipped
+                    ));
+                    if let Some(result) = &status.last_result {
+                        ui.label(format!("Last command: {}", result));
+                    }
+                    if !status.stderr.is_empty() {
+                        ui.label("Stderr:");
+                        ScrollArea::vertical()
+                            .id_source("command_stderr")
+                            .max_height(100.0)
+                            .show(ui, |ui| {
+                                ui.label(RichText::new(&status.stderr).monospace());
+                            });
                     }
+                });
+                ui.add_space(10.0);
+                let mut remove_view = None;
+                let library_names: Vec<&String> =
+                    self.library_states.iter().map(|l| &l.name).collect();
+                for (view_idx, view) in views.iter_mut().enumerate() {
+                    let ViewConfigState {
+                        origin,
+                        name,
+                        filter: filter_state,
+                        library,
+                        alert,
+                        alert_condition,
+                    } = view;
+                    ui.push_id(view_idx, |ui| {
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                let label = ui.label("View name: ");
+                                ui.text_edit_singleline(name).labelled_by(label.id);
+                                if ui.button("Remove view").clicked() {
+                                    remove_view = Some(view_idx);
+                                }
+                            });
+                            ui.add_space(10.0);
+                            ui.checkbox(alert, "New filtered message alert");
+                            draw_alert_condition(ui, alert_condition);
+                            if let Some(channel_view) = origin {
+                                ui.label(self.channel_list[*idx].alert_summary(*channel_view));
+                            }
+                            ui.add_space(10.0);
+                            if !library_names.is_empty() || !library.is_empty() {
+                                ui.horizontal_wrapped(|ui| {
+                                    ui.label("Library filters: ");
+                                    for library_name in library_names.iter() {
+                                        let mut used = library.contains(library_name);
+                                        if ui.checkbox(&mut used, library_name.as_str()).changed() {
+                                            if used {
+                                                library.push(library_name.to_string());
+                                            } else {
+                                                library.retain(|n| n != *library_name);
+                                            }
+                                        }
+                                    }
+                                    for missing in
+                                        library.iter().filter(|n| !library_names.contains(n))
+                                    {
+                                        ui.label(
+                                            RichText::new(format!("{} (missing)", missing))
+                                                .color(Color32::RED),
+                                        );
+                                    }
+                                });
+                                ui.add_space(10.0);
+                            }
+                            match draw_filter_config(ui, filter_state) {
+                                Some(PresetRequest::Import) => {
+                                    self.preset_btn =
+                                        Some(PresetAction::Import(FilterTarget::View(view_idx)))
+                                }
+                                Some(PresetRequest::Export) => {
+                                    self.export_preset =
+                                        Some(FilterPreset::new(&name, filter_state.clone()))
+                                }
+                                None => {}
+                            }
+                        });
+                    });
+                    ui.add_space(10.0);
                 }
+                if let Some(view_idx) = remove_view {
+                    views.remove(view_idx);
+                }
+                if ui.button("Add view").clicked() {
+                    views.push(ViewConfigState {
+                        origin: None,
+                        name: format!("View {}", views.len() + 1),
+                        filter: FilterState::default(),
+                        library: vec![],
+                        alert: false,
+                        alert_condition: AlertCondition::default(),
+                    });
+                }
+                ui.add_space(10.0);
+                ui.separator();
+                draw_test_bench(
+                    ui,
+                    &mut self.test_bench,
+                    &self.channel_list[*idx],
+                    views,
+                    &self.filter_library.read().unwrap(),
+                );
                 ui.add_space(10.0);
-                draw_filter_config(ui, filter_state);
+                ui.separator();
+                ui.label("Rules");
+                let log_errors = self.channel_list[*idx].rule_log_errors();
+                let log_template = PathBuf::from(&config.log.template);
+                let mut remove_rule = None;
+                for (rule_idx, rule) in config.rules.iter_mut().enumerate() {
+                    ui.push_id(("rule", rule_idx), |ui| {
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                let label = ui.label("Rule name: ");
+                                ui.text_edit_singleline(&mut rule.name)
+                                    .labelled_by(label.id);
+                                if ui.button("Remove rule").clicked() {
+                                    remove_rule = Some(rule_idx);
+                                }
+                            });
+                            let label = ui.label("Condition: ");
+                            ui.add(
+                                TextEdit::singleline(&mut rule.condition)
+                                    .desired_width(500.0)
+                                    .hint_text("login = \"nightbot\""),
+                            )
+                            .labelled_by(label.id);
+                            if let Err(e) = Expression::parse(&rule.condition) {
+                                ui.label(RichText::new(e.to_string()).color(Color32::RED));
+                            }
+                            ui.horizontal(|ui| {
+                                let mut highlight = rule.highlight.is_some();
+                                if ui.checkbox(&mut highlight, "Highlight").changed() {
+                                    rule.highlight = highlight.then_some([128, 64, 0]);
+                                }
+                                if let Some(color) = &mut rule.highlight {
+                                    egui::color_picker::color_edit_button_srgb(ui, color);
+                                }
+                            });
+                            ui.checkbox(&mut rule.sound, "Play alert sound");
+                            if rule.sound
+                                && draw_sound_file(
+                                    ui,
+                                    &self.alert_player,
+                                    &mut rule.sound_file,
+                                    "Channel sound",
+                                    &mut self.error_msg,
+                                )
+                            {
+                                self.sound_btn = Some(SoundTarget::Rule(rule_idx));
+                            }
+                            if rule.sound {
+                                let mut own = rule.alert_settings.is_some();
+                                if ui
+                                    .checkbox(&mut own, "Own cooldown and queue policy")
+                                    .changed()
+                                {
+                                    rule.alert_settings = own.then_some(config.alert_settings);
+                                }
+                                if let Some(settings) = &mut rule.alert_settings {
+                                    draw_alert_settings(ui, settings);
+                                }
+                            }
+                            ui.horizontal(|ui| {
+                                let mut urgent = rule.urgent.is_some();
+                                if ui
+                                    .checkbox(&mut urgent, "Urgent")
+                                    .on_hover_text(
+                                        "Flash the channel until the message is clicked \
+                                         or Ctrl+Shift+A is pressed",
+                                    )
+                                    .changed()
+                                {
+                                    rule.urgent = urgent.then_some(30);
+                                }
+                                if let Some(secs) = &mut rule.urgent {
+                                    ui.label("repeat the alert every");
+                                    ui.add(DragValue::new(secs).clamp_range(1..=3600).suffix(" s"));
+                                    ui.label("until acknowledged");
+                                }
+                            });
+                            ui.checkbox(&mut rule.hide, "Hide from all messages");
+                            ui.horizontal(|ui| match rule.log.clone() {
+                                None => {
+                                    ui.menu_button("Log", |ui| {
+                                        for format in LogFormat::ALL {
+                                            if ui
+                                                .button(format.name())
+                                                .on_hover_text(format!(
+                                                    "{}",
+                                                    log_template.display()
+                                                ))
+                                                .clicked()
+                                            {
+                                                rule.log = Some(log_template.clone());
+                                                rule.log_format = format;
+                                                ui.close_menu();
+                                            }
+                                        }
+                                        ui.menu_button("Custom file", |ui| {
+                                            for format in LogFormat::ALL {
+                                                if ui.button(format.name()).clicked() {
+                                                    self.rule_log_btn = Some((rule_idx, format));
+                                                    ui.close_menu();
+                                                }
+                                            }
+                                        });
+                                    });
+                                }
+                                Some(p) => {
+                                    let error = log_errors.get(rule_idx).cloned().flatten();
+                                    let color = if error.is_some() {
+                                        Color32::RED
+                                    } else {
+                                        Color32::GREEN
+                                    };
+                                    if ui
+                                        .button(RichText::new("Stop").color(color))
+                                        .on_hover_text(
+                                            error.unwrap_or_else(|| format!("{}", p.display())),
+                                        )
+                                        .clicked()
+                                    {
+                                        rule.log = None;
+                                    }
+                                    ui.label(format!(
+                                        "{} ({})",
+                                        p.display(),
+                                        rule.log_format.name()
+                                    ));
+                                }
+                            });
+                        });
+                    });
+                    ui.add_space(10.0);
+                }
+                if let Some(rule_idx) = remove_rule {
+                    config.rules.remove(rule_idx);
+                }
+                if ui.button("Add rule").clicked() {
+                    config.rules.push(RuleState {
+                        name: format!("Rule {}", config.rules.len() + 1),
+                        ..Default::default()
+                    });
+                }
+            });
+        }
+    }
+
+    /// Returns the views whose filter changed
+    fn apply_channel_config(&mut self) -> Result<Vec<usize>, String> {
+        let mut changed = vec![];
+        if let AppState::ChannelConfig(idx, config) = &self.state {
+            for view in config.views.iter() {
+                Filter::try_from(&view.filter).map_err(|e| format!("{}: {}", view.name, e))?;
+            }
+            let rule_sounds = config
+                .rules
+                .iter()
+                .filter(|r| r.sound)
+                .filter_map(|r| r.sound_file.as_ref());
+            for path in config.alert_sound.iter().chain(rule_sounds) {
+                self.alert_player.load(path).map_err(|e| e.to_string())?;
+            }
+            if config.command.enabled {
+                parse_template(&config.command.template)
+                    .map_err(|e| format!("External command: {}", e))?;
+            }
+            let channel = &mut self.channel_list[*idx];
+            channel
+                .set_rules(&config.rules, &self.alert_player)
+                .map_err(|e| format!("Rules: {}", e))?;
+            channel.set_alert_sound(config.alert_sound.clone());
+            channel.set_alert_settings(config.alert_settings);
+            channel.set_log_settings(config.log.clone());
+            channel.set_duplicate_settings(config.duplicates);
+            channel
+                .set_command_settings(config.command.clone())
+                .map_err(|e| format!("External command: {}", e))?;
+            // Removed views go first, the views added in the screen come after the kept ones
+            for view_idx in (0..channel.view_count()).rev() {
+                if !config.views.iter().any(|v| v.origin == Some(view_idx)) {
+                    channel.remove_view(view_idx);
+                }
+            }
+            for view in config.views.iter().filter(|v| v.origin.is_none()) {
+                channel.add_view(&view.name, Filter::default());
+            }
+            for (view_idx, view) in config.views.iter().enumerate() {
+                if channel.alert(view_idx) != view.alert {
+                    channel.set_alert(view_idx, view.alert.then(|| self.alert_player.clone()));
+                }
+                if channel.alert_condition(view_idx) != view.alert_condition {
+                    channel.set_alert_condition(view_idx, view.alert_condition);
+                }
+                channel.rename_view(view_idx, &view.name);
+                let old_state = channel.get_filter_state(view_idx);
+                let old_library = channel.library_refs(view_idx);
+                channel
+                    .set_filter(view_idx, &view.filter)
+                    .map_err(|e| format!("{}: {}", view.name, e))?;
+                channel.set_library_refs(view_idx, view.library.clone());
+                if channel.get_filter_state(view_idx) != old_state || view.library != old_library {
+                    changed.push(view_idx);
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Build the library filters and share them with every channel.
+    /// References to a renamed library filter are renamed, references to a removed one dropped.
+    fn apply_library(&mut self) -> Result<(), String> {
+        let mut library = vec![];
+        for LibraryState { name, filter, .. } in self.library_states.iter() {
+            if name.trim().is_empty() {
+                return Err("Library filters need a name".to_owned());
+            }
+            if library.iter().any(|(n, _)| n == name) {
+                return Err(format!("Library filter name {} is used twice", name));
+            }
+            let filter = Filter::try_from(filter).map_err(|e| format!("{}: {}", name, e))?;
+            library.push((name.clone(), filter));
+        }
+        // The library is not locked while the channels are, `handle_msg` locks them the other way
+        let renames: HashMap<String, Option<String>> = self
+            .filter_library
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(old_idx, (old, _))| {
+                let new = self
+                    .library_states
+                    .iter()
+                    .find(|l| l.origin == Some(old_idx))
+                    .map(|l| l.name.clone());
+                (old.clone(), new)
+            })
+            .filter(|(old, new)| new.as_ref() != Some(old))
+            .collect();
+        if !renames.is_empty() {
+            for channel in self.channel_list.iter_mut() {
+                channel.update_library_refs(&renames);
+            }
+        }
+        *self.filter_library.write().unwrap() = library;
+        for (idx, library) in self.library_states.iter_mut().enumerate() {
+            library.origin = Some(idx);
+        }
+        Ok(())
+    }
+
+    /// Invalid patterns are imported as they are and listed in the error message
+    fn import_preset(&mut self, path: &std::path::Path, target: FilterTarget) {
+        let preset = match FilterPreset::load(path) {
+            Ok(p) => p,
+            Err(e) => {
+                self.error_msg = Some(e.to_string());
+                return;
+            }
+        };
+        let problems = preset.problems();
+        self.error_msg = if problems.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "Imported \"{}\" with errors:\n{}",
+                preset.name,
+                problems.join("\n")
+            ))
+        };
+        match (target, &mut self.state) {
+            (FilterTarget::Default, _) => self.def_filter = preset.filter,
+            (FilterTarget::Library(library_idx), _) => {
+                if let Some(library) = self.library_states.get_mut(library_idx) {
+                    library.filter = preset.filter;
+                }
+            }
+            (FilterTarget::View(view_idx), AppState::ChannelConfig(_, config)) => {
+                if let Some(view) = config.views.get_mut(view_idx) {
+                    view.filter = preset.filter;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn draw_export_preset(&mut self, ctx: &Context) {
+        let preset = match &mut self.export_preset {
+            Some(p) => p,
+            None => return,
+        };
+        let mut close = false;
+        egui::Window::new("Export filter")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                let label = ui.label("Name: ");
+                ui.text_edit_singleline(&mut preset.name)
+                    .labelled_by(label.id);
+                let label = ui.label("Description: ");
+                ui.text_edit_multiline(&mut preset.description)
+                    .labelled_by(label.id);
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        self.preset_btn = Some(PresetAction::Export(Box::new(preset.clone())));
+                        close = true;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        close = true;
+                    }
+                });
+            });
+        if close {
+            self.export_preset = None;
+        }
+    }
+
+    fn draw_reapply_prompt(&mut self, ctx: &Context) {
+        let prompt = match &mut self.reapply_prompt {
+            Some(p) => p,
+            None => return,
+        };
+        let channel = match self.channel_list.get_mut(prompt.channel) {
+            Some(c) => c,
+            None => {
+                self.reapply_prompt = None;
+                return;
+            }
+        };
+        let names = channel.view_names();
+        let mut close = false;
+        egui::Window::new("Filter changed")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "The filter of {} changed. Re-apply it to the messages already received?",
+                    prompt
+                        .views
+                        .iter()
+                        .filter_map(|v| names.get(*v).cloned())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+                if prompt
+                    .views
+                    .iter()
+                    .any(|v| matches!(channel.log_status(Some(*v)), Some(Ok(_))))
+                {
+                    ui.checkbox(&mut prompt.log, "Write re-filtered messages to the log");
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Re-apply").clicked() {
+                        for view in prompt.views.iter() {
+                            channel.reapply_filter(*view, prompt.log);
+                        }
+                        close = true;
+                    }
+                    if ui.button("Keep current messages").clicked() {
+                        close = true;
+                    }
+                });
             });
+        if close {
+            self.reapply_prompt = None;
         }
     }
 
@@ -352,7 +1156,7 @@ impl EguiApp {
         &mut self,
         save_state: &AppSaveState,
         ctx: &egui::Context,
-    ) -> Result<(), regex::Error> {
+    ) -> Result<(), FilterError> {
         self.font_size = save_state.font_size;
         set_font_size(ctx, self.font_size);
         self.username = save_state.username.clone();
@@ -361,6 +1165,21 @@ impl EguiApp {
             self.error_msg = Some("Login to chat failed, using anonymous".to_string())
         }
         self.max_msg_count = save_state.max_msg_count;
+        self.library_states = save_state
+            .filter_library
+            .iter()
+            .enumerate()
+            .map(|(idx, l)| LibraryState {
+                origin: Some(idx),
+                name: l.name.clone(),
+                filter: l.filter.clone(),
+            })
+            .collect();
+        *self.filter_library.write().unwrap() = save_state
+            .filter_library
+            .iter()
+            .map(|l| Ok((l.name.clone(), (&l.filter).try_into()?)))
+            .collect::<Result<_, FilterError>>()?;
         self.channel_list = save_state
             .channels
             .iter()
@@ -370,23 +1189,45 @@ impl EguiApp {
                     save.name.clone(),
                     self.max_msg_count,
                     (&save.filter).try_into()?,
+                    self.filter_library.clone(),
                 );
+                client.set_store(&save_state.store);
                 if save.enabled {
                     client.connect();
                 }
                 if let Some(log_path) = &save.log_status {
-                    client.set_log(Some(log_path.clone()));
+                    client.set_log(None, Some(log_path.clone()), save.log_format);
+                }
+                if let Some(views) = &save.views {
+                    client.remove_view(0);
+                    for view in views.iter() {
+                        client.add_view(&view.name, (&view.filter).try_into()?);
+                    }
                 }
-                if let Some(log_path) = &save.filtered_log_status {
-                    client.set_filtered_log(Some(log_path.clone()));
+                client.set_rules(&save.rules, &self.alert_player)?;
+                client.set_duplicate_settings(save.duplicates);
+                client.set_alert_sound(save.alert_sound.clone());
+                client.set_alert_settings(save.alert_settings);
+                client.set_log_settings(save.log_settings.clone());
+                if let Err(e) = client.set_command_settings(save.command.clone()) {
+                    log::error!("External command of {}: {}", save.name, e);
                 }
-                if save.alert {
-                    client.set_alert(Some(self.alert_player.clone()));
+                for (view_idx, view) in save.view_states().iter().enumerate() {
+                    if let Some(log_path) = &view.log_status {
+                        client.set_log(Some(view_idx), Some(log_path.clone()), view.log_format);
+                    }
+                    if view.alert {
+                        client.set_alert(view_idx, Some(self.alert_player.clone()));
+                    }
+                    client.set_alert_condition(view_idx, view.alert_condition);
+                    client.set_library_refs(view_idx, view.library.clone());
                 }
                 Ok(client)
             })
-            .collect::<Result<Vec<ChannelManager>, regex::Error>>()?;
+            .collect::<Result<Vec<ChannelManager>, FilterError>>()?;
         self.def_filter = save_state.def_filter.clone();
+        self.def_log = save_state.def_log.clone();
+        self.store = save_state.store.clone();
         self.show_sent_time = save_state.show_sent_time;
         self.use_twitch_color = save_state.use_twitch_color;
         self.name_display = save_state.name_display;
@@ -399,6 +1240,23 @@ impl EguiApp {
         }
         self.alert_volume = save_state.alert_volume;
         self.alert_player.set_volume(save_state.alert_volume);
+        let sound_errors: Vec<String> = save_state
+            .channels
+            .iter()
+            .flat_map(|c| {
+                let rule_sounds = c
+                    .rules
+                    .iter()
+                    .filter(|r| r.sound)
+                    .filter_map(|r| r.sound_file.as_ref());
+                c.alert_sound.iter().chain(rule_sounds)
+            })
+            .filter_map(|p| self.alert_player.load(p).err())
+            .map(|e| e.to_string())
+            .collect();
+        if !sound_errors.is_empty() {
+            self.error_msg = Some(sound_errors.join("\n"));
+        }
         self.selected_channel = save_state.selected_channel;
         self.selected_font = save_state.selected_font.clone();
         set_font(ctx, Some(&self.selected_font));
@@ -410,6 +1268,11 @@ impl EguiApp {
     }
 
     fn draw_chat(&mut self, ui: &mut Ui, size: Vec2, filtered: bool) {
+        let view = if filtered {
+            Some(self.selected_view)
+        } else {
+            None
+        };
         let mut end_pressed = false;
         let mut home_pressed = false;
 
@@ -465,7 +1328,7 @@ impl EguiApp {
                                     }
                                 });
                             }
-                            if !self.input_new_channel {
+                            if !self.input_new_channel && !self.input_thread_msg {
                                 response.request_focus();
                             }
                             if let Some(reply_msg) = &self.reply_msg {
@@ -497,31 +1360,99 @@ impl EguiApp {
                         }
                         ui.with_layout(Layout::top_down(Align::Center), |ui| {
                             ui.horizontal(|ui| {
-                                ui.label(if filtered {
-                                    "Filtered message"
+                                if filtered {
+                                    let channel = self.current_channel().unwrap();
+                                    let tabs: Vec<(String, bool)> = channel
+                                        .view_names()
+                                        .into_iter()
+                                        .enumerate()
+                                        .map(|(view_idx, name)| {
+                                            (name, channel.view_has_unread_msg(view_idx))
+                                        })
+                                        .collect();
+                                    for (view_idx, (name, unread)) in tabs.into_iter().enumerate() {
+                                        let mut text = RichText::new(name);
+                                        if unread {
+                                            text = text.color(ui.style().visuals.warn_fg_color);
+                                        }
+                                        if ui
+                                            .selectable_label(self.selected_view == view_idx, text)
+                                            .on_hover_text(
+                                                self.current_channel()
+                                                    .unwrap()
+                                                    .alert_summary(view_idx),
+                                            )
+                                            .clicked()
+                                            && self.selected_view != view_idx
+                                        {
+                                            self.selected_view = view_idx;
+                                            self.paused_filtered_messages = None;
+                                        }
+                                    }
                                 } else {
-                                    "All message"
-                                });
+                                    ui.label("All message");
+                                }
+                                if self.current_channel().unwrap().has_urgent_alert()
+                                    && ui
+                                        .button(RichText::new("Acknowledge").color(Color32::RED))
+                                        .on_hover_text("Ctrl+Shift+A")
+                                        .clicked()
+                                {
+                                    self.current_channel_mut().unwrap().acknowledge_all();
+                                }
                                 ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
-                                    let log_status = if filtered {
-                                        self.current_channel().unwrap().filtered_log_status()
-                                    } else {
-                                        self.current_channel().unwrap().log_status()
-                                    };
+                                    let log_status =
+                                        self.current_channel().unwrap().log_status(view);
                                     match log_status {
                                         None => {
-                                            let response = ui.button("Log");
                                             if self.current_channel().unwrap().state()
                                                 != ChannelConnectionState::Joined
                                             {
-                                                response.on_hover_text(
+                                                ui.button("Log").on_hover_text(
                                                     "Please enable the channel first.",
                                                 );
-                                            } else if response.clicked() {
-                                                self.log_btn =
-                                                    Some((self.selected_channel, filtered));
-                                            }
-                                        }
+                                            } else {
+                                                let template = PathBuf::from(
+                                                    self.current_channel()
+                                                        .unwrap()
+                                                        .log_settings()
+                                                        .template,
+                                                );
+                                                ui.menu_button("Log", |ui| {
+                                                    for format in LogFormat::ALL {
+                                                        if ui
+                                                            .button(format.name())
+                                                            .on_hover_text(format!(
+                                                                "{}",
+                                                                template.display()
+                                                            ))
+                                                            .clicked()
+                                                        {
+                                                            self.current_channel_mut()
+                                                                .unwrap()
+                                                                .set_log(
+                                                                    view,
+                                                                    Some(template.clone()),
+                                                                    format,
+                                                                );
+                                                            ui.close_menu();
+                                                        }
+                                                    }
+                                                    ui.menu_button("Custom file", |ui| {
+                                                        for format in LogFormat::ALL {
+                                                            if ui.button(format.name()).clicked() {
+                                                                self.log_btn = Some((
+                                                                    self.selected_channel,
+                                                                    view,
+                                                                    format,
+                                                                ));
+                                                                ui.close_menu();
+                                                            }
+                                                        }
+                                                    });
+                                                });
+                                            }
+                                        }
                                         Some(r) => match r {
                                             Err(e) => {
                                                 if ui
@@ -531,41 +1462,48 @@ impl EguiApp {
                                                     .on_hover_text(e)
                                                     .clicked()
                                                 {
-                                                    if filtered {
-                                                        self.current_channel_mut()
-                                                            .unwrap()
-                                                            .set_filtered_log(None);
-                                                    } else {
-                                                        self.current_channel_mut()
-                                                            .unwrap()
-                                                            .set_log(None);
-                                                    }
+                                                    self.current_channel_mut().unwrap().set_log(
+                                                        view,
+                                                        None,
+                                                        LogFormat::default(),
+                                                    );
                                                 }
                                             }
-                                            Ok(p) => {
+                                            Ok((p, dropped)) => {
+                                                let mut hover = format!(
+                                                    "{} ({})",
+                                                    p.display(),
+                                                    self.current_channel()
+                                                        .unwrap()
+                                                        .log_format(view)
+                                                        .name()
+                                                );
+                                                let color = if dropped > 0 {
+                                                    hover += &format!(
+                                                        "\n{} messages dropped, the log could not keep up",
+                                                        dropped
+                                                    );
+                                                    Color32::YELLOW
+                                                } else {
+                                                    Color32::GREEN
+                                                };
                                                 if ui
-                                                    .button(
-                                                        RichText::new("Stop").color(Color32::GREEN),
-                                                    )
-                                                    .on_hover_text(format!("{}", p.display()))
+                                                    .button(RichText::new("Stop").color(color))
+                                                    .on_hover_text(hover)
                                                     .clicked()
                                                 {
-                                                    if filtered {
-                                                        self.current_channel_mut()
-                                                            .unwrap()
-                                                            .set_filtered_log(None);
-                                                    } else {
-                                                        self.current_channel_mut()
-                                                            .unwrap()
-                                                            .set_log(None);
-                                                    }
+                                                    self.current_channel_mut().unwrap().set_log(
+                                                        view,
+                                                        None,
+                                                        LogFormat::default(),
+                                                    );
                                                 }
                                             }
                                         },
                                     }
 
                                     if ui.button("Clear").clicked() {
-                                        self.current_channel_mut().unwrap().clear_msg(filtered);
+                                        self.current_channel_mut().unwrap().clear_msg(view);
                                     }
 
                                     let is_paused = if filtered {
@@ -582,9 +1520,7 @@ impl EguiApp {
                                                 self.paused_filtered_messages = None;
                                             } else {
                                                 self.paused_filtered_messages = Some(
-                                                    self.current_channel()
-                                                        .unwrap()
-                                                        .get_msg(filtered),
+                                                    self.current_channel().unwrap().get_msg(view),
                                                 );
                                             }
                                         } else {
@@ -592,9 +1528,7 @@ impl EguiApp {
                                                 self.paused_messages = None;
                                             } else {
                                                 self.paused_messages = Some(
-                                                    self.current_channel()
-                                                        .unwrap()
-                                                        .get_msg(filtered),
+                                                    self.current_channel().unwrap().get_msg(view),
                                                 );
                                             }
                                         }
@@ -602,7 +1536,7 @@ impl EguiApp {
 
                                     ui.label(format!(
                                         "{} / {}",
-                                        self.current_channel_mut().unwrap().get_msg_count(filtered),
+                                        self.current_channel_mut().unwrap().get_msg_count(view),
                                         self.max_msg_count
                                     ));
                                 });
@@ -611,7 +1545,7 @@ impl EguiApp {
 
                             let mut highlight_message_found = self.show_msg_id.is_none();
                             ScrollArea::vertical()
-                                .id_source(filtered)
+                                .id_source(view)
                                 //.auto_shrink([false, false])
                                 .enable_scrolling(self.show_msg_id.is_none() || filtered)
                                 //.auto_shrink([false; 2])
@@ -626,13 +1560,13 @@ impl EguiApp {
                                             {
                                                 p.clone()
                                             } else {
-                                                self.current_channel().unwrap().get_msg(filtered)
+                                                self.current_channel().unwrap().get_msg(view)
                                             }
                                         } else {
                                             if let Some(p) = self.paused_messages.as_ref() {
                                                 p.clone()
                                             } else {
-                                                self.current_channel().unwrap().get_msg(filtered)
+                                                self.current_channel().unwrap().get_msg(view)
                                             }
                                         };
                                         for msg in messages {
@@ -664,7 +1598,7 @@ impl EguiApp {
 
                     //area.show_rows(ui, row_height, num_rows, |ui, row_range| {
                     //    let client = &self.current_channel();
-                    //    for msg in client.get_n_msg(row_range, filtered) {
+                    //    for msg in client.get_n_msg(row_range, view) {
                     //        self.draw_msg(ui, &msg, font_size);
                     //    }
                     //});
@@ -683,6 +1617,8 @@ impl EguiApp {
         };
         let bg_color = if highlight {
             Color32::BROWN
+        } else if let Some([r, g, b]) = msg.highlight() {
+            Color32::from_rgb(r, g, b)
         } else {
             ui.visuals().panel_fill
         };
@@ -735,8 +1671,8 @@ impl EguiApp {
             let time_str;
             let message;
             let mut items = vec![];
-            if self.show_sent_time {
-                let local_time = msg.sent_time().unwrap().with_timezone(&chrono::Local);
+            if let Some(sent_time) = msg.sent_time().filter(|_| self.show_sent_time) {
+                let local_time = sent_time.with_timezone(&chrono::Local);
                 //ui.label(local_time.format("%H:%M:%S").to_string());
                 time_str = local_time.format("%H:%M:%S ").to_string();
                 items.push(DisplayItem::Text(
@@ -841,8 +1777,40 @@ impl EguiApp {
                 Some(text_color),
                 Some(bg_color),
             ));
+            if msg.is_urgent() {
+                items.push(DisplayItem::Text(
+                    " (!)",
+                    Some(Color32::RED),
+                    Some(bg_color),
+                ));
+            }
+            let repeat_str = format!(" ×{}", msg.repeat_count());
+            if msg.repeat_count() > 1 {
+                items.push(DisplayItem::Text(
+                    &repeat_str,
+                    Some(ui.style().visuals.warn_fg_color),
+                    Some(bg_color),
+                ));
+            }
 
-            let response: Response = draw_text_and_image(ui, items, ui.available_width(), 5.0);
+            let mut response: Response = draw_text_and_image(ui, items, ui.available_width(), 5.0);
+            if msg.repeat_count() > 1 {
+                response = response
+                    .on_hover_text(format!("Also sent by: {}", msg.repeat_senders().join(", ")));
+            }
+            if msg.is_urgent() {
+                response = response.on_hover_text("Urgent, click to acknowledge");
+                if response.clicked() {
+                    let channel_name = msg.channel().trim_start_matches('#');
+                    if let Some(channel) = self
+                        .channel_list
+                        .iter_mut()
+                        .find(|c| c.channel_name() == channel_name)
+                    {
+                        channel.acknowledge(msg.id());
+                    }
+                }
+            }
             if response.is_pointer_button_down_on() {
                 self.context_msg = Some(msg.clone());
             }
@@ -901,27 +1869,75 @@ impl EguiApp {
                 if drew_badge {
                     ui.separator();
                 }
+                let channel_name = msg.channel().trim_start_matches('#');
+                let stored = self
+                    .channel_list
+                    .iter()
+                    .find(|c| c.channel_name() == channel_name)
+                    .and_then(|c| c.user_msg_count(msg.sender_login()));
+                if let Some(count) = stored {
+                    ui.label(format!("{} stored message(s)", count));
+                    if ui.button("Message history").clicked() {
+                        self.user_history = Some(UserHistory {
+                            channel: channel_name.to_owned(),
+                            login: msg.sender_login().to_owned(),
+                            display: msg.sender_display().to_owned(),
+                            days: Some(7),
+                            msgs: vec![],
+                            error: None,
+                        });
+                        self.load_user_history();
+                        self.thread_root_id = None;
+                        ui.close_menu();
+                    }
+                    ui.separator();
+                }
                 if ui.button("Reply to this").clicked() {
                     self.reply_msg = Some(msg.clone());
                     ui.close_menu();
                 }
-                if ui.button("Add user to filter").clicked() {
-                    match Regex::new(&regex::escape(msg.sender_login())) {
-                        Ok(r) => self
-                            .current_channel_mut()
-                            .unwrap()
-                            .mut_filter(|f| f.add_author_pat(r)),
-                        Err(e) => self.error_msg = Some(format!("{}", e)),
+                if ui.button("Show reply thread").clicked() {
+                    self.user_history = None;
+                    self.thread_root_id = Some(
+                        msg.reply_thread_root_id()
+                            .cloned()
+                            .unwrap_or_else(|| msg.id().to_owned()),
+                    );
+                    ui.close_menu();
+                }
+                let selected_view = self.selected_view;
+                let has_view = self.current_channel().unwrap().view_count() > selected_view;
+                let user_pattern = match msg.user_id() {
+                    Some(user_id) => PatternState {
+                        text: user_id.to_owned(),
+                        mode: MatchMode::Word,
+                        case_sensitive: false,
+                        target: AuthorField::UserId,
+                    },
+                    None => PatternState {
+                        text: msg.sender_login().to_owned(),
+                        mode: MatchMode::Word,
+                        case_sensitive: false,
+                        target: AuthorField::Login,
+                    },
+                };
+                if has_view && ui.button("Add user to filter").clicked() {
+                    if let Err(e) = self
+                        .current_channel_mut()
+                        .unwrap()
+                        .mut_filter(selected_view, |f| f.add_author_pat(&user_pattern))
+                    {
+                        self.error_msg = Some(format!("{}", e));
                     }
                     ui.close_menu();
                 }
-                if ui.button("Add user to exclusive filter").clicked() {
-                    match Regex::new(&regex::escape(msg.sender_login())) {
-                        Ok(r) => self
-                            .current_channel_mut()
-                            .unwrap()
-                            .mut_filter(|f| f.add_exc_author_pat(r)),
-                        Err(e) => self.error_msg = Some(format!("{}", e)),
+                if has_view && ui.button("Add user to exclusive filter").clicked() {
+                    if let Err(e) = self
+                        .current_channel_mut()
+                        .unwrap()
+                        .mut_filter(selected_view, |f| f.add_exc_author_pat(&user_pattern))
+                    {
+                        self.error_msg = Some(format!("{}", e));
                     }
                     ui.close_menu();
                 }
@@ -946,6 +1962,411 @@ impl EguiApp {
         main_space
     }
 
+    fn draw_thread(&mut self, ui: &mut Ui) {
+        let root_id = self.thread_root_id.clone().unwrap();
+        if self.thread_stored.as_ref().map(|(id, _)| id) != Some(&root_id) {
+            let stored = self.current_channel().unwrap().stored_thread(&root_id);
+            self.thread_stored = Some((root_id.clone(), stored));
+        }
+        let stored = &self.thread_stored.as_ref().unwrap().1;
+        let messages = self.current_channel().unwrap().get_thread(&root_id, stored);
+        ui.horizontal(|ui| {
+            ui.label("Reply thread");
+            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                if ui.button("Close").clicked() {
+                    self.thread_root_id = None;
+                    self.input_thread_msg = false;
+                }
+                ui.label(format!("{} messages", messages.len()));
+            });
+        });
+        ui.separator();
+        ui.with_layout(Layout::bottom_up(Align::Min), |ui| {
+            let root = messages.first().filter(|m| m.id() == root_id).cloned();
+            let is_connect = self.current_channel().unwrap().is_connected();
+            let mut edit = TextEdit::singleline(&mut self.thread_msg)
+                .desired_width(f32::INFINITY)
+                .hint_text("Reply to thread");
+            if root.is_none() || !is_connect {
+                edit = edit.interactive(false);
+            }
+            if self.access_token.is_empty() {
+                edit = edit
+                    .interactive(false)
+                    .hint_text("Provide the access token to send message");
+            }
+            let response = ui.add(edit);
+            if response.lost_focus() {
+                ui.input_mut(|input| {
+                    if input.consume_key(Modifiers::default(), Key::Enter) {
+                        self.current_channel()
+                            .unwrap()
+                            .send_msg(self.thread_msg.clone(), root.clone());
+                        self.thread_msg = String::new();
+                    }
+                });
+                self.input_thread_msg = false;
+            }
+            if response.gained_focus() {
+                self.input_thread_msg = true;
+            }
+            ui.separator();
+            ui.with_layout(Layout::top_down(Align::Min), |ui| {
+                ScrollArea::vertical()
+                    .id_source("reply_thread")
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        if messages.is_empty() {
+                            ui.label("The thread is no longer in the message buffer");
+                        }
+                        for msg in messages.iter() {
+                            self.draw_msg(ui, msg);
+                        }
+                    });
+            });
+        });
+    }
+
+    fn load_user_history(&mut self) {
+        let history = match &mut self.user_history {
+            Some(history) => history,
+            None => return,
+        };
+        let channel = match self
+            .channel_list
+            .iter()
+            .find(|c| c.channel_name() == history.channel)
+        {
+            Some(channel) => channel,
+            None => return,
+        };
+        let spec = QuerySpec {
+            user: Some(history.login.clone()),
+            from: history
+                .days
+                .map(|days| Utc::now() - chrono::Duration::days(days)),
+            limit: Some(USER_HISTORY_LEN),
+            ..Default::default()
+        };
+        match channel.query(spec) {
+            Ok(msgs) => {
+                history.msgs = msgs;
+                history.error = None;
+            }
+            Err(e) => history.error = Some(e.to_string()),
+        }
+    }
+
+    fn draw_user_history(&mut self, ui: &mut Ui) {
+        let mut history = self.user_history.take().unwrap();
+        let thread_root_id = self.thread_root_id.clone();
+        let mut close = false;
+        let mut reload = false;
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Messages of {}({})",
+                history.display, history.login
+            ));
+            ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                close = ui.button("Close").clicked();
+                ui.label(format!("{} messages", history.msgs.len()));
+            });
+        });
+        ui.horizontal(|ui| {
+            let period_name = |days: Option<i64>| match days {
+                Some(1) => "Last day".to_owned(),
+                Some(7) => "Last week".to_owned(),
+                Some(30) => "Last month".to_owned(),
+                Some(days) => format!("Last {} days", days),
+                None => "All".to_owned(),
+            };
+            ComboBox::from_id_source("user_history_period")
+                .selected_text(period_name(history.days))
+                .show_ui(ui, |ui| {
+                    for days in [Some(1), Some(7), Some(30), None] {
+                        reload |= ui
+                            .selectable_value(&mut history.days, days, period_name(days))
+                            .changed();
+                    }
+                });
+            reload |= ui.button("Refresh").clicked();
+        });
+        if let Some(e) = &history.error {
+            ui.colored_label(ui.style().visuals.error_fg_color, e);
+        }
+        ui.separator();
+        ScrollArea::vertical()
+            .id_source("user_history")
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                if history.msgs.is_empty() {
+                    ui.label("No stored message in this period");
+                }
+                for msg in history.msgs.iter() {
+                    self.draw_msg(ui, msg);
+                }
+            });
+        // A message of the history may have opened another history or a reply thread
+        if !close && self.user_history.is_none() && self.thread_root_id == thread_root_id {
+            self.user_history = Some(history);
+            if reload {
+                self.load_user_history();
+            }
+        }
+    }
+
+    /// Show the log files of a channel in the logs screen
+    fn open_logs(&mut self, channel: usize) {
+        self.logs.channel = channel;
+        self.logs.files = self
+            .channel_list
+            .get(channel)
+            .map(|c| c.log_files())
+            .unwrap_or_default();
+        self.logs.open = None;
+        self.logs.loading = None;
+        self.logs.hits = None;
+        self.logs.error = None;
+    }
+
+    /// Read a log file in the background, `page` is shown once it is read
+    fn open_log_file(&mut self, path: PathBuf, page: usize) {
+        let channel = match self.channel_list.get(self.logs.channel) {
+            Some(channel) => channel.channel_name().to_owned(),
+            None => return,
+        };
+        self.logs.error = None;
+        let reader_path = path.clone();
+        self.logs.loading = Some((
+            path,
+            page,
+            ASYNC_RUNTIME.spawn_blocking(move || read_log_file(&reader_path, &channel)),
+        ));
+    }
+
+    fn log_query(&self) -> Result<LogQuery, String> {
+        let date = |s: &str| {
+            let s = s.trim();
+            if s.is_empty() {
+                Ok(None)
+            } else {
+                NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .map(Some)
+                    .map_err(|_| format!("Invalid date {}, expected YYYY-MM-DD", s))
+            }
+        };
+        Ok(LogQuery {
+            text: self.logs.text.clone(),
+            regex: self.logs.regex,
+            user: self.logs.user.clone(),
+            from: date(&self.logs.from)?,
+            to: date(&self.logs.to)?,
+        })
+    }
+
+    fn draw_logs(&mut self, ui: &mut Ui) {
+        if self.logs.search.as_ref().map(|s| s.is_finished()) == Some(true) {
+            let search = self.logs.search.take().unwrap();
+            match ASYNC_RUNTIME.block_on(search) {
+                Ok(Ok(hits)) => self.logs.hits = Some(hits),
+                Ok(Err(e)) => self.logs.error = Some(e),
+                Err(e) => self.logs.error = Some(e.to_string()),
+            }
+        }
+        if self.logs.loading.as_ref().map(|(_, _, l)| l.is_finished()) == Some(true) {
+            let (path, page, loading) = self.logs.loading.take().unwrap();
+            match ASYNC_RUNTIME.block_on(loading) {
+                Ok(Ok(msgs)) => {
+                    self.logs.open = Some((path, msgs));
+                    self.logs.page = page;
+                }
+                Ok(Err(e)) => self.logs.error = Some(format!("{}: {}", path.display(), e)),
+                Err(e) => self.logs.error = Some(e.to_string()),
+            }
+        }
+        let mut open_channel = None;
+        ui.horizontal(|ui| {
+            ComboBox::from_label("Channel")
+                .selected_text(
+                    self.channel_list
+                        .get(self.logs.channel)
+                        .map(|c| c.channel_name())
+                        .unwrap_or_default(),
+                )
+                .show_ui(ui, |ui| {
+                    for (idx, channel) in self.channel_list.iter().enumerate() {
+                        if ui
+                            .selectable_label(self.logs.channel == idx, channel.channel_name())
+                            .clicked()
+                        {
+                            open_channel = Some(idx);
+                        }
+                    }
+                });
+            if ui.button("Refresh").clicked() {
+                open_channel = Some(self.logs.channel);
+            }
+            ui.label(format!("{} file(s)", self.logs.files.len()));
+        });
+        if let Some(idx) = open_channel {
+            self.open_logs(idx);
+        }
+        ui.horizontal(|ui| {
+            ui.label("Text");
+            ui.add(TextEdit::singleline(&mut self.logs.text).desired_width(200.0));
+            ui.checkbox(&mut self.logs.regex, "Regex");
+            ui.label("User");
+            ui.add(TextEdit::singleline(&mut self.logs.user).desired_width(100.0));
+            ui.label("From");
+            ui.add(
+                TextEdit::singleline(&mut self.logs.from)
+                    .hint_text("YYYY-MM-DD")
+                    .desired_width(90.0),
+            );
+            ui.label("To");
+            ui.add(
+                TextEdit::singleline(&mut self.logs.to)
+                    .hint_text("YYYY-MM-DD")
+                    .desired_width(90.0),
+            );
+            if self.logs.search.is_some() {
+                ui.spinner();
+            } else if ui.button("Search").clicked() {
+                match (self.log_query(), self.channel_list.get(self.logs.channel)) {
+                    (Ok(query), Some(channel)) => {
+                        let files = self.logs.files.clone();
+                        let channel = channel.channel_name().to_owned();
+                        self.logs.error = None;
+                        self.logs.search = Some(
+                            ASYNC_RUNTIME
+                                .spawn_blocking(move || search_logs(&files, &channel, &query)),
+                        );
+                    }
+                    (Err(e), _) => self.logs.error = Some(e),
+                    (_, None) => {}
+                }
+            }
+        });
+        if let Some(e) = &self.logs.error {
+            ui.colored_label(ui.style().visuals.error_fg_color, e);
+        }
+        ui.separator();
+        let mut open_file = None;
+        let mut context = None;
+        ui.horizontal_top(|ui| {
+            ui.vertical(|ui| {
+                ui.set_width(250.0);
+                ScrollArea::vertical()
+                    .id_source("log_files")
+                    .auto_shrink([false, false])
+                    .show(ui, |ui| {
+                        for path in self.logs.files.iter() {
+                            let selected = self.logs.hits.is_none()
+                                && self.logs.open.as_ref().map(|(p, _)| p) == Some(path);
+                            let name = path.file_name().unwrap_or_default().to_string_lossy();
+                            if ui
+                                .selectable_label(selected, name)
+                                .on_hover_text(path.display().to_string())
+                                .clicked()
+                            {
+                                open_file = Some(path.clone());
+                            }
+                        }
+                    });
+            });
+            ui.separator();
+            ui.vertical(|ui| {
+                if let Some(hits) = self.logs.hits.take() {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{} result(s)", hits.len()));
+                        if hits.len() >= crate::chat_log::MAX_LOG_HITS {
+                            ui.label("(more results were not shown)");
+                        }
+                        if ui.button("Close").clicked() {
+                            context = Some(None);
+                        }
+                    });
+                    ScrollArea::vertical()
+                        .id_source("log_hits")
+                        .auto_shrink([false, false])
+                        .show(ui, |ui| {
+                            let mut file = None;
+                            for hit in hits.iter() {
+                                if file != Some(&hit.path) {
+                                    file = Some(&hit.path);
+                                    ui.separator();
+                                    ui.strong(hit.path.display().to_string());
+                                }
+                                ui.horizontal(|ui| {
+                                    if ui.small_button("Context").clicked() {
+                                        context = Some(Some(hit.clone()));
+                                    }
+                                    ui.vertical(|ui| self.draw_msg(ui, &hit.msg));
+                                });
+                            }
+                        });
+                    self.logs.hits = Some(hits);
+                } else if let Some((path, _, _)) = &self.logs.loading {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!("Reading {}", path.display()));
+                    });
+                } else if let Some((path, msgs)) = self.logs.open.take() {
+                    let pages = msgs.len().max(1).div_ceil(LOG_PAGE_LEN);
+                    ui.horizontal(|ui| {
+                        ui.strong(path.display().to_string());
+                        ui.separator();
+                        if ui.button("<").clicked() && self.logs.page > 0 {
+                            self.logs.page -= 1;
+                        }
+                        ui.label(format!("Page {} / {}", self.logs.page + 1, pages));
+                        if ui.button(">").clicked() && self.logs.page + 1 < pages {
+                            self.logs.page += 1;
+                        }
+                        ui.label(format!("{} message(s)", msgs.len()));
+                    });
+                    ScrollArea::vertical()
+                        .id_source("log_viewer")
+                        .auto_shrink([false, false])
+                        .show(ui, |ui| {
+                            let start = (self.logs.page * LOG_PAGE_LEN).min(msgs.len());
+                            let end = (start + LOG_PAGE_LEN).min(msgs.len());
+                            for msg in msgs[start..end].iter() {
+                                if self.logs.scroll && self.show_msg_id.as_deref() == Some(msg.id())
+                                {
+                                    ui.scroll_to_cursor(Some(Align::Center));
+                                    self.logs.scroll = false;
+                                }
+                                self.draw_msg(ui, msg);
+                            }
+                        });
+                    self.logs.open = Some((path, msgs));
+                } else {
+                    ui.label("Select a file or search the logs of the channel");
+                }
+            });
+        });
+        match context {
+            Some(Some(hit)) => {
+                let page = hit.index / LOG_PAGE_LEN;
+                if self.logs.open.as_ref().map(|(p, _)| p) != Some(&hit.path) {
+                    self.open_log_file(hit.path.clone(), page);
+                } else {
+                    self.logs.page = page;
+                }
+                self.logs.hits = None;
+                self.show_msg_id = Some(hit.msg.id().to_owned());
+                self.logs.scroll = true;
+            }
+            Some(None) => self.logs.hits = None,
+            None => {}
+        }
+        if let Some(path) = open_file {
+            self.logs.hits = None;
+            self.open_log_file(path, 0);
+        }
+    }
+
     fn draw_channel_list(&mut self, ui: &mut Ui, size: Vec2) {
         let mut remove_channel = None;
 
@@ -962,9 +2383,7 @@ impl EguiApp {
                     let text_color = match client.state() {
                         ChannelConnectionState::Uninitialized => Color32::GRAY,
                         ChannelConnectionState::Joined => {
-                            if client.log_status().is_some()
-                                || client.filtered_log_status().is_some()
-                            {
+                            if client.is_logging() {
                                 Color32::BLUE
                             } else {
                                 Color32::GREEN
@@ -992,7 +2411,8 @@ impl EguiApp {
                                 ui.separator();
                                 if ui.button("Configuration").clicked() {
                                     self.state =
-                                        AppState::ChannelConfig(idx, client.get_filter_state());
+                                        AppState::ChannelConfig(idx, Box::new((&*client).into()));
+                                    self.test_bench = TestBench::default();
                                     ui.close_menu();
                                 }
                                 if ui.button("Delete").clicked() {
@@ -1003,6 +2423,7 @@ impl EguiApp {
                             .clicked()
                         {
                             self.state = AppState::Normal;
+                            self.thread_root_id = None;
                         };
 
                         channel_ui.with_layout(Layout::right_to_left(Align::RIGHT), |sub_ui| {
@@ -1014,16 +2435,28 @@ impl EguiApp {
                                     client.disconnect();
                                 }
                             }
-                            if client.has_unread_filtered_msg() {
+                            if client.has_urgent_alert() {
+                                // Flashes until the urgent messages are acknowledged
+                                let on = Utc::now().timestamp_millis() / 500 % 2 == 0;
+                                let color = if on {
+                                    Color32::RED
+                                } else {
+                                    sub_ui.style().visuals.warn_fg_color
+                                };
+                                sub_ui.label(RichText::new("!!").color(color).strong());
+                                sub_ui
+                                    .ctx()
+                                    .request_repaint_after(std::time::Duration::from_millis(500));
+                            } else if client.has_unread_filtered_msg() {
                                 sub_ui.label(
                                     RichText::new("!").color(sub_ui.style().visuals.warn_fg_color),
                                 );
                             }
-                            sub_ui.label(format!(
-                                "{} / {}",
-                                client.get_msg_count(false),
-                                client.get_msg_count(true)
-                            ));
+                            let mut counts = vec![client.get_msg_count(None).to_string()];
+                            for view_idx in 0..client.view_count() {
+                                counts.push(client.get_msg_count(Some(view_idx)).to_string());
+                            }
+                            sub_ui.label(counts.join(" / "));
                         });
                     });
 
@@ -1098,28 +2531,117 @@ impl eframe::App for EguiApp {
                 100 - (now.timestamp_millis() - self.last_time_updated.timestamp_millis()) as u64,
             ));
         }
-        if let Some((idx, filtered)) = self.log_btn {
-            if let Some(path) = FileDialog::new().save_file() {
-                if filtered {
-                    self.channel_list[idx].set_filtered_log(Some(path));
-                } else {
-                    self.channel_list[idx].set_log(Some(path));
-                }
+        if let Some((idx, view, format)) = self.log_btn {
+            if let Some(path) = log_file_dialog(self.channel_list[idx].channel_name(), format) {
+                self.channel_list[idx].set_log(view, Some(path), format);
             }
             self.log_btn = None;
         }
+        match self.preset_btn.take() {
+            Some(PresetAction::Import(target)) => {
+                if let Some(path) = FileDialog::new()
+                    .add_filter("Filter preset", &["ron"])
+                    .pick_file()
+                {
+                    self.import_preset(&path, target);
+                }
+            }
+            Some(PresetAction::Export(preset)) => {
+                if let Some(path) = FileDialog::new()
+                    .add_filter("Filter preset", &["ron"])
+                    .set_file_name(&format!("{}.ron", sanitize(&preset.name)))
+                    .save_file()
+                {
+                    self.error_msg = preset.save(&path).err().map(|e| e.to_string());
+                }
+            }
+            None => {}
+        }
+        if let Some((rule_idx, format)) = self.rule_log_btn {
+            if let AppState::ChannelConfig(idx, config) = &mut self.state {
+                let channel = self.channel_list[*idx].channel_name();
+                if let Some(path) = log_file_dialog(channel, format) {
+                    config.rules[rule_idx].log = Some(path);
+                    config.rules[rule_idx].log_format = format;
+                }
+            }
+            self.rule_log_btn = None;
+        }
+        if self.log_dir_btn {
+            if let Some(dir) = FileDialog::new().pick_folder() {
+                match &mut self.state {
+                    AppState::ChannelConfig(_, config) => config.log.dir = dir,
+                    _ => self.def_log.dir = dir,
+                }
+            }
+            self.log_dir_btn = false;
+        }
+        if self.store_dir_btn {
+            if let Some(dir) = FileDialog::new().pick_folder() {
+                self.store.dir = dir;
+            }
+            self.store_dir_btn = false;
+        }
+        if let Some(target) = self.sound_btn.take() {
+            if let AppState::ChannelConfig(_, config) = &mut self.state {
+                if let Some(path) = FileDialog::new()
+                    .add_filter("Sound", &SOUND_EXTENSIONS)
+                    .pick_file()
+                {
+                    let error = self.alert_player.load(&path).err();
+                    self.error_msg = error.map(|e| e.to_string());
+                    match target {
+                        SoundTarget::Channel => config.alert_sound = Some(path),
+                        SoundTarget::Rule(rule_idx) => {
+                            config.rules[rule_idx].sound_file = Some(path)
+                        }
+                    }
+                }
+            }
+        }
         ctx.request_repaint_after(std::time::Duration::from_secs(1));
+        if self.state == AppState::Normal
+            && self.thread_root_id.is_some()
+            && self.current_channel().is_some()
+        {
+            egui::SidePanel::right("reply_thread")
+                .default_width(400.0)
+                .show(ctx, |ui| self.draw_thread(ui));
+        } else {
+            self.thread_stored = None;
+        }
+        if self.state == AppState::Normal && self.user_history.is_some() {
+            egui::SidePanel::right("user_history")
+                .default_width(400.0)
+                .show(ctx, |ui| self.draw_user_history(ui));
+        }
+        if ctx.input_mut(|i| i.consume_key(Modifiers::CTRL | Modifiers::SHIFT, Key::A)) {
+            if let Some(channel) = self.current_channel_mut() {
+                channel.acknowledge_all();
+            }
+        }
+        self.draw_reapply_prompt(ctx);
+        self.draw_export_preset(ctx);
         egui::CentralPanel::default().show(ctx, |app_ui| {
             let compact_mode = app_ui.available_height() / app_ui.available_width() > 0.9;
             app_ui.horizontal(|ui| {
-                if let AppState::ChannelConfig(idx, filter_state) = &self.state {
+                if let AppState::ChannelConfig(_, _) = &self.state {
                     if ui.button("Back").clicked() {
-                        match self.channel_list[*idx].set_filter(filter_state) {
-                            Ok(_) => {
+                        match self.apply_channel_config() {
+                            Ok(changed) => {
+                                if let AppState::ChannelConfig(idx, _) = self.state {
+                                    if !changed.is_empty() {
+                                        self.reapply_prompt = Some(ReapplyPrompt {
+                                            channel: idx,
+                                            views: changed,
+                                            log: false,
+                                        });
+                                    }
+                                }
                                 self.error_msg = None;
                                 self.state = AppState::Normal;
                             }
-                            Err(e) => self.error_msg = Some(format!("{}", e)),
+                            Err(e) => self.error_msg = Some(e),
                         }
                     }
                     ui.separator();
@@ -1139,19 +2661,16 @@ impl eframe::App for EguiApp {
                         AppState::Config => {
                             if self.credential_changed && self.re_login().is_err() {
                                 self.error_msg = Some("Login to chat failed".to_string());
-                            } else if let Err(e) = build_regexes(&self.def_filter.inc_author) {
-                                self.error_msg = Some(format!("{}", e));
-                            } else if let Err(e) = build_regexes(&self.def_filter.inc_msg) {
-                                self.error_msg = Some(format!("{}", e));
-                            } else if let Err(e) = build_regexes(&self.def_filter.exc_msg) {
-                                self.error_msg = Some(format!("{}", e));
-                            } else if let Err(e) = build_regexes(&self.def_filter.exc_author) {
+                            } else if let Err(e) = Filter::try_from(&self.def_filter) {
                                 self.error_msg = Some(format!("{}", e));
+                            } else if let Err(e) = self.apply_library() {
+                                self.error_msg = Some(e);
                             } else {
                                 self.error_msg = None;
                                 self.state = AppState::Normal;
                                 for channel in self.channel_list.iter_mut() {
                                     channel.set_max_msg_count(self.max_msg_count);
+                                    channel.set_store(&self.store);
                                 }
                             }
                         }
@@ -1163,6 +2682,19 @@ impl eframe::App for EguiApp {
                     }
                 }
                 ui.separator();
+                if !matches!(self.state, AppState::Config | AppState::ChannelConfig(_, _))
+                    && ui
+                        .selectable_label(self.state == AppState::Logs, "Logs")
+                        .clicked()
+                {
+                    if self.state == AppState::Logs {
+                        self.state = AppState::Normal;
+                    } else {
+                        self.state = AppState::Logs;
+                        self.open_logs(self.selected_channel);
+                    }
+                }
+                ui.separator();
                 if compact_mode
                     && (self.state == AppState::Normal || self.state == AppState::ChannelList)
                 {
@@ -1209,6 +2741,34 @@ impl eframe::App for EguiApp {
                         );
                     }
                 }
+                // Visual fallback for the alerts that could not be heard
+                let mut silent = self
+                    .alert_player
+                    .silent_alerts(chrono::Duration::seconds(10));
+                if !silent.is_empty() {
+                    silent.dedup();
+                    ui.separator();
+                    ui.label(
+                        RichText::new(format!("Alert: {}", silent.join(", ")))
+                            .color(ui.style().visuals.warn_fg_color)
+                            .strong(),
+                    )
+                    .on_hover_text(self.alert_player.status().to_string());
+                }
+                let mut sound_errors = self
+                    .alert_player
+                    .sound_errors(chrono::Duration::seconds(10));
+                if !sound_errors.is_empty() {
+                    sound_errors.dedup();
+                    ui.separator();
+                    ui.label(
+                        RichText::new(format!(
+                            "Alert played with the default sound, {}",
+                            sound_errors.join(", ")
+                        ))
+                        .color(Color32::RED),
+                    );
+                }
             });
             app_ui.separator();
             match &mut self.state {
@@ -1216,11 +2776,18 @@ impl eframe::App for EguiApp {
                 AppState::Config => self.draw_config(app_ui, ctx),
                 AppState::ChannelConfig(_, _) => self.draw_channel_config(app_ui),
                 AppState::ChannelList => self.draw_channel_list(app_ui, app_ui.available_size()),
+                AppState::Logs => self.draw_logs(app_ui),
             }
         });
         self.last_time_updated = now;
     }
 
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        for channel in self.channel_list.iter() {
+            channel.flush_logs();
+        }
+    }
+
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         storage.set_string(
             super::APP_SAVE_STATE_KEY,
@@ -1229,17 +2796,6 @@ impl eframe::App for EguiApp {
     }
 }
 
-fn build_regexes(patterns: &str) -> Result<Vec<Regex>, regex::Error> {
-    let mut out = vec![];
-    for line in patterns
-        .split('\n')
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-    {
-        out.push(Regex::new(line)?);
-    }
-    Ok(out)
-}
 pub fn set_font_size(ctx: &Context, size: f32) {
     let arc: &Style = &ctx.style();
     let mut style = arc.clone();
@@ -1257,15 +2813,83 @@ pub fn set_font_size(ctx: &Context, size: f32) {
     ctx.set_style(style)
 }
 
+#[derive(Deserialize, Serialize)]
+struct FilterViewSaveState {
+    name: String,
+    filter: FilterState,
+    log_status: Option<PathBuf>,
+    #[serde(default)]
+    log_format: LogFormat,
+    alert: bool,
+    #[serde(default)]
+    alert_condition: AlertCondition,
+    #[serde(default)]
+    library: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LibraryFilterSaveState {
+    name: String,
+    filter: FilterState,
+}
+
 #[derive(Deserialize, Serialize)]
 struct ChannelSaveState {
     name: String,
     enabled: bool,
+    // `filter`, `filtered_log_status` and `alert` are the single filter view of old save states
+    #[serde(default, skip_serializing)]
     filter: FilterState,
     bell: bool,
     log_status: Option<PathBuf>,
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default, skip_serializing)]
     filtered_log_status: Option<PathBuf>,
+    #[serde(default, skip_serializing)]
     alert: bool,
+    #[serde(default)]
+    views: Option<Vec<FilterViewSaveState>>,
+    #[serde(default)]
+    rules: Vec<RuleState>,
+    #[serde(default)]
+    duplicates: DuplicateSettings,
+    #[serde(default)]
+    alert_sound: Option<PathBuf>,
+    #[serde(default)]
+    alert_settings: AlertSettings,
+    #[serde(default)]
+    command: CommandSettings,
+    #[serde(default)]
+    log_settings: LogSettings,
+}
+
+impl ChannelSaveState {
+    fn view_states(&self) -> Vec<FilterViewSaveState> {
+        match &self.views {
+            Some(views) => views
+                .iter()
+                .map(|v| FilterViewSaveState {
+                    name: v.name.clone(),
+                    filter: v.filter.clone(),
+                    log_status: v.log_status.clone(),
+                    log_format: v.log_format,
+                    alert: v.alert,
+                    alert_condition: v.alert_condition,
+                    library: v.library.clone(),
+                })
+                .collect(),
+            None => vec![FilterViewSaveState {
+                name: DEFAULT_VIEW_NAME.to_owned(),
+                filter: self.filter.clone(),
+                log_status: self.filtered_log_status.clone(),
+                log_format: LogFormat::default(),
+                alert: self.alert,
+                alert_condition: AlertCondition::default(),
+                library: vec![],
+            }],
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -1284,6 +2908,12 @@ pub struct AppSaveState {
     selected_channel: usize,
     selected_font: String,
     max_msg_count: usize,
+    #[serde(default)]
+    filter_library: Vec<LibraryFilterSaveState>,
+    #[serde(default)]
+    def_log: LogSettings,
+    #[serde(default)]
+    store: StoreSettings,
 }
 
 impl From<&EguiApp> for AppSaveState {
@@ -1296,14 +2926,38 @@ impl From<&EguiApp> for AppSaveState {
                 .map(|c| ChannelSaveState {
                     name: c.channel_name().to_owned(),
                     enabled: c.is_connected(),
-                    filter: c.get_filter_state(),
-                    log_status: c.log_status().and_then(|r| r.ok()),
-                    filtered_log_status: c.filtered_log_status().and_then(|r| r.ok()),
+                    filter: FilterState::default(),
+                    log_status: c.log_template(None),
+                    log_format: c.log_format(None),
+                    filtered_log_status: None,
                     bell: false,
-                    alert: c.alert(),
+                    alert: false,
+                    views: Some(
+                        c.view_names()
+                            .into_iter()
+                            .enumerate()
+                            .map(|(view_idx, name)| FilterViewSaveState {
+                                name,
+                                filter: c.get_filter_state(view_idx),
+                                log_status: c.log_template(Some(view_idx)),
+                                log_format: c.log_format(Some(view_idx)),
+                                alert: c.alert(view_idx),
+                                alert_condition: c.alert_condition(view_idx),
+                                library: c.library_refs(view_idx),
+                            })
+                            .collect(),
+                    ),
+                    rules: c.rule_states(),
+                    duplicates: c.duplicate_settings(),
+                    alert_sound: c.alert_sound(),
+                    alert_settings: c.alert_settings(),
+                    command: c.command_settings(),
+                    log_settings: c.log_settings(),
                 })
                 .collect(),
             def_filter: value.def_filter.clone(),
+            def_log: value.def_log.clone(),
+            store: value.store.clone(),
             use_twitch_color: value.use_twitch_color,
             name_display: value.name_display,
             show_sent_time: value.show_sent_time,
@@ -1315,6 +2969,16 @@ impl From<&EguiApp> for AppSaveState {
             selected_channel: value.selected_channel,
             selected_font: value.selected_font.clone(),
             max_msg_count: value.max_msg_count,
+            filter_library: value
+                .filter_library
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(name, filter)| LibraryFilterSaveState {
+                    name: name.clone(),
+                    filter: filter.into(),
+                })
+                .collect(),
         }
     }
 }
@@ -1344,39 +3008,664 @@ fn toggle_btn(ui: &mut egui::Ui, on: &mut bool) -> egui::Response {
     response
 }
 
-fn draw_filter_config(ui: &mut Ui, filter_state: &mut FilterState) {
-    ui.group(|group_ui| {
-        let label: Response = group_ui.label("Inclusive Message Filters: ");
-        group_ui
-            .add(TextEdit::multiline(&mut filter_state.inc_msg).desired_width(500.0))
-            .labelled_by(label.id);
+enum PresetRequest {
+    Import,
+    Export,
+}
+
+/// Returns the preset button clicked, if any
+fn draw_filter_config(ui: &mut Ui, filter_state: &mut FilterState) -> Option<PresetRequest> {
+    let mut request = None;
+    ui.horizontal(|ui| {
+        if ui.button("Import filter").clicked() {
+            request = Some(PresetRequest::Import);
+        }
+        if ui.button("Export filter").clicked() {
+            request = Some(PresetRequest::Export);
+        }
     });
-    ui.add_space(10.0);
-    ui.group(|group_ui| {
-        let label = group_ui.label("Inclusive Author Filters (only test against user id)");
-        group_ui
-            .add(TextEdit::multiline(&mut filter_state.inc_author).desired_width(500.0))
-            .labelled_by(label.id);
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut filter_state.use_expression, "Use filter expression");
+        if ui
+            .button("Convert lists to expression")
+            .on_hover_text("Replace the expression with one equivalent to the lists below")
+            .clicked()
+        {
+            filter_state.expression = filter_state.to_expression();
+            filter_state.use_expression = true;
+        }
     });
     ui.add_space(10.0);
-    ui.group(|group_ui| {
-        let label: Response = group_ui.label("Exclusive Message Filters: ");
-        group_ui
-            .add(TextEdit::multiline(&mut filter_state.exc_msg).desired_width(500.0))
-            .labelled_by(label.id);
-    });
+    if filter_state.use_expression {
+        ui.group(|group_ui| {
+            let label = group_ui.label("Filter expression: ");
+            group_ui
+                .add(
+                    TextEdit::multiline(&mut filter_state.expression)
+                        .desired_width(500.0)
+                        .hint_text("badge = \"moderator\" and msg ~ \"https?://\""),
+                )
+                .labelled_by(label.id);
+            group_ui.label(
+                RichText::new(
+                    "Fields: msg, login, display, user_id, badge, color, bits, letters, first. \
+                     Script percentages: cjk, han, kana, hangul, latin, cyrillic, greek, \
+                     arabic, hebrew, thai. \
+                     Heuristics: length, caps, urls, emotes, mentions, run. \
+                     Operators: ~ (regex), =, !=, <, <=, >, >=, and, or, not, ( )",
+                )
+                .small(),
+            );
+            if let Err(e) = Expression::parse(&filter_state.expression) {
+                group_ui.label(RichText::new(e.to_string()).color(Color32::RED));
+            }
+        });
+        return request;
+    }
+    draw_pattern_list(
+        ui,
+        "Inclusive Message Filters: ",
+        &mut filter_state.inc_msg,
+        false,
+    );
     ui.add_space(10.0);
-    ui.group(|group_ui| {
-        let label = group_ui.label("Exclusive Author Filters (only test against user id)");
-        group_ui
-            .add(TextEdit::multiline(&mut filter_state.exc_author).desired_width(500.0))
-            .labelled_by(label.id);
-    });
+    draw_pattern_list(
+        ui,
+        "Inclusive Author Filters: ",
+        &mut filter_state.inc_author,
+        true,
+    );
+    ui.add_space(10.0);
+    draw_pattern_list(
+        ui,
+        "Exclusive Message Filters: ",
+        &mut filter_state.exc_msg,
+        false,
+    );
+    ui.add_space(10.0);
+    draw_pattern_list(
+        ui,
+        "Exclusive Author Filters: ",
+        &mut filter_state.exc_author,
+        true,
+    );
+    ui.add_space(10.0);
+    draw_script_list(
+        ui,
+        "Inclusive Script Filters: ",
+        &mut filter_state.inc_script,
+    );
+    ui.add_space(10.0);
+    draw_script_list(
+        ui,
+        "Exclusive Script Filters: ",
+        &mut filter_state.exc_script,
+    );
+    ui.add_space(10.0);
+    draw_heuristic_list(
+        ui,
+        "Inclusive Heuristic Filters: ",
+        &mut filter_state.inc_heuristic,
+    );
+    ui.add_space(10.0);
+    draw_heuristic_list(
+        ui,
+        "Exclusive Heuristic Filters: ",
+        &mut filter_state.exc_heuristic,
+    );
     ui.add_space(10.0);
     ui.checkbox(&mut filter_state.broadcaster, "Broadcaster");
     ui.checkbox(&mut filter_state.moderator, "Moderator");
     ui.checkbox(&mut filter_state.vip, "VIP");
     ui.checkbox(&mut filter_state.partner, "Partner");
+    request
+}
+
+/// Ask where to write a log, suggesting a file name with the extension of `format`
+fn log_file_dialog(channel: &str, format: LogFormat) -> Option<PathBuf> {
+    FileDialog::new()
+        .add_filter(format.name(), &[format.extension()])
+        .set_file_name(&format!("{}.{}", channel, format.extension()))
+        .save_file()
+}
+
+/// Returns whether a new sound file should be picked.
+/// `default` names the sound played when no file is set.
+fn draw_sound_file(
+    ui: &mut Ui,
+    player: &AlertPlayer,
+    path: &mut Option<PathBuf>,
+    default: &str,
+    error: &mut Option<String>,
+) -> bool {
+    let mut pick = false;
+    ui.horizontal(|ui| {
+        ui.label("Sound: ");
+        match path.as_deref() {
+            Some(p) => {
+                ui.label(format!("{}", p.display()));
+                if !p.is_file() {
+                    ui.label(RichText::new("(missing)").color(Color32::RED));
+                }
+            }
+            None => {
+                ui.label(default);
+            }
+        }
+        if ui.button("Choose").clicked() {
+            pick = true;
+        }
+        if path.is_some() && ui.button("Reset").clicked() {
+            *path = None;
+        }
+        if ui.button("Preview").clicked() {
+            *error = path
+                .as_deref()
+                .and_then(|p| player.load(p).err())
+                .map(|e| e.to_string());
+            if error.is_none() {
+                player.play_sound(path.as_deref());
+            }
+        }
+    });
+    pick
+}
+
+fn draw_command_settings(ui: &mut Ui, settings: &mut CommandSettings) {
+    ui.checkbox(&mut settings.enabled, "Run a command on filtered messages");
+    ui.add_enabled_ui(settings.enabled, |ui| {
+        ui.add(
+            TextEdit::singleline(&mut settings.template)
+                .desired_width(500.0)
+                .hint_text("espeak \"{display}: {msg}\""),
+        );
+        ui.label(
+            RichText::new(
+                "Run without a shell. Placeholders: {msg}, {login}, {display}, {user_id}, {id}, \
+                 {channel}, {view}, also set as TWITCH_MSG, TWITCH_LOGIN, ... environment variables",
+            )
+            .small(),
+        );
+        if settings.enabled {
+            if let Err(e) = parse_template(&settings.template) {
+                ui.label(RichText::new(e).color(Color32::RED));
+            }
+        }
+        ui.horizontal(|ui| {
+            ui.label("At most");
+            ui.add(DragValue::new(&mut settings.max_running).clamp_range(1..=32));
+            ui.label("running, killed after");
+            ui.add(
+                DragValue::new(&mut settings.timeout_secs)
+                    .clamp_range(1..=3600)
+                    .suffix(" s"),
+            );
+        });
+    });
+}
+
+/// Returns whether the directory should be chosen with a dialog
+fn draw_log_settings(ui: &mut Ui, settings: &mut LogSettings) -> bool {
+    let mut choose_dir = false;
+    ui.horizontal(|ui| {
+        ui.label("Directory:");
+        let mut dir = settings.dir.to_string_lossy().into_owned();
+        if ui.text_edit_singleline(&mut dir).changed() {
+            settings.dir = PathBuf::from(dir);
+        }
+        choose_dir = ui.button("Choose").clicked();
+    });
+    ui.horizontal(|ui| {
+        ui.label("Path template:");
+        ui.add(TextEdit::singleline(&mut settings.template).desired_width(400.0));
+    });
+    ui.label(
+        RichText::new(
+            "Placeholders: {dir}, {channel}, {view} (\"all\" for all messages, or the rule name), \
+             {yyyy}, {mm}, {dd}, {ext} (extension of the log format)",
+        )
+        .small(),
+    );
+    ui.horizontal(|ui| {
+        ComboBox::from_label("Rotation")
+            .selected_text(settings.rotation.name())
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut settings.rotation, Rotation::Never, "Never");
+                ui.selectable_value(&mut settings.rotation, Rotation::Daily, "Daily");
+                if ui
+                    .selectable_label(
+                        matches!(settings.rotation, Rotation::Size { .. }),
+                        "By size",
+                    )
+                    .clicked()
+                    && !matches!(settings.rotation, Rotation::Size { .. })
+                {
+                    settings.rotation = Rotation::Size { max_mb: 10 };
+                }
+            });
+        if let Rotation::Size { max_mb } = &mut settings.rotation {
+            ui.label("at");
+            ui.add(DragValue::new(max_mb).clamp_range(1..=10240).suffix(" MB"));
+        }
+    });
+    ui.checkbox(&mut settings.gzip, "Compress finished files with gzip");
+    ui.horizontal(|ui| {
+        ui.label("Keep");
+        ui.add(DragValue::new(&mut settings.keep_files).clamp_range(0..=10000));
+        ui.label("finished files (0 keeps all)");
+    });
+    choose_dir
+}
+
+/// Returns whether the directory should be chosen with a dialog
+fn draw_store_settings(ui: &mut Ui, settings: &mut StoreSettings) -> bool {
+    let mut choose_dir = false;
+    ui.checkbox(
+        &mut settings.enabled,
+        "Store every message on disk, for the message history of users and reply threads",
+    );
+    ui.add_enabled_ui(settings.enabled, |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Directory:");
+            let mut dir = settings.dir.to_string_lossy().into_owned();
+            if ui.text_edit_singleline(&mut dir).changed() {
+                settings.dir = PathBuf::from(dir);
+            }
+            choose_dir = ui.button("Choose").clicked();
+        });
+        ui.horizontal(|ui| {
+            ui.label("Keep");
+            ui.add(DragValue::new(&mut settings.keep_days).clamp_range(0..=3650));
+            ui.label("days (0 keeps all, the index of every stored message then stays in memory)");
+        });
+    });
+    choose_dir
+}
+
+fn draw_alert_settings(ui: &mut Ui, settings: &mut AlertSettings) {
+    ui.horizontal(|ui| {
+        ui.label("Cooldown: ");
+        ui.add(
+            DragValue::new(&mut settings.cooldown_secs)
+                .clamp_range(0..=3600)
+                .suffix(" s"),
+        );
+        ComboBox::from_label("while other alerts play")
+            .selected_text(settings.queue.name())
+            .show_ui(ui, |ui| {
+                for policy in QueuePolicy::ALL {
+                    ui.selectable_value(&mut settings.queue, policy, policy.name());
+                }
+            });
+    });
+}
+
+/// Returns whether the condition changed
+fn draw_alert_condition(ui: &mut Ui, condition: &mut AlertCondition) -> bool {
+    let old = *condition;
+    ui.horizontal(|ui| {
+        ComboBox::from_label("Alert when")
+            .selected_text(condition.name())
+            .show_ui(ui, |ui| {
+                for c in [
+                    AlertCondition::Burst {
+                        count: 3,
+                        seconds: 10,
+                    },
+                    AlertCondition::default(),
+                ] {
+                    if ui
+                        .selectable_label(
+                            std::mem::discriminant(condition) == std::mem::discriminant(&c),
+                            c.name(),
+                        )
+                        .clicked()
+                        && std::mem::discriminant(condition) != std::mem::discriminant(&c)
+                    {
+                        *condition = c;
+                    }
+                }
+            });
+        match condition {
+            AlertCondition::Burst { count, seconds } => {
+                ui.label("at least");
+                ui.add(DragValue::new(count).clamp_range(1..=1000));
+                ui.label("messages within");
+                ui.add(DragValue::new(seconds).clamp_range(1..=3600).suffix(" s"));
+            }
+            AlertCondition::AfterSilence { seconds } => {
+                let mut minutes = *seconds as f64 / 60.0;
+                ui.label("after");
+                if ui
+                    .add(
+                        DragValue::new(&mut minutes)
+                            .clamp_range(0.0..=1440.0)
+                            .speed(0.1)
+                            .suffix(" min"),
+                    )
+                    .changed()
+                {
+                    *seconds = (minutes * 60.0).round() as u64;
+                }
+                ui.label("without filtered messages");
+            }
+        }
+    });
+    *condition != old
+}
+
+fn draw_test_bench(
+    ui: &mut Ui,
+    bench: &mut TestBench,
+    channel: &ChannelManager,
+    views: &[ViewConfigState],
+    library: &[(String, Filter)],
+) {
+    ui.label("Filter test bench");
+    if views.is_empty() {
+        ui.label("Add a view to test its filter");
+        return;
+    }
+    bench.view = bench.view.min(views.len() - 1);
+    ComboBox::from_label("View to test")
+        .selected_text(&views[bench.view].name)
+        .show_ui(ui, |ui| {
+            for (view_idx, view) in views.iter().enumerate() {
+                ui.selectable_value(&mut bench.view, view_idx, &view.name);
+            }
+        });
+
+    let view = &views[bench.view];
+    let referenced = library
+        .iter()
+        .filter(|(name, _)| view.library.contains(name))
+        .map(|(_, filter)| FilterState::from(filter))
+        .collect();
+    let key = (view.filter.clone(), view.library.clone(), referenced);
+    if bench.key.as_ref() != Some(&key) {
+        bench.filter = Some(Filter::try_from(&key.0));
+        bench.key = Some(key);
+        bench.last_id = None;
+        bench.updated = None;
+        bench.results.clear();
+    }
+    // New messages are explained at most once per second, and only those not explained yet
+    let due = bench
+        .updated
+        .filter(|t| t.elapsed() < std::time::Duration::from_secs(1))
+        .is_none();
+    if let (true, Some(Ok(filter))) = (due, &bench.filter) {
+        let count = channel.get_msg_count(None);
+        let last_id = channel
+            .get_n_msg(count.saturating_sub(1)..count, None)
+            .pop()
+            .map(|m| m.id().to_owned());
+        if last_id != bench.last_id {
+            let mut known: HashMap<String, Explanation> = bench
+                .results
+                .drain(..)
+                .map(|(msg, explanation)| (msg.id().to_owned(), explanation))
+                .collect();
+            bench.results = channel
+                .get_msg(None)
+                .into_iter()
+                .map(|msg| {
+                    let explanation = known.remove(msg.id()).unwrap_or_else(|| {
+                        filter.explain_with_library(library, &view.library, &msg)
+                    });
+                    (msg, explanation)
+                })
+                .collect();
+            bench.last_id = last_id;
+        }
+        bench.updated = Some(std::time::Instant::now());
+    }
+    let filter = match &bench.filter {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => {
+            ui.label(RichText::new(e.to_string()).color(Color32::RED));
+            return;
+        }
+        None => return,
+    };
+
+    ui.horizontal(|ui| {
+        let label = ui.label("Sender: ");
+        if ui
+            .text_edit_singleline(&mut bench.login)
+            .labelled_by(label.id)
+            .changed()
+        {
+            bench.picked = None;
+        }
+    });
+    let label = ui.label("Message or raw IRC line: ");
+    if ui
+        .add(TextEdit::multiline(&mut bench.text).desired_rows(2))
+        .labelled_by(label.id)
+        .changed()
+    {
+        bench.picked = None;
+    }
+    let sample = bench.picked.clone().or_else(|| {
+        (!bench.text.is_empty())
+            .then(|| {
+                chat_client::build_sample_msg(channel.channel_name(), &bench.login, &bench.text)
+            })
+            .flatten()
+    });
+    if let Some(msg) = sample {
+        let explanation = filter.explain_with_library(library, &view.library, &msg);
+        let color = if explanation.matched {
+            Color32::GREEN
+        } else {
+            Color32::RED
+        };
+        ui.label(RichText::new(explanation.to_string()).color(color));
+    }
+
+    ui.add_space(10.0);
+    let matched = bench.results.iter().filter(|(_, e)| e.matched).count();
+    ui.horizontal(|ui| {
+        ui.label(format!(
+            "{} of {} buffered messages match",
+            matched,
+            bench.results.len()
+        ));
+        ui.checkbox(&mut bench.only_matched, "Only matching");
+    });
+    let mut picked = None;
+    ScrollArea::vertical()
+        .id_source("test_bench")
+        .max_height(300.0)
+        .show(ui, |ui| {
+            for (msg, explanation) in bench
+                .results
+                .iter()
+                .rev()
+                .filter(|(_, e)| e.matched || !bench.only_matched)
+            {
+                ui.horizontal(|ui| {
+                    let (mark, color) = if explanation.matched {
+                        ("✔", Color32::GREEN)
+                    } else {
+                        ("✘", Color32::RED)
+                    };
+                    ui.label(RichText::new(mark).color(color));
+                    if ui
+                        .selectable_label(
+                            false,
+                            format!("{}: {}", msg.sender_display(), msg.payload()),
+                        )
+                        .on_hover_text(explanation.to_string())
+                        .clicked()
+                    {
+                        picked = Some(msg.clone());
+                    }
+                });
+            }
+        });
+    if let Some(msg) = picked {
+        bench.login = msg.sender_login().to_owned();
+        bench.text = msg.payload().to_owned();
+        bench.picked = Some(msg);
+    }
+}
+
+fn draw_pattern_list(ui: &mut Ui, label: &str, patterns: &mut Vec<PatternState>, author: bool) {
+    ui.push_id(label, |ui| {
+        ui.group(|group_ui| {
+            group_ui.label(label);
+            let mut remove = None;
+            for (idx, pattern) in patterns.iter_mut().enumerate() {
+                group_ui.horizontal(|ui| {
+                    if author {
+                        ComboBox::from_id_source(("target", idx))
+                            .selected_text(pattern.target.name())
+                            .width(120.0)
+                            .show_ui(ui, |ui| {
+                                for target in AuthorField::ALL {
+                                    ui.selectable_value(&mut pattern.target, target, target.name());
+                                }
+                            });
+                    }
+                    ComboBox::from_id_source(idx)
+                        .selected_text(pattern.mode.name())
+                        .width(120.0)
+                        .show_ui(ui, |ui| {
+                            for mode in MatchMode::ALL {
+                                ui.selectable_value(&mut pattern.mode, mode, mode.name());
+                            }
+                        });
+                    ui.checkbox(&mut pattern.case_sensitive, "Aa")
+                        .on_hover_text("Case sensitive");
+                    ui.add(TextEdit::singleline(&mut pattern.text).desired_width(300.0));
+                    if pattern.mode == MatchMode::Regex {
+                        if let Err(e) = Regex::new(&pattern.to_regex_str()) {
+                            ui.label(RichText::new("!").color(Color32::RED))
+                                .on_hover_text(e.to_string());
+                        }
+                    }
+                    if ui.button("x").clicked() {
+                        remove = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = remove {
+                patterns.remove(idx);
+            }
+            if group_ui.button("+").clicked() {
+                patterns.push(PatternState::default());
+            }
+        });
+    });
+}
+
+fn draw_script_list(ui: &mut Ui, label: &str, rules: &mut Vec<ScriptRule>) {
+    ui.push_id(label, |ui| {
+        ui.group(|group_ui| {
+            group_ui.label(label);
+            let mut remove = None;
+            for (idx, rule) in rules.iter_mut().enumerate() {
+                group_ui.horizontal(|ui| {
+                    ComboBox::from_id_source(("condition", idx))
+                        .selected_text(rule.condition.name())
+                        .width(120.0)
+                        .show_ui(ui, |ui| {
+                            for condition in ScriptCondition::ALL {
+                                ui.selectable_value(
+                                    &mut rule.condition,
+                                    condition,
+                                    condition.name(),
+                                );
+                            }
+                        });
+                    if rule.condition != ScriptCondition::NoLetters {
+                        ComboBox::from_id_source(("script", idx))
+                            .selected_text(rule.script.name())
+                            .width(120.0)
+                            .show_ui(ui, |ui| {
+                                for script in Script::ALL {
+                                    ui.selectable_value(&mut rule.script, script, script.name());
+                                }
+                            });
+                    }
+                    if ui.button("x").clicked() {
+                        remove = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = remove {
+                rules.remove(idx);
+            }
+            if group_ui.button("+").clicked() {
+                rules.push(ScriptRule::default());
+            }
+        });
+    });
+}
+
+fn draw_heuristic_list(ui: &mut Ui, label: &str, heuristics: &mut Vec<Heuristic>) {
+    ui.push_id(label, |ui| {
+        ui.group(|group_ui| {
+            group_ui.label(label);
+            let mut remove = None;
+            for (idx, heuristic) in heuristics.iter_mut().enumerate() {
+                group_ui.horizontal(|ui| {
+                    ComboBox::from_id_source(idx)
+                        .selected_text(heuristic.name())
+                        .width(160.0)
+                        .show_ui(ui, |ui| {
+                            for h in Heuristic::ALL {
+                                let same =
+                                    std::mem::discriminant(heuristic) == std::mem::discriminant(&h);
+                                if ui.selectable_label(same, h.name()).clicked() && !same {
+                                    *heuristic = h;
+                                }
+                            }
+                        });
+                    match heuristic {
+                        Heuristic::Length { min, max } => {
+                            ui.label("from");
+                            ui.add(DragValue::new(min).clamp_range(0..=500));
+                            ui.label("to");
+                            ui.add(DragValue::new(max).clamp_range(*min..=500));
+                            ui.label("characters");
+                        }
+                        Heuristic::Uppercase {
+                            percent,
+                            min_letters,
+                        } => {
+                            ui.label("at least");
+                            ui.add(DragValue::new(percent).clamp_range(1..=100).suffix(" %"));
+                            ui.label("of at least");
+                            ui.add(DragValue::new(min_letters).clamp_range(1..=500));
+                            ui.label("letters");
+                        }
+                        Heuristic::Urls { min }
+                        | Heuristic::Emotes { min }
+                        | Heuristic::Mentions { min } => {
+                            ui.label("at least");
+                            ui.add(DragValue::new(min).clamp_range(1..=100));
+                        }
+                        Heuristic::RepeatedChars { min } => {
+                            ui.label("run of at least");
+                            ui.add(DragValue::new(min).clamp_range(2..=500));
+                        }
+                    }
+                    if ui.button("x").clicked() {
+                        remove = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = remove {
+                heuristics.remove(idx);
+            }
+            if group_ui.button("+").clicked() {
+                heuristics.push(Heuristic::default());
+            }
+        });
+    });
 }
 
 #[cached]